@@ -0,0 +1,84 @@
+use async_std::future::timeout;
+use log::warn;
+use std::env;
+use std::future::Future;
+use std::time::Duration;
+
+const DEFAULT_CACHE_FETCH_TIMEOUT_MS: u64 = 2000;
+
+/// Deadline for HTTP fetches made on a cache miss, read from `CACHE_FETCH_TIMEOUT_MS`.
+pub(crate) fn cache_fetch_timeout() -> Duration {
+    let millis = env::var("CACHE_FETCH_TIMEOUT_MS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_CACHE_FETCH_TIMEOUT_MS);
+    Duration::from_millis(millis)
+}
+
+/// Returns the `cached` value, or falls back to `fetch` when the cache missed. Early after startup
+/// the cache is still warming up, so features relying on it would otherwise silently misbehave.
+pub(crate) async fn cached_or_fetch<T, F, Fut>(
+    cached: Option<T>,
+    fetch: F,
+    fetch_timeout: Duration,
+) -> Option<T>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = serenity::Result<T>>,
+{
+    if cached.is_some() {
+        return cached;
+    }
+    match timeout(fetch_timeout, fetch()).await {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(error)) => {
+            warn!("Fetch after cache miss failed: {error}");
+            None
+        }
+        Err(_) => {
+            warn!("Fetch after cache miss timed out");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::cached_or_fetch;
+    use futures::future::pending;
+    use std::time::Duration;
+
+    #[async_std::test]
+    async fn test_cached_or_fetch_hit() {
+        let value = cached_or_fetch(
+            Some(1),
+            || async { panic!("Should not fetch on a cache hit") },
+            Duration::from_millis(100),
+        )
+        .await;
+        assert_eq!(Some(1), value);
+    }
+
+    #[async_std::test]
+    async fn test_cached_or_fetch_miss() {
+        let value = cached_or_fetch(None, || async { Ok(2) }, Duration::from_millis(100)).await;
+        assert_eq!(Some(2), value);
+    }
+
+    #[async_std::test]
+    async fn test_cached_or_fetch_error() {
+        let value: Option<u32> = cached_or_fetch(
+            None,
+            || async { Err(serenity::Error::Other("Unknown Message")) },
+            Duration::from_millis(100),
+        )
+        .await;
+        assert_eq!(None, value);
+    }
+
+    #[async_std::test]
+    async fn test_cached_or_fetch_timeout() {
+        let value: Option<u32> = cached_or_fetch(None, pending, Duration::from_millis(50)).await;
+        assert_eq!(None, value);
+    }
+}
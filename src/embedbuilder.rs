@@ -1,63 +1,243 @@
 use crate::messages;
 use crate::messages::TextField;
+use log::warn;
 use serenity::model::channel::AttachmentType;
 use std::borrow::Cow;
+use std::env;
 use std::io::{Cursor, Write};
 
 pub const ONE_MEGABYTE: usize = 1024 * 1024;
-pub const DISCORD_MAX_ATTACHMENT_SIZE: usize = 5 * ONE_MEGABYTE;
+// The free tier limit, servers with a higher one can raise it with ATTACHMENT_SIZE_LIMIT.
+pub const DEFAULT_ATTACHMENT_SIZE_LIMIT: usize = 8 * ONE_MEGABYTE;
 
 pub const DISCORD_MAX_TITLE: usize = 256;
 pub const DISCORD_MAX_DESCRIPTION: usize = 4096;
 pub const DISCORD_MAX_FIELDS: usize = 25;
 pub const DISCORD_MAX_VALUE: usize = 1024;
-//pub const DISCORD_MAX_FOOTER: usize = 2048;
+pub const DISCORD_MAX_FOOTER: usize = 2048;
 pub const DISCORD_MAX_AUTHOR: usize = 256;
 pub const DISCORD_MAX_EMBED_TOTAL: usize = 6000;
+// Not a Discord limit, caps how many messages a single response can turn into.
+pub const MAX_EMBEDS_PER_RESPONSE: usize = 10;
+pub const DISCORD_MAX_ATTACHMENTS: usize = 10;
+pub const DISCORD_MAX_CONTENT: usize = 2000;
+pub const DEFAULT_INLINE_FILE_MAX_BYTES: usize = 1024;
 
-fn truncate(string: String, length: usize) -> String {
-    if string.len() > length {
-        return string[0..length].to_string();
+/// Markers that make it visible to users that content was cut. Configurable through the
+/// `CONTINUED_MARKER` and `TRUNCATED_MARKER` environment variables.
+pub(crate) struct Markers {
+    /// Prefixed to every chunk after the first when content is split.
+    pub continued: String,
+    /// Appended to content that was truncated.
+    pub truncated: String,
+}
+
+impl Markers {
+    pub(crate) fn from_env() -> Markers {
+        let default = Markers::default();
+        Markers {
+            continued: env::var("CONTINUED_MARKER").unwrap_or(default.continued),
+            truncated: env::var("TRUNCATED_MARKER").unwrap_or(default.truncated),
+        }
+    }
+}
+
+impl Default for Markers {
+    fn default() -> Markers {
+        Markers {
+            continued: "\u{2026}(continued) ".to_string(),
+            truncated: "\u{2026}(truncated)".to_string(),
+        }
+    }
+}
+
+/// Parses a color written as six hex digits, optionally prefixed with `#` or `0x`.
+pub(crate) fn parse_color(color: &str) -> Option<i32> {
+    let color = color.trim();
+    let hex = color
+        .strip_prefix('#')
+        .or_else(|| color.strip_prefix("0x"))
+        .unwrap_or(color);
+    // from_str_radix alone would also take a sign, and any number of digits.
+    if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    i32::from_str_radix(hex, 16).ok()
+}
+
+/// Maximum embed author name length, configurable through `MAX_AUTHOR_LENGTH` but never above
+/// Discord's own limit.
+fn max_author_length() -> usize {
+    env::var("MAX_AUTHOR_LENGTH")
+        .ok()
+        .and_then(|l| l.parse().ok())
+        .map_or(DISCORD_MAX_AUTHOR, |l: usize| l.min(DISCORD_MAX_AUTHOR))
+}
+
+/// Returns the largest index not greater than `index` that lies on a char boundary.
+fn floor_char_boundary(string: &str, index: usize) -> usize {
+    if index >= string.len() {
+        return string.len();
+    }
+    let mut index = index;
+    while !string.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// Discord rejects fields with an empty name or value, so those are replaced with a zero width
+/// space.
+fn non_empty(string: String) -> String {
+    if string.is_empty() {
+        "\u{200b}".to_string()
+    } else {
+        string
+    }
+}
+
+fn truncate(string: String, length: usize, markers: &Markers) -> String {
+    if string.len() <= length {
+        return string;
+    }
+    if markers.truncated.len() >= length {
+        let end = floor_char_boundary(&string, length);
+        return string[0..end].to_string();
+    }
+    let end = floor_char_boundary(&string, length - markers.truncated.len());
+    format!("{}{}", &string[0..end], markers.truncated)
+}
+
+/// Splits `content` into chunks of at most `limit` bytes, prefixing every chunk after the first
+/// with the continued marker. Chunks end at a line break or space where possible, and a code
+/// block cut by a split is closed at the end of the chunk and reopened, with the same language,
+/// at the start of the next one.
+pub(crate) fn split_content(content: &str, limit: usize, markers: &Markers) -> Vec<String> {
+    const FENCE: &str = "```";
+    let mut chunks = vec![];
+    let mut rest = content;
+    let mut prefix = String::new();
+    let mut fence: Option<String> = None;
+    while prefix.len() + rest.len() > limit {
+        let room = limit.saturating_sub(prefix.len());
+        let (mut chunk, mut next) = split_at_break(rest, room);
+        let mut open = fence_after(chunk, fence.clone());
+        if open.is_some() {
+            // Leave room to close the fence.
+            (chunk, next) = split_at_break(rest, room.saturating_sub(FENCE.len() + 1));
+            open = fence_after(chunk, fence.clone());
+        }
+        match &open {
+            Some(_) => chunks.push(format!("{prefix}{chunk}\n{FENCE}")),
+            None => chunks.push(format!("{prefix}{chunk}")),
+        }
+        rest = next;
+        prefix = match &open {
+            Some(language) => format!("{}{FENCE}{language}\n", markers.continued),
+            None => markers.continued.clone(),
+        };
+        fence = open;
+    }
+    chunks.push(format!("{prefix}{rest}"));
+    chunks
+}
+
+/// Splits `text` so the first part is at most `room` bytes, preferring to split at the last line
+/// break, then the last space. The character split at is dropped.
+fn split_at_break(text: &str, room: usize) -> (&str, &str) {
+    let mut end = floor_char_boundary(text, room);
+    if end == 0 {
+        // No room left, make progress by at least one character.
+        end = text.chars().next().map_or(text.len(), char::len_utf8);
+    }
+    if end < text.len() {
+        let head = &text[..end];
+        if let Some(at) = head.rfind('\n').or_else(|| head.rfind(' ')) {
+            if at > 0 {
+                return (&text[..at], &text[at + 1..]);
+            }
+        }
     }
-    string
+    (&text[..end], &text[end..])
+}
+
+/// Follows the code fences in `text`, starting inside a block of `open` language if it is set,
+/// and returns the language of the block still open at the end of `text`, if any.
+fn fence_after(text: &str, open: Option<String>) -> Option<String> {
+    let mut open = open;
+    let mut rest = text;
+    while let Some(at) = rest.find("```") {
+        rest = &rest[at + 3..];
+        open = match open {
+            Some(_) => None,
+            None => {
+                let line = rest.split('\n').next().unwrap_or_default();
+                Some(line.trim().to_string())
+            }
+        };
+    }
+    open
 }
 
 pub(crate) fn build_embeds(embed_content: messages::EmbedContent) -> Vec<messages::EmbedContent> {
+    let markers = Markers::from_env();
     let mut embeds = vec![];
     let mut first = messages::EmbedContent::default();
+    let color = embed_color(&embed_content);
     let mut total_chars;
-    first.title = truncate(embed_content.title, DISCORD_MAX_TITLE);
+    first.title = truncate(embed_content.title, DISCORD_MAX_TITLE, &markers);
     first.description = if !embed_content.description.is_empty() {
-        truncate(embed_content.description, DISCORD_MAX_DESCRIPTION)
+        truncate(embed_content.description, DISCORD_MAX_DESCRIPTION, &markers)
     } else {
         "\u{200b}".to_string()
     };
-    first.snapshot = embed_content.snapshot;
+    first.snapshots = embed_content.snapshots;
 
-    let author = truncate(embed_content.author, DISCORD_MAX_AUTHOR);
+    let max_author = max_author_length();
+    if embed_content.author.len() > max_author {
+        warn!(
+            "Embed author is {} bytes long, truncating to {max_author}",
+            embed_content.author.len()
+        );
+    }
+    let author = truncate(embed_content.author, max_author, &markers);
     first.author.clone_from(&author);
-    first.color = embed_content.color;
+    first.color = color;
 
+    // The footer goes on the last embed, so every embed keeps room for it. That may be the first
+    // embed, so the footer only gets what its title, description and author leave.
     total_chars = first.title.len() + first.description.len() + first.author.len();
+    let footer_length = DISCORD_MAX_FOOTER.min(DISCORD_MAX_EMBED_TOTAL.saturating_sub(total_chars));
+    let footer_text = truncate(embed_content.footer_text, footer_length, &markers);
 
     let mut last = first;
 
-    for field in embed_content.textfield {
+    let total_fields = embed_content.textfield.len();
+    for (i, field) in embed_content.textfield.into_iter().enumerate() {
         let mut trimmed_field = TextField::default();
-        let title = truncate(field.title, DISCORD_MAX_TITLE);
-        let text = truncate(field.text, DISCORD_MAX_VALUE);
+        let title = truncate(non_empty(field.title), DISCORD_MAX_TITLE, &markers);
+        let text = truncate(non_empty(field.text), DISCORD_MAX_VALUE, &markers);
 
         trimmed_field.title.clone_from(&title);
         trimmed_field.text.clone_from(&text);
         trimmed_field.inline = field.inline;
 
         let next_size = total_chars + trimmed_field.title.len() + trimmed_field.text.len();
-        if last.textfield.len() >= DISCORD_MAX_FIELDS || next_size > DISCORD_MAX_EMBED_TOTAL {
+        if last.textfield.len() >= DISCORD_MAX_FIELDS
+            || next_size + footer_text.len() > DISCORD_MAX_EMBED_TOTAL
+        {
+            if embeds.len() + 1 >= MAX_EMBEDS_PER_RESPONSE {
+                warn!(
+                    "Embed needs more than {MAX_EMBEDS_PER_RESPONSE} messages, dropping {} fields",
+                    total_fields - i
+                );
+                break;
+            }
             embeds.push(last);
             last = messages::EmbedContent::default();
             last.description = "\u{200b}".to_string();
             last.author.clone_from(&author);
-            last.color = embed_content.color;
+            last.color = color;
             total_chars = last.title.len() + last.description.len() + last.author.len();
         }
 
@@ -65,12 +245,136 @@ pub(crate) fn build_embeds(embed_content: messages::EmbedContent) -> Vec<message
         total_chars += title.len() + text.len();
     }
 
+    last.footer_text = footer_text;
+    last.footer_icon_url = embed_content.footer_icon_url;
+    last.timestamp = embed_content.timestamp;
     embeds.push(last);
     embeds
 }
 
-pub(crate) fn split_file(filename: String, filedata: &[u8]) -> Vec<(String, AttachmentType)> {
-    return if filedata.len() < DISCORD_MAX_ATTACHMENT_SIZE {
+/// The embed's color, preferring `color_hex` over `color` when set. An invalid `color_hex` falls
+/// back to `color`, which holds the channel or global default when the client left it unset.
+fn embed_color(embed_content: &messages::EmbedContent) -> i32 {
+    if embed_content.color_hex.is_empty() {
+        return embed_content.color;
+    }
+    parse_color(&embed_content.color_hex).unwrap_or_else(|| {
+        warn!(
+            "Invalid embed color {:?}, using the default",
+            embed_content.color_hex
+        );
+        embed_content.color
+    })
+}
+
+/// Flattens an embed into plain text: the title in bold, the description, then one line per
+/// field. Images are left out.
+pub(crate) fn flatten_embed(embed_content: &messages::EmbedContent) -> String {
+    let mut lines = vec![];
+    if !embed_content.title.is_empty() {
+        lines.push(format!("**{}**", embed_content.title));
+    }
+    if !embed_content.description.is_empty() {
+        lines.push(embed_content.description.clone());
+    }
+    for field in &embed_content.textfield {
+        lines.push(format!("**{}**: {}", field.title, field.text));
+    }
+    lines.join("\n")
+}
+
+/// Formats `text` as a fenced code block, breaking up any fences inside the text so they can't
+/// terminate the block early.
+pub(crate) fn code_block(text: &str, language: &str) -> String {
+    let escaped = text.replace("```", "`\u{200b}``");
+    format!("```{language}\n{escaped}\n```")
+}
+
+/// Splits `text` into fenced code blocks of at most `limit` bytes each, closing the fence at the
+/// end of every chunk and reopening it with the same language at the start of the next. Splits
+/// happen at line breaks where possible.
+pub(crate) fn split_code_block(text: &str, language: &str, limit: usize) -> Vec<String> {
+    let escaped = text.replace("```", "`\u{200b}``");
+    let open = format!("```{language}\n");
+    let close = "\n```";
+    let room = limit.saturating_sub(open.len() + close.len());
+    let mut chunks = vec![];
+    let mut rest = escaped.as_str();
+    while rest.len() > room {
+        let mut end = floor_char_boundary(rest, room);
+        if end == 0 {
+            // The fences leave no room, make progress by at least one character.
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        let (chunk, next) = match rest[..end].rfind('\n') {
+            Some(newline) if newline > 0 => (&rest[..newline], &rest[newline + 1..]),
+            _ => (&rest[..end], &rest[end..]),
+        };
+        chunks.push(format!("{open}{chunk}{close}"));
+        rest = next;
+    }
+    chunks.push(format!("{open}{rest}{close}"));
+    chunks
+}
+
+/// Whether inline files too long for one message are split across several code blocks rather
+/// than attached, on when `INLINE_FILE_SPLIT` is set.
+pub(crate) fn inline_file_split() -> bool {
+    env::var("INLINE_FILE_SPLIT").is_ok()
+}
+
+/// Largest text file posted inline, configurable through `INLINE_FILE_MAX_BYTES`.
+pub(crate) fn inline_file_max_bytes() -> usize {
+    env::var("INLINE_FILE_MAX_BYTES")
+        .ok()
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(DEFAULT_INLINE_FILE_MAX_BYTES)
+}
+
+/// Returns the code block to post in place of `file`, if the client asked for it to be inlined
+/// and it is small enough text to fit in a single message. Anything else is attached as usual.
+pub(crate) fn inline_file(file: &messages::ProtoFile, max_bytes: usize) -> Option<String> {
+    if !file.inline || file.data.len() > max_bytes {
+        return None;
+    }
+    let text = std::str::from_utf8(&file.data).ok()?;
+    let block = code_block(text, &file.language);
+    if block.len() > DISCORD_MAX_CONTENT {
+        return None;
+    }
+    Some(block)
+}
+
+/// Like `inline_file`, but a file too long for a single message is split into several code
+/// blocks instead of being attached.
+pub(crate) fn inline_file_parts(
+    file: &messages::ProtoFile,
+    max_bytes: usize,
+) -> Option<Vec<String>> {
+    if !file.inline || file.data.len() > max_bytes {
+        return None;
+    }
+    let text = std::str::from_utf8(&file.data).ok()?;
+    Some(split_code_block(text, &file.language, DISCORD_MAX_CONTENT))
+}
+
+/// Largest attachment sent to Discord, read from `ATTACHMENT_SIZE_LIMIT` in bytes.
+pub(crate) fn attachment_size_limit() -> usize {
+    env::var("ATTACHMENT_SIZE_LIMIT")
+        .ok()
+        .and_then(|l| l.parse().ok())
+        .filter(|l| *l > 0)
+        .unwrap_or(DEFAULT_ATTACHMENT_SIZE_LIMIT)
+}
+
+/// Returns the attachments to send for a file: the file itself if it fits in `size_limit`,
+/// otherwise a zip of it split into parts of at most `size_limit` bytes.
+pub(crate) fn split_file(
+    filename: String,
+    filedata: &[u8],
+    size_limit: usize,
+) -> Vec<(String, AttachmentType<'_>)> {
+    if filedata.len() <= size_limit {
         let mut attachments = vec![];
         let filename2 = filename.clone();
         attachments.push((
@@ -91,7 +395,7 @@ pub(crate) fn split_file(filename: String, filedata: &[u8]) -> Vec<(String, Atta
         zip.write_all(filedata).unwrap();
         let zipdata = zip.finish().unwrap().into_inner();
 
-        let chunks = zipdata.chunks(ONE_MEGABYTE);
+        let chunks = zipdata.chunks(size_limit);
         for (i, chunk) in chunks.enumerate() {
             let zipfilename = format!("{}.zip.{:0>3}", filename, i);
             let mut data = vec![0u8; chunk.len()];
@@ -105,5 +409,5 @@ pub(crate) fn split_file(filename: String, filedata: &[u8]) -> Vec<(String, Atta
             ));
         }
         attachments
-    };
+    }
 }
@@ -0,0 +1,343 @@
+use crate::stream::Peekable;
+use async_std::future::timeout;
+use async_std::io::{BufRead, BufReadExt, Read, ReadExt, Write, WriteExt};
+use byteorder::{ByteOrder, LittleEndian};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use protobuf::MessageFull;
+use std::env;
+use std::io;
+use std::io::{Read as _, Write as _};
+use std::time::Duration;
+
+const DEFAULT_LENGTH_PREFIX_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_CODEC_DETECT_TIMEOUT_MS: u64 = 250;
+const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 300;
+
+/// Set in a length prefix when the frame's payload is gzip compressed. Frames are far smaller than
+/// 2 GiB, so clients that don't know about compression never set it.
+pub(crate) const GZIP_FLAG: usize = 1 << 31;
+
+/// Deadline for the rest of a length prefix once its first byte has arrived, read from
+/// `LENGTH_PREFIX_TIMEOUT_MS`.
+pub(crate) fn length_prefix_timeout() -> Duration {
+    let millis = env::var("LENGTH_PREFIX_TIMEOUT_MS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_LENGTH_PREFIX_TIMEOUT_MS);
+    Duration::from_millis(millis)
+}
+
+/// How long a new client has to send its first byte for its codec to be told from it, read from
+/// `CODEC_DETECT_TIMEOUT_MS`. Protobuf clients may wait for the greeting instead, so this is kept
+/// short: they are only registered once it has passed.
+pub(crate) fn codec_detect_timeout() -> Duration {
+    let millis = env::var("CODEC_DETECT_TIMEOUT_MS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_CODEC_DETECT_TIMEOUT_MS);
+    Duration::from_millis(millis)
+}
+
+/// How long a client may go without sending a frame before it is assumed dead, read from
+/// `IDLE_TIMEOUT_SECS`.
+pub(crate) fn idle_timeout() -> Duration {
+    let secs = env::var("IDLE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_IDLE_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+const DEFAULT_WRITE_TIMEOUT_SECS: u64 = 10;
+
+/// How long writing a frame to a client may take before the client is assumed stuck, read from
+/// `WRITE_TIMEOUT_SECS`.
+pub(crate) fn write_timeout() -> Duration {
+    let secs = env::var("WRITE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_WRITE_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+/// Largest frame a client may send, read from `MAX_FRAME_SIZE`. Anything bigger is treated as a
+/// broken client rather than allocated.
+pub(crate) fn max_frame_size() -> usize {
+    env::var("MAX_FRAME_SIZE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_MAX_FRAME_SIZE)
+}
+
+/// Reads the 4 byte little-endian length prefix of the next frame.
+///
+/// Waiting for the first byte is unbounded, as an idle client is fine, but a client that stalls
+/// part way through a length prefix is broken, so the remaining bytes must arrive within
+/// `prefix_timeout`.
+pub(crate) async fn read_length<R: Read + Unpin>(
+    stream: &mut R,
+    prefix_timeout: Duration,
+) -> io::Result<usize> {
+    let length_buf = &mut [0u8; 4];
+    stream.read_exact(&mut length_buf[..1]).await?;
+    match timeout(prefix_timeout, stream.read_exact(&mut length_buf[1..])).await {
+        Ok(result) => result?,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "stalled while reading length prefix",
+            ))
+        }
+    }
+    Ok(LittleEndian::read_u32(length_buf) as usize)
+}
+
+/// Writes `data` as a single length-prefixed frame.
+pub(crate) async fn write_frame<W: Write + Unpin>(stream: &mut W, data: &[u8]) -> io::Result<()> {
+    write_prefixed(stream, data.len(), data).await
+}
+
+/// Writes `data` gzip compressed as a single frame, with `GZIP_FLAG` set in its length prefix.
+pub(crate) async fn write_gzip_frame<W: Write + Unpin>(
+    stream: &mut W,
+    data: &[u8],
+) -> io::Result<()> {
+    let compressed = gzip(data)?;
+    write_prefixed(stream, compressed.len() | GZIP_FLAG, &compressed).await
+}
+
+async fn write_prefixed<W: Write + Unpin>(
+    stream: &mut W,
+    prefix: usize,
+    data: &[u8],
+) -> io::Result<()> {
+    let length_buf = &mut [0u8; 4];
+    LittleEndian::write_u32(length_buf, prefix as u32);
+    stream.write_all(length_buf).await?;
+    stream.write_all(data).await
+}
+
+/// The protocol a client speaks: length-prefixed protobuf frames, or one JSON object per line for
+/// clients written without protobuf tooling. Both carry the same messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Codec {
+    Protobuf,
+    JsonLines,
+}
+
+impl Codec {
+    pub(crate) fn decode<M: MessageFull>(self, data: &[u8]) -> Result<M, String> {
+        match self {
+            Codec::Protobuf => M::parse_from_bytes(data).map_err(|error| error.to_string()),
+            Codec::JsonLines => {
+                let line = std::str::from_utf8(data).map_err(|error| error.to_string())?;
+                protobuf_json_mapping::parse_from_str(line).map_err(|error| error.to_string())
+            }
+        }
+    }
+
+    /// Encodes `message` as the payload of a frame, or as a line without its newline.
+    pub(crate) fn encode<M: MessageFull>(self, message: &M) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Protobuf => Ok(message.write_to_bytes()?),
+            Codec::JsonLines => protobuf_json_mapping::print_to_string(message)
+                .map(String::into_bytes)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string())),
+        }
+    }
+}
+
+/// Tells the codec of a client from the first byte it sent: a JSON line starts with `{`, or with
+/// whitespace before it. Anything else is the first byte of a length prefix.
+pub(crate) fn detect_codec(first_byte: u8) -> Codec {
+    if first_byte == b'{' || first_byte.is_ascii_whitespace() {
+        Codec::JsonLines
+    } else {
+        Codec::Protobuf
+    }
+}
+
+/// Tells the codec of a client from the first byte it sends, leaving it to be read. JSON clients
+/// announce themselves by sending their first line within `announce_timeout` of connecting, a
+/// client that sends nothing by then is taken to speak protobuf, which leaves clients that wait
+/// for the greeting before sending anything waiting no longer than that.
+pub(crate) async fn peek_codec<R: Read + Unpin>(
+    reader: &mut Peekable<R>,
+    announce_timeout: Duration,
+) -> Codec {
+    match timeout(announce_timeout, reader.peek(1)).await {
+        Ok(Ok(&[first, ..])) => detect_codec(first),
+        // Reading will fail the same way, and end the connection, or the client is waiting for
+        // the greeting.
+        _ => Codec::Protobuf,
+    }
+}
+
+/// Reads a line, without its newline. Returns `None` once the stream has ended, and fails on a
+/// line longer than `max_size` bytes.
+pub(crate) async fn read_line<R: BufRead + Unpin>(
+    reader: &mut R,
+    max_size: usize,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    reader
+        .take(max_size as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    } else if line.len() > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line longer than {max_size} bytes"),
+        ));
+    }
+    Ok(Some(line))
+}
+
+/// Writes `data` followed by a newline.
+pub(crate) async fn write_line<W: Write + Unpin>(stream: &mut W, data: &[u8]) -> io::Result<()> {
+    let mut line = Vec::with_capacity(data.len() + 1);
+    line.extend_from_slice(data);
+    line.push(b'\n');
+    stream.write_all(&line).await
+}
+
+fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Inflates a gzip compressed frame, failing if it inflates to more than `max_size` bytes so a
+/// small frame can't make the server allocate without bound.
+pub(crate) fn gunzip(data: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+    let mut inflated = vec![];
+    GzDecoder::new(data)
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut inflated)?;
+    if inflated.len() > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame inflates to more than {max_size} bytes"),
+        ));
+    }
+    Ok(inflated)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::framing::{
+        detect_codec, gunzip, peek_codec, read_length, read_line, write_gzip_frame, Codec,
+        GZIP_FLAG,
+    };
+    use crate::stream::Peekable;
+    use async_std::io::ReadExt;
+    use async_std::io::WriteExt;
+    use async_std::net::{TcpListener, TcpStream};
+    use std::io;
+    use std::time::Duration;
+
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[async_std::test]
+    async fn test_read_length() {
+        let (mut client, mut server) = connected_pair().await;
+        client.write_all(&1234u32.to_le_bytes()).await.unwrap();
+
+        let length = read_length(&mut server, Duration::from_millis(100)).await;
+        assert_eq!(1234, length.unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_read_length_stalled_prefix() {
+        let (mut client, mut server) = connected_pair().await;
+        client.write_all(&[0x01, 0x02]).await.unwrap();
+
+        let error = read_length(&mut server, Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+    }
+
+    #[async_std::test]
+    async fn test_gzip_frame_round_trip() {
+        let (mut client, mut server) = connected_pair().await;
+        let data = "snapshot ".repeat(1000).into_bytes();
+        write_gzip_frame(&mut client, &data).await.unwrap();
+
+        let prefix = read_length(&mut server, Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert_ne!(0, prefix & GZIP_FLAG);
+        let length = prefix & !GZIP_FLAG;
+        assert!(length < data.len());
+        let mut compressed = vec![0u8; length];
+        server.read_exact(&mut compressed).await.unwrap();
+        assert_eq!(data, gunzip(&compressed, data.len()).unwrap());
+
+        let error = gunzip(&compressed, data.len() - 1).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+
+    #[test]
+    fn test_detect_codec() {
+        assert_eq!(Codec::JsonLines, detect_codec(b'{'));
+        assert_eq!(Codec::JsonLines, detect_codec(b' '));
+        assert_eq!(Codec::JsonLines, detect_codec(b'\n'));
+        assert_eq!(Codec::Protobuf, detect_codec(8));
+        assert_eq!(Codec::Protobuf, detect_codec(0));
+    }
+
+    #[async_std::test]
+    async fn test_read_line() {
+        let mut reader = async_std::io::BufReader::new(&b"{\"ping\": {}}\nno newline"[..]);
+        assert_eq!(
+            b"{\"ping\": {}}".to_vec(),
+            read_line(&mut reader, 64).await.unwrap().unwrap()
+        );
+        assert_eq!(
+            b"no newline".to_vec(),
+            read_line(&mut reader, 64).await.unwrap().unwrap()
+        );
+        assert!(read_line(&mut reader, 64).await.unwrap().is_none());
+
+        let mut reader = async_std::io::BufReader::new(&b"0123456789\n"[..]);
+        let error = read_line(&mut reader, 4).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+
+    #[async_std::test]
+    async fn test_peek_codec_defaults_to_protobuf() {
+        let (mut client, server) = connected_pair().await;
+        let mut reader = Peekable::new(server);
+        let wait = Duration::from_millis(50);
+        // A client waiting for the greeting isn't waited on for long.
+        let peeked =
+            async_std::future::timeout(Duration::from_secs(5), peek_codec(&mut reader, wait));
+        assert_eq!(Codec::Protobuf, peeked.await.unwrap());
+
+        client.write_all(b"{}\n").await.unwrap();
+        let mut line = vec![0u8; 3];
+        reader.read_exact(&mut line).await.unwrap();
+        assert_eq!(b"{}\n", line.as_slice());
+
+        let (mut client, server) = connected_pair().await;
+        let mut reader = Peekable::new(server);
+        client.write_all(b"{\"ping\": {}}\n").await.unwrap();
+        assert_eq!(Codec::JsonLines, peek_codec(&mut reader, wait).await);
+    }
+}
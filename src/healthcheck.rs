@@ -1,67 +1,331 @@
+use crate::framing::{length_prefix_timeout, read_length, write_frame};
 use crate::messages;
-use async_std::io::ReadExt;
+use crate::server::{auth_token, bind_address};
+use crate::stream::{connect_tls, served_certificate, tcp_listener, unix_socket_path};
+use async_std::future::timeout;
+use async_std::io::{Read, ReadExt, Write, WriteExt};
 use async_std::net::TcpStream;
-use byteorder::{ByteOrder, LittleEndian};
-use futures::AsyncWriteExt;
+use async_std::os::unix::net::UnixStream;
+use futures_rustls::rustls::Certificate;
 use protobuf::Message;
 use std::env;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 
+/// The shim accepted the connection and answered the probe.
+pub(crate) const EXIT_HEALTHY: i32 = 0;
+/// The shim could not be connected to, or didn't answer the probe in time.
+pub(crate) const EXIT_UNREACHABLE: i32 = 1;
+/// The environment is invalid, so there is nothing meaningful to check.
+pub(crate) const EXIT_MISCONFIGURED: i32 = 2;
+
+const DEFAULT_HEALTHCHECK_TIMEOUT_SECS: u64 = 10;
+
+/// Replies read while waiting for the one the probe expects, which may follow a Greeting,
+/// SettingsApplied and MessageSent.
+const MAX_REPLIES: usize = 10;
+
+/// How long the whole healthcheck may take, read from `HEALTHCHECK_TIMEOUT_SECS`.
+fn healthcheck_timeout() -> Duration {
+    let secs = env::var("HEALTHCHECK_TIMEOUT_SECS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_HEALTHCHECK_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+#[derive(Debug, PartialEq)]
+enum Failure {
+    Unreachable(String),
+    Misconfigured(String),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Unreachable(_) => EXIT_UNREACHABLE,
+            Failure::Misconfigured(_) => EXIT_MISCONFIGURED,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Unreachable(reason) => {
+                write!(f, "unreachable (exit {EXIT_UNREACHABLE}): {reason}")
+            }
+            Failure::Misconfigured(reason) => {
+                write!(f, "misconfigured (exit {EXIT_MISCONFIGURED}): {reason}")
+            }
+        }
+    }
+}
+
+/// What is checked once connected.
+#[derive(Debug, PartialEq)]
+enum Probe {
+    /// Accepting the connection is enough.
+    Connect,
+    /// The shim must answer a Ping with a Pong.
+    Ping,
+    /// An embed posted to this channel must come back as a command, so Discord is reachable too.
+    RoundTrip(u64),
+}
+
+/// The probe to run: a round trip through `HEALTH_CHECK_CHANNEL_ID` when set, otherwise a Ping
+/// when `HEALTHCHECK_PING=true`, otherwise just a connect.
+fn probe(channel_id: Option<String>, ping: Option<String>) -> Result<Probe, Failure> {
+    if let Some(channel_id) = channel_id {
+        return channel_id.parse().map(Probe::RoundTrip).map_err(|e| {
+            Failure::Misconfigured(format!(
+                "invalid HEALTH_CHECK_CHANNEL_ID [{channel_id}]: {e}"
+            ))
+        });
+    }
+    if ping.is_some_and(|ping| ping == "true") {
+        return Ok(Probe::Ping);
+    }
+    Ok(Probe::Connect)
+}
+
+/// Checks that the shim serving on the configured bind address is healthy, returning the exit
+/// code for the process. The reason for a failure is written to stderr.
 pub async fn healthcheck() -> i32 {
-    let mut client = TcpStream::connect("127.0.0.1:23416").await.unwrap();
-    let channel_id: u64 = env::var("HEALTH_CHECK_CHANNEL_ID")
-        .expect("channel id")
-        .parse()
-        .unwrap();
-    let flag = uuid::Uuid::new_v4().to_string();
-
-    // Send settings
-    {
-        let mut response = messages::Response::new();
-        let mut settings = messages::Settings {
-            channel_id,
-            ..Default::default()
+    match check().await {
+        Ok(()) => EXIT_HEALTHY,
+        Err(failure) => {
+            eprintln!("Healthcheck failed, {failure}");
+            failure.exit_code()
+        }
+    }
+}
+
+async fn check() -> Result<(), Failure> {
+    let target = target()?;
+    let probe = probe(
+        env::var("HEALTH_CHECK_CHANNEL_ID").ok(),
+        env::var("HEALTHCHECK_PING").ok(),
+    )?;
+    let limit = healthcheck_timeout();
+    timeout(limit, run(&target, probe))
+        .await
+        .map_err(|_| Failure::Unreachable(format!("no answer from {target} within {limit:?}")))?
+}
+
+/// Where the shim accepts clients, and so where the healthcheck connects.
+#[derive(Debug)]
+enum Target {
+    Tcp(SocketAddr),
+    /// TCP with TLS, where the shim must present this certificate.
+    Tls(SocketAddr, Certificate),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Tcp(addr) | Target::Tls(addr, _) => write!(f, "{addr}"),
+            Target::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// The TCP listener, over TLS when the shim terminates it, or the Unix domain socket when the
+/// TCP listener is disabled.
+fn target() -> Result<Target, Failure> {
+    if !tcp_listener() {
+        return unix_socket_path().map(Target::Unix).ok_or_else(|| {
+            Failure::Misconfigured(
+                "DISABLE_TCP_LISTENER is set and UNIX_SOCKET is not set".to_string(),
+            )
+        });
+    }
+    let addr = connect_address(bind_address().map_err(Failure::Misconfigured)?);
+    match served_certificate().map_err(Failure::Misconfigured)? {
+        Some(certificate) => Ok(Target::Tls(addr, certificate)),
+        None => Ok(Target::Tcp(addr)),
+    }
+}
+
+trait Connection: Read + Write + Unpin + Send {}
+
+impl<S: Read + Write + Unpin + Send> Connection for S {}
+
+async fn connect(target: &Target) -> std::io::Result<Box<dyn Connection>> {
+    Ok(match target {
+        Target::Tcp(addr) => Box::new(TcpStream::connect(addr).await?),
+        Target::Tls(addr, certificate) => Box::new(connect_tls(*addr, certificate.clone()).await?),
+        Target::Unix(path) => Box::new(UnixStream::connect(path).await?),
+    })
+}
+
+/// The address to connect to for `bind`.
+fn connect_address(mut bind: SocketAddr) -> SocketAddr {
+    if bind.ip().is_unspecified() {
+        // Listening on every interface, so loopback will do.
+        let loopback = match bind {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
         };
-        settings.channel_id = channel_id;
-        response.set_settings(settings);
+        bind.set_ip(loopback);
+    }
+    bind
+}
 
-        let bytes = response.write_to_bytes().unwrap();
-        send_data(&mut client, bytes).await;
+async fn run(target: &Target, probe: Probe) -> Result<(), Failure> {
+    let mut client = connect(target)
+        .await
+        .map_err(|e| Failure::Unreachable(format!("cannot connect to {target}: {e}")))?;
+    if probe == Probe::Connect {
+        return Ok(());
     }
-    // Send flag
-    {
+    let lost =
+        |e: std::io::Error| Failure::Unreachable(format!("lost connection to {target}: {e}"));
+
+    if let Some(token) = auth_token() {
         let mut response = messages::Response::new();
-        let message = messages::EmbedContent {
-            title: flag.clone(),
+        response.set_auth(messages::Auth {
+            token,
             ..Default::default()
-        };
-        response.set_embed(message);
+        });
+        send_response(&mut client, &response).await.map_err(lost)?;
+    }
+
+    match probe {
+        Probe::Connect => Ok(()),
+        Probe::Ping => {
+            let mut response = messages::Response::new();
+            response.set_ping(messages::Ping::new());
+            send_response(&mut client, &response).await.map_err(lost)?;
+            if !await_request(&mut client, |request| request.has_pong())
+                .await
+                .map_err(lost)?
+            {
+                return Err(Failure::Unreachable(format!("no Pong from {target}")));
+            }
+            Ok(())
+        }
+        Probe::RoundTrip(channel_id) => {
+            let mut response = messages::Response::new();
+            response.set_settings(messages::Settings {
+                channel_id,
+                ..Default::default()
+            });
+            send_response(&mut client, &response).await.map_err(lost)?;
+
+            let flag = uuid::Uuid::new_v4().to_string();
+            let mut response = messages::Response::new();
+            response.set_embed(messages::EmbedContent {
+                title: flag.clone(),
+                ..Default::default()
+            });
+            send_response(&mut client, &response).await.map_err(lost)?;
 
-        let bytes = response.write_to_bytes().unwrap();
-        send_data(&mut client, bytes).await;
+            if !await_request(&mut client, |request| request.command() == flag)
+                .await
+                .map_err(lost)?
+            {
+                return Err(Failure::Unreachable(format!(
+                    "the embed posted to channel {channel_id} did not come back from Discord"
+                )));
+            }
+            Ok(())
+        }
     }
+}
 
-    // Read up to 5 responses
-    for _ in 0..5 {
-        let length_buf = &mut [0u8; 4];
-        client.read_exact(length_buf).await.unwrap();
-        let length = LittleEndian::read_u32(length_buf) as usize;
+async fn send_response<W: Write + Unpin>(
+    client: &mut W,
+    response: &messages::Response,
+) -> std::io::Result<()> {
+    write_frame(client, &response.write_to_bytes()?).await?;
+    // TLS only sends what it has buffered once flushed.
+    client.flush().await
+}
 
+/// Reads replies until one matches `expected`, giving up after `MAX_REPLIES`.
+async fn await_request<R: Read + Unpin>(
+    client: &mut R,
+    expected: impl Fn(&messages::Request) -> bool,
+) -> std::io::Result<bool> {
+    for _ in 0..MAX_REPLIES {
+        let length = read_length(client, length_prefix_timeout()).await?;
         let mut buf = vec![0u8; length];
-        client.read_exact(&mut buf).await.unwrap();
-
-        let request = messages::Request::parse_from_bytes(buf.as_slice()).unwrap();
-        if request.command() == flag {
-            return 0; // Success
+        client.read_exact(&mut buf).await?;
+        let request = messages::Request::parse_from_bytes(&buf)?;
+        if expected(&request) {
+            return Ok(true);
         }
     }
-    -1
+    Ok(false)
 }
 
-async fn send_data(tcpstream: &mut TcpStream, data: Vec<u8>) {
-    let length = data.len() as u32;
-    let length_buf = &mut [0u8; 4];
-    LittleEndian::write_u32(length_buf, length);
+#[cfg(test)]
+mod tests {
+    use crate::healthcheck::{
+        connect_address, probe, run, Failure, Probe, Target, EXIT_UNREACHABLE,
+    };
+    use crate::stream::{accept_tls, bind_unix, load_tls, read_certs};
+    use async_std::net::TcpListener;
+    use futures::future::join;
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn test_probe() {
+        assert_eq!(Ok(Probe::Connect), probe(None, None));
+        assert_eq!(Ok(Probe::Ping), probe(None, Some("true".to_string())));
+        assert_eq!(
+            Ok(Probe::RoundTrip(42)),
+            probe(Some("42".to_string()), Some("true".to_string()))
+        );
+
+        let failure = probe(Some("general".to_string()), None).unwrap_err();
+        assert_eq!(2, failure.exit_code());
+        assert!(failure.to_string().starts_with("misconfigured (exit 2): "));
+    }
+
+    #[async_std::test]
+    async fn test_run_connect() {
+        let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+        let target = Target::Tcp(connect_address(listener.local_addr().unwrap()));
+        assert_eq!(Ok(()), run(&target, Probe::Connect).await);
 
-    tcpstream.write_all(length_buf).await.unwrap();
-    tcpstream.write_all(&data).await.unwrap();
+        drop(listener);
+        let failure = run(&target, Probe::Connect).await.unwrap_err();
+        assert!(matches!(failure, Failure::Unreachable(_)));
+        assert_eq!(EXIT_UNREACHABLE, failure.exit_code());
+    }
+
+    #[async_std::test]
+    async fn test_run_connect_tls() {
+        let cert = Path::new("test_data/tls/cert.pem");
+        let acceptor = load_tls(cert, Path::new("test_data/tls/key.pem")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let target = Target::Tls(addr, read_certs(cert).unwrap().remove(0));
+
+        let accept = async {
+            let (tcp, _) = listener.accept().await.unwrap();
+            accept_tls(&acceptor, tcp, Duration::from_secs(5)).await
+        };
+        let (checked, accepted) = join(run(&target, Probe::Connect), accept).await;
+        assert_eq!(Ok(()), checked);
+        assert!(accepted.is_ok());
+    }
+
+    #[async_std::test]
+    async fn test_run_connect_unix() {
+        let path = std::env::temp_dir().join(format!("discordshim-{}.sock", uuid::Uuid::new_v4()));
+        let target = Target::Unix(path.clone());
+        let listener = bind_unix(&path).await.unwrap();
+        assert_eq!(Ok(()), run(&target, Probe::Connect).await);
+
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+        assert!(run(&target, Probe::Connect).await.is_err());
+    }
 }
@@ -0,0 +1,68 @@
+use log::kv::{Error, Key, Value, VisitSource};
+use log::Record;
+use serde_json::Map;
+use std::env;
+use std::io::Write;
+
+/// Sets up logging. `LOG_FORMAT=json` logs one JSON object per line for log collectors, anything
+/// else keeps the human readable text.
+pub(crate) fn init() {
+    if env::var("LOG_FORMAT").is_ok_and(|format| format == "json") {
+        pretty_env_logger::env_logger::Builder::from_default_env()
+            .format(|buf, record| {
+                let line = json_line(&buf.timestamp_millis().to_string(), record);
+                writeln!(buf, "{line}")
+            })
+            .init();
+    } else {
+        pretty_env_logger::init_timed();
+    }
+}
+
+/// Collects the key-values of a record, such as `peer`, as JSON fields.
+struct Fields(Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        self.0.insert(key.to_string(), value.to_string().into());
+        Ok(())
+    }
+}
+
+fn json_line(timestamp: &str, record: &Record) -> String {
+    let mut fields = Fields(Map::new());
+    let _ = record.key_values().visit(&mut fields);
+    let mut line = fields.0;
+    line.insert("timestamp".to_string(), timestamp.into());
+    line.insert("level".to_string(), record.level().as_str().into());
+    line.insert("target".to_string(), record.target().into());
+    line.insert("message".to_string(), record.args().to_string().into());
+    serde_json::Value::Object(line).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::json_line;
+    use log::{Level, Record};
+
+    #[test]
+    fn test_json_line() {
+        let peer = [("peer", "127.0.0.1:4000")];
+        let line = json_line(
+            "2024-01-31T12:00:00.000Z",
+            &Record::builder()
+                .args(format_args!("Dropping \"client\""))
+                .level(Level::Info)
+                .target("discordshim::server")
+                .key_values(&peer)
+                .build(),
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!("2024-01-31T12:00:00.000Z", json["timestamp"]);
+        assert_eq!("INFO", json["level"]);
+        assert_eq!("discordshim::server", json["target"]);
+        assert_eq!("Dropping \"client\"", json["message"]);
+        assert_eq!("127.0.0.1:4000", json["peer"]);
+    }
+}
@@ -1,49 +1,195 @@
+mod cache;
 mod embedbuilder;
+mod framing;
 mod healthcheck;
+mod logging;
 mod messages;
+mod metrics;
+mod persist;
 mod server;
+mod stream;
 mod test;
+mod transform;
+mod webhook;
 
+use async_std::channel::Receiver;
+use async_std::net::TcpListener;
 use async_std::sync::RwLock;
+use log::debug;
 use log::error;
+use log::info;
 use log::warn;
 use serenity::client::{Context, EventHandler};
 use serenity::Client;
 use std::env;
+use std::net::SocketAddr;
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-use crate::server::Server;
+use crate::server::{
+    allowed_users, attachment_filenames, bind_address, is_allowed, listener_drain, parse_bind,
+    shutdown_timeout, typing_indicator, unix_listener, Server,
+};
+use crate::stream::{tls_acceptor, Listener};
 use serenity::async_trait;
 use serenity::framework::standard::StandardFramework;
 
-use crate::healthcheck::healthcheck;
-use serenity::model::channel::Message;
+use crate::cache::{cache_fetch_timeout, cached_or_fetch};
+use crate::healthcheck::{healthcheck, EXIT_MISCONFIGURED, EXIT_UNREACHABLE};
+use crate::metrics::serve_metrics;
+use crate::persist::{persist_stats, stats_file};
+use serenity::model::channel::{Message, Reaction, ReactionType};
+use serenity::model::event::MessageUpdateEvent;
 use serenity::model::gateway::Ready;
-use serenity::model::id::ChannelId;
+use serenity::model::id::{ChannelId, GuildId, MessageId};
 use serenity::prelude::GatewayIntents;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::task;
 
 struct Handler {
-    healthcheckchannel: ChannelId,
+    // Where admin commands and health check flags are handled, if configured.
+    healthcheckchannel: Option<ChannelId>,
     server: Arc<RwLock<Server>>,
+    bind: SocketAddr,
+    // `ready` fires again on reconnects, but the listener, signal handler and metrics endpoint must
+    // only be started once. A rerun would fail to bind the address already listened on, and stop a
+    // listener rotated in by /listen.
+    started: AtomicBool,
+}
+
+impl Handler {
+    /// Starts accepting connections on `addr`, leaving the current listener accepting for the
+    /// drain window so clients can migrate before it is stopped. The Unix socket, if any, is
+    /// rebound along with it.
+    async fn rotate_listener(&self, ctx: Context, addr: &str) -> String {
+        let addr = match parse_bind(addr) {
+            Ok(addr) => addr,
+            Err(error) => return error,
+        };
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(error) => return format!("Failed to listen on {addr}: {error}"),
+        };
+        let mut listeners = vec![listener.into()];
+        listeners.extend(unix_listener().await);
+        let (stop, previous) = self.server.read().await.replace_listener().await;
+        task::spawn(run_listener(
+            Arc::new(ctx),
+            self.server.clone(),
+            listeners,
+            stop,
+        ));
+
+        let drain = listener_drain();
+        if let Some(previous) = previous {
+            task::spawn(async move {
+                async_std::task::sleep(drain).await;
+                info!("Stopping previous listener");
+                let _ = previous.send(()).await;
+            });
+        }
+        format!(
+            "Listening on {addr}, the previous listener stops accepting in {} seconds",
+            drain.as_secs()
+        )
+    }
+
+    async fn forward_reaction(&self, ctx: Context, reaction: Reaction, added: bool) {
+        // Ignore DMs and reactions made by the bot itself.
+        if reaction.guild_id.is_none() {
+            return;
+        }
+        let user = match reaction.user_id {
+            Some(user) if user != ctx.cache.current_user_id() => user,
+            _ => return,
+        };
+
+        // Only reactions on the bot's own messages are of interest to clients.
+        let message = cached_or_fetch(
+            ctx.cache.message(reaction.channel_id, reaction.message_id),
+            || reaction.message(&ctx.http),
+            cache_fetch_timeout(),
+        )
+        .await;
+        let message = match message {
+            Some(message) => message,
+            None => return,
+        };
+        if !message.is_own(&ctx.cache) {
+            return;
+        }
+
+        self.server
+            .read()
+            .await
+            .send_reaction(
+                reaction.channel_id,
+                user,
+                reaction.message_id,
+                reaction.emoji.to_string(),
+                added,
+            )
+            .await;
+    }
 }
 
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, new_message: Message) {
+        let in_healthcheck = self.healthcheckchannel == Some(new_message.channel_id);
         // Check for statistics messages
-        if new_message.channel_id == self.healthcheckchannel && new_message.content == "/stats" {
+        if in_healthcheck && new_message.content == "/stats" {
             self.server
                 .read()
                 .await
                 .send_stats(new_message.channel_id, ctx.clone())
                 .await;
         }
+        if in_healthcheck && new_message.content == "/stats embed" {
+            self.server
+                .read()
+                .await
+                .send_stats_embed(new_message.channel_id, ctx.clone())
+                .await;
+        }
+        if in_healthcheck && new_message.content == "/clients" {
+            self.server
+                .read()
+                .await
+                .send_clients(new_message.channel_id, ctx.clone())
+                .await;
+        }
+
+        // Check for reset statistics messages, optionally targeting a client IP or ip:port.
+        if in_healthcheck {
+            if let Some(target) = command_argument(&new_message.content, "/reset-stats") {
+                let target = if target.is_empty() {
+                    None
+                } else {
+                    Some(target)
+                };
+                let reset = self.server.read().await.reset_stats(target).await;
+                let reply = format!("Reset stats for {reset} clients");
+                if let Err(error) = new_message.channel_id.say(&ctx, reply).await {
+                    error!("{error}");
+                }
+            }
+        }
+
+        // Check for listener rotation messages, e.g. "/listen 0.0.0.0:23417".
+        if in_healthcheck {
+            if let Some(addr) = new_message.content.strip_prefix("/listen ") {
+                let reply = self.rotate_listener(ctx.clone(), addr).await;
+                if let Err(error) = new_message.channel_id.say(&ctx, reply).await {
+                    error!("{error}");
+                }
+            }
+        }
 
         // Check for health check message.
-        if new_message.is_own(ctx.cache) {
-            if new_message.channel_id == self.healthcheckchannel {
+        if new_message.is_own(&ctx.cache) {
+            if in_healthcheck {
                 if new_message.embeds.len() != 1 {
                     return;
                 }
@@ -63,19 +209,44 @@ impl EventHandler for Handler {
         }
 
         if new_message.is_private() {
+            // Only clients that opted in get direct messages, and only from allowed users.
+            if is_allowed(&allowed_users(), new_message.author.id) {
+                self.server
+                    .read()
+                    .await
+                    .send_direct_command(new_message.author.id, new_message.content)
+                    .await;
+            }
+            return;
+        }
+        if !is_allowed(&allowed_users(), new_message.author.id) {
+            debug!(
+                "Ignoring message from {}, not an allowed user",
+                new_message.author.id
+            );
+            if let Ok(emoji) = env::var("DENIED_REACTION") {
+                if let Err(error) = new_message.react(&ctx, ReactionType::Unicode(emoji)).await {
+                    error!("{error}");
+                }
+            }
             return;
         }
         // Process all other messages as normal.
-        self.server
-            .read()
-            .await
+        let server = self.server.read().await;
+        let forwarded = server
             .send_command(
                 new_message.channel_id,
                 new_message.author.id,
                 new_message.content,
             )
             .await;
-        for attachment in new_message.attachments {
+        if forwarded > 0 && typing_indicator() {
+            server.start_typing(&ctx.http, new_message.channel_id).await;
+        }
+        drop(server);
+        let filenames =
+            attachment_filenames(new_message.attachments.iter().map(|a| a.filename.as_str()));
+        for (attachment, filename) in new_message.attachments.into_iter().zip(filenames) {
             let filedata = attachment.download().await.unwrap();
             self.server
                 .read()
@@ -83,28 +254,145 @@ impl EventHandler for Handler {
                 .send_file(
                     new_message.channel_id,
                     new_message.author.id,
-                    attachment.filename,
+                    filename,
                     filedata,
+                    attachment.content_type.as_deref(),
                 )
                 .await;
         }
     }
 
+    async fn message_update(
+        &self,
+        ctx: Context,
+        _old_if_available: Option<Message>,
+        _new: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        // Ignore DMs, edits that don't touch the content, and the bot's own messages.
+        if event.guild_id.is_none() {
+            return;
+        }
+        let (author, content) = match (event.author, event.content) {
+            (Some(author), Some(content)) => (author, content),
+            _ => return,
+        };
+        if author.id == ctx.cache.current_user_id() {
+            return;
+        }
+        if !is_allowed(&allowed_users(), author.id) {
+            debug!("Ignoring edit from {}, not an allowed user", author.id);
+            return;
+        }
+        self.server
+            .read()
+            .await
+            .send_message_edit(event.channel_id, author.id, event.id, content)
+            .await;
+    }
+
+    async fn message_delete(
+        &self,
+        ctx: Context,
+        channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        guild_id: Option<GuildId>,
+    ) {
+        if guild_id.is_none() {
+            return;
+        }
+        // The author is only known if the message was cached, forward the rest.
+        if let Some(message) = ctx.cache.message(channel_id, deleted_message_id) {
+            if message.is_own(&ctx.cache) {
+                return;
+            }
+        }
+        self.server
+            .read()
+            .await
+            .send_message_delete(channel_id, deleted_message_id)
+            .await;
+    }
+
+    async fn reaction_add(&self, ctx: Context, add_reaction: Reaction) {
+        self.forward_reaction(ctx, add_reaction, true).await;
+    }
+
+    async fn reaction_remove(&self, ctx: Context, removed_reaction: Reaction) {
+        self.forward_reaction(ctx, removed_reaction, false).await;
+    }
+
     async fn ready(&self, _ctx: Context, _ready: Ready) {
         let ctx = Arc::new(_ctx);
-        task::spawn(run_server(ctx, self.server.clone()));
+        if !self.started.swap(true, Ordering::SeqCst) {
+            task::spawn(shutdown_on_signal(ctx.clone(), self.server.clone()));
+            if let Ok(addr) = env::var("METRICS_ADDR") {
+                task::spawn(serve_metrics(addr, self.server.clone()));
+            }
+            if let Some(path) = stats_file() {
+                task::spawn(persist_stats(path, self.server.clone()));
+            }
+            task::spawn(run_server(ctx, self.server.clone(), self.bind));
+        }
     }
 }
 
-async fn run_server(_ctx: Arc<Context>, server: Arc<RwLock<Server>>) {
-    server.read().await.run(_ctx).await
+/// The argument to `command` if `content` is that command, on its own or followed by whitespace.
+fn command_argument<'a>(content: &'a str, command: &str) -> Option<&'a str> {
+    let rest = content.strip_prefix(command)?;
+    (rest.is_empty() || rest.starts_with(char::is_whitespace)).then_some(rest.trim())
+}
+
+async fn run_server(_ctx: Arc<Context>, server: Arc<RwLock<Server>>, bind: SocketAddr) {
+    server.read().await.run(_ctx, bind).await
+}
+
+/// Waits for SIGTERM or SIGINT, then lets clients finish what they are doing before exiting.
+async fn shutdown_on_signal(ctx: Arc<Context>, server: Arc<RwLock<Server>>) {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(error) => {
+            error!("Failed to install SIGTERM handler: {error}");
+            return;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+
+    info!("Shutting down");
+    let server = server.read().await;
+    server.shutdown().await;
+    if !server.wait_for_clients(shutdown_timeout()).await {
+        warn!("Clients still connected after the shutdown timeout");
+    }
+    server.flush_presence(ctx).await;
+    if let Some(path) = stats_file() {
+        if let Err(error) = persist::save(&path, &server.totals().await) {
+            error!("Failed to save stats to {}: {error}", path.display());
+        }
+    }
+    exit(0);
+}
+
+async fn run_listener(
+    ctx: Arc<Context>,
+    server: Arc<RwLock<Server>>,
+    listeners: Vec<Listener>,
+    stop: Receiver<()>,
+) {
+    server.read().await.listen(listeners, ctx, stop).await
 }
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init_timed();
+    // The logger reads its settings from the environment, so .env is loaded first and any error
+    // loading it is only logged once the logger is up.
+    let dotenv = dotenvy::dotenv();
+    logging::init();
 
-    match dotenvy::dotenv() {
+    match dotenv {
         Ok(_) => {}
         Err(e) => {
             warn!("Error loading .env file: {}", e);
@@ -122,23 +410,59 @@ async fn main() {
             &_ => {}
         }
     }
-    error!("Usage: TODO");
+    error!("Usage: discordshim serve|healthcheck");
+    exit(EXIT_MISCONFIGURED);
 }
 
 async fn serve() -> i32 {
     let framework = StandardFramework::new().configure(|c| c.prefix("~"));
-    let channelid: u64 = env::var("HEALTH_CHECK_CHANNEL_ID")
-        .expect("channel id")
-        .parse()
-        .unwrap();
+    let healthcheckchannel = match env::var("HEALTH_CHECK_CHANNEL_ID") {
+        Ok(channel) => match channel.parse() {
+            Ok(channel) => Some(ChannelId(channel)),
+            Err(e) => {
+                error!("Invalid HEALTH_CHECK_CHANNEL_ID [{channel}]: {e}");
+                return EXIT_MISCONFIGURED;
+            }
+        },
+        Err(_) => None,
+    };
+    let bind = match bind_address() {
+        Ok(bind) => bind,
+        Err(e) => {
+            error!("{e}");
+            return EXIT_MISCONFIGURED;
+        }
+    };
+
+    let tls = match tls_acceptor() {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("{e}");
+            return EXIT_MISCONFIGURED;
+        }
+    };
+
+    let mut server = Server::new();
+    if let Some(acceptor) = tls {
+        info!("Serving TCP clients over TLS");
+        server.use_tls(acceptor);
+    }
+    if let Some(path) = stats_file() {
+        server.restore_totals(persist::load(&path)).await;
+    }
 
     let handler = Handler {
-        healthcheckchannel: ChannelId(channelid),
-        server: Arc::new(RwLock::new(Server::new())),
+        healthcheckchannel,
+        server: Arc::new(RwLock::new(server)),
+        bind,
+        started: AtomicBool::new(false),
     };
 
     // Login with a bot token from the environment
-    let token = env::var("DISCORD_TOKEN").expect("token");
+    let Ok(token) = env::var("DISCORD_TOKEN") else {
+        error!("DISCORD_TOKEN is not set");
+        return EXIT_MISCONFIGURED;
+    };
     let intents = GatewayIntents::non_privileged() | GatewayIntents::MESSAGE_CONTENT;
     let mut client: Client = Client::builder(token, intents)
         .event_handler(handler)
@@ -149,7 +473,7 @@ async fn serve() -> i32 {
     // start listening for events by starting a single shard
     if let Err(why) = client.start().await {
         error!("An error occurred while running the client: {:?}", why);
-        return -1;
+        return EXIT_UNREACHABLE;
     }
     0
 }
@@ -0,0 +1,230 @@
+use crate::persist::Totals;
+use crate::server::Server;
+use async_std::future::timeout;
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::sync::RwLock;
+use log::{debug, error, info};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+const MAX_REQUEST_BYTES: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counters exposed on the metrics endpoint.
+pub(crate) struct Metrics {
+    pub(crate) connected_clients: usize,
+    pub(crate) forwarded_messages: u64,
+    pub(crate) forwarded_bytes: u64,
+    /// Messages and bytes of the clients currently connected, by the channel they post to.
+    pub(crate) channels: BTreeMap<u64, (u64, u64)>,
+    /// Running totals by client IP, including clients no longer connected.
+    pub(crate) clients: BTreeMap<String, Totals>,
+}
+
+/// Whether the totals of each client IP are exposed, on when `METRICS_PER_CLIENT` is set. Every IP
+/// that ever connected gets its own series, so this is off unless the clients are few and known.
+fn metrics_per_client() -> bool {
+    env::var("METRICS_PER_CLIENT").is_ok()
+}
+
+/// Formats `metrics` in the Prometheus text exposition format, with the totals of each client IP
+/// when `per_client` is set.
+fn render(metrics: &Metrics, per_client: bool) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# HELP discordshim_connected_clients Clients currently connected.\n\
+         # TYPE discordshim_connected_clients gauge\n\
+         discordshim_connected_clients {}",
+        metrics.connected_clients
+    );
+    let _ = writeln!(
+        out,
+        "# HELP discordshim_messages_total Messages received from clients.\n\
+         # TYPE discordshim_messages_total counter\n\
+         discordshim_messages_total {}",
+        metrics.forwarded_messages
+    );
+    let _ = writeln!(
+        out,
+        "# HELP discordshim_bytes_total Bytes received from clients.\n\
+         # TYPE discordshim_bytes_total counter\n\
+         discordshim_bytes_total {}",
+        metrics.forwarded_bytes
+    );
+    out.push_str(
+        "# HELP discordshim_channel_messages Messages from the connected clients of a channel.\n\
+         # TYPE discordshim_channel_messages gauge\n",
+    );
+    for (channel, (messages, _)) in &metrics.channels {
+        let _ = writeln!(
+            out,
+            "discordshim_channel_messages{{channel=\"{channel}\"}} {messages}"
+        );
+    }
+    out.push_str(
+        "# HELP discordshim_channel_bytes Bytes from the connected clients of a channel.\n\
+         # TYPE discordshim_channel_bytes gauge\n",
+    );
+    for (channel, (_, bytes)) in &metrics.channels {
+        let _ = writeln!(
+            out,
+            "discordshim_channel_bytes{{channel=\"{channel}\"}} {bytes}"
+        );
+    }
+    if !per_client {
+        return out;
+    }
+    out.push_str(
+        "# HELP discordshim_client_messages_total Messages received from a client IP.\n\
+         # TYPE discordshim_client_messages_total counter\n",
+    );
+    for (ip, totals) in &metrics.clients {
+        let _ = writeln!(
+            out,
+            "discordshim_client_messages_total{{ip=\"{ip}\"}} {}",
+            totals.num_messages
+        );
+    }
+    out.push_str(
+        "# HELP discordshim_client_bytes_total Bytes received from a client IP.\n\
+         # TYPE discordshim_client_bytes_total counter\n",
+    );
+    for (ip, totals) in &metrics.clients {
+        let _ = writeln!(
+            out,
+            "discordshim_client_bytes_total{{ip=\"{ip}\"}} {}",
+            totals.total_data
+        );
+    }
+    out
+}
+
+/// Serves the metrics on `addr`, from `METRICS_ADDR`, until the process exits.
+pub(crate) async fn serve_metrics(addr: String, server: Arc<RwLock<Server>>) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to serve metrics on {addr}: {e}");
+            return;
+        }
+    };
+    info!("Serving metrics on {addr}");
+    serve(listener, server).await
+}
+
+async fn serve(listener: TcpListener, server: Arc<RwLock<Server>>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                debug!("Metrics accept failed with [{e}]");
+                continue;
+            }
+        };
+        if let Err(e) = respond(stream, &server).await {
+            debug!("Metrics request failed with [{e}]");
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, server: &RwLock<Server>) -> std::io::Result<()> {
+    let request = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request) => request?,
+        Err(_) => return Ok(()),
+    };
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let response = if path == "/metrics" {
+        let body = render(&server.read().await.metrics().await, metrics_per_client());
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await
+}
+
+/// Reads the request head, which is all that is needed to route it.
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut request = vec![];
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::{render, serve, Metrics};
+    use crate::persist::Totals;
+    use crate::server::Server;
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::sync::RwLock;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics {
+            connected_clients: 2,
+            forwarded_messages: 10,
+            forwarded_bytes: 1234,
+            channels: BTreeMap::from([(42, (7, 1000)), (43, (3, 234))]),
+            clients: BTreeMap::from([(
+                "10.0.0.2".to_string(),
+                Totals {
+                    num_messages: 12,
+                    total_data: 3456,
+                },
+            )]),
+        };
+        let rendered = render(&metrics, true);
+        assert!(rendered.contains("# TYPE discordshim_messages_total counter\n"));
+        assert!(rendered.contains("\ndiscordshim_connected_clients 2\n"));
+        assert!(rendered.contains("\ndiscordshim_messages_total 10\n"));
+        assert!(rendered.contains("\ndiscordshim_bytes_total 1234\n"));
+        assert!(rendered.contains("\ndiscordshim_channel_messages{channel=\"42\"} 7\n"));
+        assert!(rendered.contains("\ndiscordshim_channel_bytes{channel=\"43\"} 234\n"));
+        assert!(rendered.contains("\ndiscordshim_client_messages_total{ip=\"10.0.0.2\"} 12\n"));
+        assert!(rendered.contains("\ndiscordshim_client_bytes_total{ip=\"10.0.0.2\"} 3456\n"));
+
+        let rendered = render(&metrics, false);
+        assert!(rendered.contains("\ndiscordshim_channel_bytes{channel=\"43\"} 234\n"));
+        assert!(!rendered.contains("discordshim_client_"));
+    }
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[async_std::test]
+    async fn test_serves_metrics() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        async_std::task::spawn(serve(listener, Arc::new(RwLock::new(Server::new()))));
+
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\ndiscordshim_connected_clients 0\n"));
+
+        let response = get(addr, "/").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,101 @@
+use crate::server::Server;
+use async_std::sync::RwLock;
+use async_std::task;
+use log::{debug, error, warn};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_STATS_SAVE_INTERVAL_SECS: u64 = 60;
+
+/// Running totals of a client, kept across connections and restarts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Totals {
+    pub(crate) num_messages: u64,
+    pub(crate) total_data: u64,
+}
+
+/// File the totals are kept in, from `STATS_FILE`. Totals are not persisted when unset.
+pub(crate) fn stats_file() -> Option<PathBuf> {
+    env::var("STATS_FILE").ok().map(PathBuf::from)
+}
+
+/// How often the totals are saved, read from `STATS_SAVE_INTERVAL_SECS`.
+fn save_interval() -> Duration {
+    let secs = env::var("STATS_SAVE_INTERVAL_SECS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_STATS_SAVE_INTERVAL_SECS);
+    Duration::from_secs(secs)
+}
+
+/// Loads the totals saved at `path` by client IP. A missing or unreadable file starts from zero.
+pub(crate) fn load(path: &Path) -> BTreeMap<String, Totals> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return BTreeMap::new(),
+        Err(error) => {
+            warn!("Failed to read stats from {}: {error}", path.display());
+            return BTreeMap::new();
+        }
+    };
+    serde_json::from_str(&data).unwrap_or_else(|error| {
+        warn!("Ignoring invalid stats in {}: {error}", path.display());
+        BTreeMap::new()
+    })
+}
+
+/// Saves `totals` to `path`. The file is written next to it and synced to disk first, then renamed
+/// over it, so a crash mid-write leaves the previous totals intact.
+pub(crate) fn save(path: &Path, totals: &BTreeMap<String, Totals>) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(&serde_json::to_vec(totals)?)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+/// Saves the server's totals to `path` periodically, until the process exits.
+pub(crate) async fn persist_stats(path: PathBuf, server: Arc<RwLock<Server>>) {
+    loop {
+        task::sleep(save_interval()).await;
+        let totals = server.read().await.totals().await;
+        match save(&path, &totals) {
+            Ok(()) => debug!("Saved stats to {}", path.display()),
+            Err(error) => error!("Failed to save stats to {}: {error}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::persist::{load, save, Totals};
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_save_and_load() {
+        let path = env::temp_dir().join(format!("discordshim-stats-{}.json", uuid::Uuid::new_v4()));
+        assert!(load(&path).is_empty());
+
+        let totals = BTreeMap::from([(
+            "127.0.0.1".to_string(),
+            Totals {
+                num_messages: 3,
+                total_data: 1024,
+            },
+        )]);
+        save(&path, &totals).unwrap();
+        assert_eq!(totals, load(&path));
+
+        fs::write(&path, "not json").unwrap();
+        assert!(load(&path).is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
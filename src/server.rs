@@ -1,63 +1,463 @@
-use crate::embedbuilder::{build_embeds, split_file};
+use crate::cache::{cache_fetch_timeout, cached_or_fetch};
+use crate::embedbuilder::{
+    attachment_size_limit, build_embeds, flatten_embed, inline_file, inline_file_max_bytes,
+    inline_file_parts, inline_file_split, parse_color, split_content, split_file, Markers,
+    DISCORD_MAX_ATTACHMENTS, DISCORD_MAX_CONTENT,
+};
+use crate::framing::{
+    codec_detect_timeout, gunzip, idle_timeout, length_prefix_timeout, max_frame_size, peek_codec,
+    read_length, read_line, write_frame, write_gzip_frame, write_line, write_timeout, Codec,
+    GZIP_FLAG,
+};
 use crate::messages;
 use crate::messages::EmbedContent;
-use async_std::io::{ReadExt, WriteExt};
+use crate::metrics::Metrics;
+use crate::persist::Totals;
+use crate::stream::{
+    accept_tls, bind_unix, tcp_listener, tls_handshake_timeout, unix_socket_path, ClientStream,
+    Listener, Peekable, Peer,
+};
+use crate::transform::{load_transforms, MessageTransform};
+use crate::webhook::{event_webhook_url, notify, unix_time, ConnectionEvent};
+use async_std::channel::{self, Receiver, Sender};
+use async_std::future::timeout;
+use async_std::io::{BufReader, ReadExt, WriteExt};
 use async_std::net::TcpListener;
-use async_std::net::TcpStream;
+use async_std::net::{Shutdown, SocketAddr};
 use async_std::sync::{Mutex, RwLock};
-use byteorder::{ByteOrder, LittleEndian};
+use async_std::task;
 use csv::Writer;
-use futures::stream::StreamExt;
-use log::{debug, error, info};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future::{self, join};
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
+use futures_rustls::TlsAcceptor;
+use log::{debug, error, info, warn};
 use protobuf::Message;
 use regex::Regex;
+use serenity::async_trait;
+use serenity::builder::{CreateEmbed, CreateMessage, ParseValue};
 use serenity::client::Context;
-use serenity::model::id::{ChannelId, UserId};
+use serenity::http::{Http, HttpError, Typing};
+use serenity::model::channel::{Channel, ChannelType};
+use serenity::model::id::{ChannelId, MessageId, UserId};
 use serenity::model::prelude::OnlineStatus;
 use serenity::model::prelude::{Activity, AttachmentType};
+use serenity::model::Timestamp;
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::env;
+use std::future::Future;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
+use url::Url;
+
+/// Optional protocol features this server supports, advertised in the greeting.
+const FEATURES: &[&str] = &["reactions", "pin", "fanout", "gzip", "jsonlines"];
+
+/// Counters saturate rather than wrap, once they come within a single maximum sized frame of the
+/// ceiling they can no longer be trusted to be exact.
+const STATS_CEILING: u64 = u64::MAX - u32::MAX as u64;
 
 #[derive(serde::Serialize)]
 struct Stats {
     ip: String,
     num_messages: u64,
     total_data: u64,
+    dropped_presence: u64,
+    dropped_frames: u64,
+    /// Unix timestamp the client connected at.
+    connected_at: u64,
+    connected_seconds: u64,
+}
+
+impl Stats {
+    fn is_approximate(&self) -> bool {
+        self.num_messages >= STATS_CEILING || self.total_data >= STATS_CEILING
+    }
+}
+
+/// Where a message forwarded to clients was posted.
+#[derive(Clone, Copy)]
+enum Origin {
+    Channel(ChannelId),
+    /// A direct message to the bot from this user.
+    DirectMessage(UserId),
+}
+
+/// Cancelled once a client's connection ends, so in-flight multi-part sends can stop early.
+#[derive(Default)]
+struct CancellationToken(AtomicBool);
+
+impl CancellationToken {
+    fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
 }
 
 struct DiscordSettings {
-    tcpstream: RwLock<TcpStream>,
+    stream: RwLock<ClientStream>,
+    // Taken once when the connection is accepted, so it's known even after the socket is gone.
+    peer: Peer,
+    codec: Codec,
     channel: RwLock<ChannelId>,
+    routes: RwLock<HashMap<String, ChannelId>>,
+    allowed_users: RwLock<Vec<UserId>>,
     // Only relevant when self-hosting, global discordshim won't support presence anyway
     prefix: Mutex<String>,
     cycle_time: Mutex<i32>,
     enabled: Mutex<bool>,
+    plain_text: Mutex<bool>,
+    forward_dms: Mutex<bool>,
+    gzip: Mutex<bool>,
+    disconnect_reason: Mutex<Option<String>>,
+    // Messages sent for EditEmbed responses, by the client's key.
+    edited_messages: Mutex<HashMap<String, (ChannelId, MessageId)>>,
+    // The latest messages reported to the client in a MessageSent, the only ones it may delete by
+    // id.
+    sent_messages: Mutex<VecDeque<(ChannelId, MessageId)>>,
+    // Pings unanswered in a row, counted once the client has answered one.
+    missed_pongs: Mutex<Option<u32>>,
     num_messages: Mutex<u64>,
     total_data: Mutex<u64>,
+    dropped_presence: Mutex<u64>,
+    // Frames to the client dropped because its outbound queue was full.
+    dropped_frames: Mutex<u64>,
+    outbound: OutboundQueue,
+    writer: Mutex<Option<task::JoinHandle<()>>>,
+    rate_limit: Mutex<TokenBucket>,
+    cancel: Arc<CancellationToken>,
+    connected_at: SystemTime,
 }
 
 impl DiscordSettings {
+    /// Wraps a client's connection from `peer`, starting the task that writes to it.
+    fn new(stream: impl Into<ClientStream>, peer: Peer) -> DiscordSettings {
+        let stream = stream.into();
+        let cancel = Arc::new(CancellationToken::default());
+        let (outbound, frames, rung) = OutboundQueue::new(outbound_queue(), Overflow::from_env());
+        let writer = task::spawn(write_frames(
+            stream.clone(),
+            peer.clone(),
+            frames,
+            rung,
+            write_timeout(),
+            cancel.clone(),
+        ));
+        DiscordSettings {
+            stream: RwLock::new(stream),
+            peer,
+            codec: Codec::Protobuf,
+            channel: RwLock::new(ChannelId(0)),
+            routes: RwLock::new(HashMap::new()),
+            allowed_users: RwLock::new(vec![]),
+            prefix: Mutex::new("".to_string()),
+            cycle_time: Mutex::new(0),
+            enabled: Mutex::new(false),
+            plain_text: Mutex::new(false),
+            forward_dms: Mutex::new(false),
+            gzip: Mutex::new(false),
+            disconnect_reason: Mutex::new(None),
+            edited_messages: Mutex::new(HashMap::new()),
+            sent_messages: Mutex::new(VecDeque::new()),
+            missed_pongs: Mutex::new(None),
+            num_messages: Mutex::new(0),
+            total_data: Mutex::new(0),
+            dropped_presence: Mutex::new(0),
+            dropped_frames: Mutex::new(0),
+            outbound,
+            writer: Mutex::new(Some(writer)),
+            rate_limit: Mutex::new(TokenBucket::new(rate_limit_per_minute(), Instant::now())),
+            cancel,
+            connected_at: SystemTime::now(),
+        }
+    }
+
+    fn peer(&self) -> String {
+        self.peer.to_string()
+    }
+
+    async fn record_message(&self, size: u64) {
+        let mut num_messages = self.num_messages.lock().await;
+        *num_messages = num_messages.saturating_add(1);
+        let mut total_data = self.total_data.lock().await;
+        *total_data = total_data.saturating_add(size);
+    }
+
+    /// Queues `request` for the client's writer without waiting for it to be written. Fails once
+    /// the writer has given up on the client.
+    async fn send_request(&self, request: &messages::Request) -> std::io::Result<()> {
+        let frame = Frame {
+            data: self.encode_frame(request).await?,
+            droppable: is_droppable(request),
+        };
+        if self.outbound.push(frame)? {
+            let mut dropped = self.dropped_frames.lock().await;
+            *dropped = dropped.saturating_add(1);
+            if *dropped == 1 || dropped.is_power_of_two() {
+                let peer = self.peer();
+                warn!(
+                    peer = peer.as_str();
+                    "Outbound queue for {peer} is full, {dropped} frames dropped so far"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Lets the writer finish what is queued, for up to `write_timeout`, then closes the
+    /// connection.
+    async fn flush_and_close(&self, write_timeout: Duration) {
+        self.outbound.close();
+        if let Some(writer) = self.writer.lock().await.take() {
+            if timeout(write_timeout, writer).await.is_err() {
+                debug!("Gave up on writing to {}", self.peer());
+            }
+        }
+        let _ = self.stream.read().await.shutdown(Shutdown::Both);
+    }
+
+    /// Encodes `request` as it goes on the wire in the protocol the client speaks, compressed if
+    /// the client asked for gzip.
+    async fn encode_frame(&self, request: &messages::Request) -> std::io::Result<Vec<u8>> {
+        let data = self.codec.encode(request)?;
+        let mut frame = vec![];
+        match self.codec {
+            Codec::Protobuf if *self.gzip.lock().await => {
+                write_gzip_frame(&mut frame, &data).await?
+            }
+            Codec::Protobuf => write_frame(&mut frame, &data).await?,
+            Codec::JsonLines => write_line(&mut frame, &data).await?,
+        }
+        Ok(frame)
+    }
+
+    async fn stats_reply(&self) -> messages::Request {
+        let uptime = SystemTime::now()
+            .duration_since(self.connected_at)
+            .unwrap_or_default();
+        let stats = messages::ClientStats {
+            num_messages: *self.num_messages.lock().await,
+            total_data: *self.total_data.lock().await,
+            uptime_seconds: uptime.as_secs(),
+            ..Default::default()
+        };
+
+        messages::Request {
+            message: Some(messages::request::Message::ClientStats(stats)),
+            ..Default::default()
+        }
+    }
+
+    /// The channel a response is sent to: its route's channel if it names one, otherwise the
+    /// default channel.
+    async fn target_channel(&self, route: &str) -> ChannelId {
+        if !route.is_empty() {
+            match self.routes.read().await.get(route) {
+                Some(channel) => return *channel,
+                None => warn!("Unknown route [{route}], using the default channel"),
+            }
+        }
+        *self.channel.read().await
+    }
+
+    /// Whether messages posted in `channel` are meant for this client.
+    async fn listens_to(&self, channel: ChannelId) -> bool {
+        channel.0 != 0
+            && (*self.channel.read().await == channel
+                || self.routes.read().await.values().any(|c| *c == channel))
+    }
+
+    /// Whether messages from `origin` are meant for this client.
+    async fn receives(&self, origin: Origin) -> bool {
+        match origin {
+            Origin::Channel(channel) => self.listens_to(channel).await,
+            Origin::DirectMessage(user) => {
+                // Unlike commands in a channel, an empty allowlist lets no one in.
+                *self.forward_dms.lock().await && self.allowed_users.read().await.contains(&user)
+            }
+        }
+    }
+
+    /// Stores the validated form of `new_settings` and returns what was stored.
+    async fn apply_settings(&self, new_settings: messages::Settings) -> messages::Settings {
+        let applied = validate_settings(new_settings, is_cloud_server());
+        *self.channel.write().await = ChannelId(applied.channel_id);
+        *self.routes.write().await = applied
+            .routes
+            .iter()
+            .map(|route| (route.name.clone(), ChannelId(route.channel_id)))
+            .collect();
+        *self.allowed_users.write().await =
+            applied.allowed_users.iter().copied().map(UserId).collect();
+        self.prefix.lock().await.clone_from(&applied.command_prefix);
+        *self.cycle_time.lock().await = applied.cycle_time;
+        *self.enabled.lock().await = applied.presence_enabled;
+        *self.plain_text.lock().await = applied.plain_text;
+        *self.forward_dms.lock().await = applied.forward_dms;
+        *self.gzip.lock().await = applied.gzip;
+        applied
+    }
+
+    /// Records why the client is leaving and stops reading from it, so the reader stops without
+    /// waiting for the client to close the connection. Responses already read are still handled
+    /// and their replies written before the connection is closed.
+    async fn disconnect(&self, reason: String) {
+        info!("Client is disconnecting: {reason}");
+        *self.disconnect_reason.lock().await = Some(reason);
+        let _ = self.stream.read().await.shutdown(Shutdown::Read);
+    }
+
+    async fn reset_stats(&self) {
+        *self.num_messages.lock().await = 0;
+        *self.total_data.lock().await = 0;
+        *self.dropped_presence.lock().await = 0;
+        *self.dropped_frames.lock().await = 0;
+    }
+
     async fn get_stats(&self) -> Stats {
         Stats {
-            ip: self
-                .tcpstream
-                .read()
-                .await
-                .peer_addr()
-                .unwrap()
-                .to_string()
-                .clone(),
+            ip: self.peer(),
             num_messages: *self.num_messages.lock().await,
             total_data: *self.total_data.lock().await,
+            dropped_presence: *self.dropped_presence.lock().await,
+            dropped_frames: *self.dropped_frames.lock().await,
+            connected_at: self
+                .connected_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            connected_seconds: SystemTime::now()
+                .duration_since(self.connected_at)
+                .unwrap_or_default()
+                .as_secs(),
         }
     }
 }
 
+/// What the server does in Discord for its clients: posting what their responses ask for, and
+/// updating the presence as they come and go. The server does it in Discord, tests record it
+/// instead.
+#[async_trait]
+trait Dispatch: Send + Sync {
+    /// Posts `file` to `channel`, returning the messages it was posted as.
+    async fn send_file(
+        &self,
+        server: &Server,
+        channel: ChannelId,
+        file: &messages::ProtoFile,
+        cancel: &CancellationToken,
+    ) -> serenity::Result<Vec<MessageId>>;
+
+    /// Posts `embed` to `channel`, returning the messages it was posted as.
+    async fn send_embed(
+        &self,
+        server: &Server,
+        channel: ChannelId,
+        embed: messages::EmbedContent,
+        plain_text: bool,
+        cancel: &CancellationToken,
+    ) -> serenity::Result<Vec<MessageId>>;
+
+    async fn edit_embed(
+        &self,
+        server: &Server,
+        settings: &DiscordSettings,
+        channel: ChannelId,
+        edit: messages::EditEmbed,
+    ) -> serenity::Result<MessageId>;
+
+    async fn delete_message(&self, channel: ChannelId, message: MessageId) -> serenity::Result<()>;
+
+    async fn create_dm_channel(&self, user: UserId) -> serenity::Result<ChannelId>;
+
+    async fn set_presence(&self, activity: Activity);
+
+    async fn clients_changed(&self, server: &Server, num_servers: usize);
+}
+
+struct DiscordDispatch(Arc<Context>);
+
+#[async_trait]
+impl Dispatch for DiscordDispatch {
+    async fn send_file(
+        &self,
+        server: &Server,
+        channel: ChannelId,
+        file: &messages::ProtoFile,
+        cancel: &CancellationToken,
+    ) -> serenity::Result<Vec<MessageId>> {
+        server.send_protofile(&self.0, channel, file, cancel).await
+    }
+
+    async fn send_embed(
+        &self,
+        server: &Server,
+        channel: ChannelId,
+        embed: messages::EmbedContent,
+        plain_text: bool,
+        cancel: &CancellationToken,
+    ) -> serenity::Result<Vec<MessageId>> {
+        server
+            .send_embed(&self.0, channel, embed, plain_text, cancel)
+            .await
+    }
+
+    async fn edit_embed(
+        &self,
+        server: &Server,
+        settings: &DiscordSettings,
+        channel: ChannelId,
+        edit: messages::EditEmbed,
+    ) -> serenity::Result<MessageId> {
+        server
+            .send_edit_embed(&self.0, settings, channel, edit)
+            .await
+    }
+
+    async fn delete_message(&self, channel: ChannelId, message: MessageId) -> serenity::Result<()> {
+        delete_message(&self.0, channel, message).await
+    }
+
+    async fn create_dm_channel(&self, user: UserId) -> serenity::Result<ChannelId> {
+        Ok(user.create_dm_channel(&self.0).await?.id)
+    }
+
+    async fn set_presence(&self, activity: Activity) {
+        self.0
+            .shard
+            .set_presence(Some(activity), OnlineStatus::Online);
+    }
+
+    async fn clients_changed(&self, server: &Server, num_servers: usize) {
+        server.update_presence(self.0.clone(), num_servers).await;
+    }
+}
+
 pub(crate) struct Server {
     clients: Arc<Mutex<Vec<Arc<DiscordSettings>>>>,
     last_presense_update: Mutex<SystemTime>,
+    pinned: Mutex<HashMap<ChannelId, MessageId>>,
+    transforms: Vec<Box<dyn MessageTransform>>,
+    listener_stop: Mutex<Option<Sender<()>>>,
+    clean_disconnects: Mutex<u64>,
+    dropped_connections: Mutex<u64>,
+    // Unlike the per-client counters, these are never reset, as metrics scrapers expect.
+    forwarded_messages: Mutex<u64>,
+    forwarded_bytes: Mutex<u64>,
+    started_at: SystemTime,
+    typing: Arc<Mutex<TypingIndicators<Typing>>>,
+    dm_channels: Mutex<HashMap<UserId, ChannelId>>,
+    // Running totals by client IP, which outlive connections and, with a stats file, restarts.
+    totals: Mutex<BTreeMap<String, Totals>>,
+    // Terminates TLS on TCP connections when set. Unix socket clients are local, so stay plain.
+    tls: Option<TlsAcceptor>,
 }
 
 impl Server {
@@ -65,67 +465,308 @@ impl Server {
         Server {
             clients: Arc::new(Mutex::new(Vec::new())),
             last_presense_update: Mutex::new(SystemTime::UNIX_EPOCH),
+            pinned: Mutex::new(HashMap::new()),
+            transforms: load_transforms(),
+            listener_stop: Mutex::new(None),
+            clean_disconnects: Mutex::new(0),
+            dropped_connections: Mutex::new(0),
+            forwarded_messages: Mutex::new(0),
+            forwarded_bytes: Mutex::new(0),
+            started_at: SystemTime::now(),
+            typing: Arc::new(Mutex::new(TypingIndicators::default())),
+            dm_channels: Mutex::new(HashMap::new()),
+            totals: Mutex::new(BTreeMap::new()),
+            tls: None,
+        }
+    }
+
+    /// Requires TCP clients to connect over TLS, using `acceptor`.
+    pub(crate) fn use_tls(&mut self, acceptor: TlsAcceptor) {
+        self.tls = Some(acceptor);
+    }
+
+    /// Accepts clients on `bind`, unless TCP is turned off, and on the Unix socket if one is
+    /// configured. A listener already running, which may have been rotated in by `/listen`, is
+    /// kept instead.
+    pub(crate) async fn run(&self, ctx: Arc<Context>, bind: SocketAddr) {
+        if self.listener_stop.lock().await.is_some() {
+            debug!("Already accepting clients, keeping the current listener");
+            return;
+        }
+        let mut listeners = vec![];
+        if tcp_listener() {
+            debug!("Starting TCP listener on {bind}");
+            let listener = TcpListener::bind(bind).await.expect("Failed to bind");
+            listeners.push(listener.into());
+        }
+        listeners.extend(unix_listener().await);
+        if listeners.is_empty() {
+            error!("Not accepting clients, DISABLE_TCP_LISTENER is set and UNIX_SOCKET is not set");
+            return;
+        }
+        let (stop, _) = self.replace_listener().await;
+        self.listen(listeners, ctx, stop).await;
+    }
+
+    /// Stops accepting connections and tells every client the server is going away. Clients stop
+    /// being read from, which counts as a clean close: any response being handled is finished and
+    /// its replies written before the connection is closed.
+    pub(crate) async fn shutdown(&self) {
+        if let Some(stop) = self.listener_stop.lock().await.take() {
+            let _ = stop.send(()).await;
+        }
+        let goodbye = messages::Request {
+            message: Some(messages::request::Message::Disconnect(
+                messages::Disconnect {
+                    reason: "Server shutting down".to_string(),
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        };
+        for client in self.clients.lock().await.iter() {
+            if let Err(error) = client.send_request(&goodbye).await {
+                debug!("Failed to send shutdown notice: {error}");
+            }
+            let _ = client.stream.read().await.shutdown(Shutdown::Read);
         }
     }
 
-    pub(crate) async fn run(&self, ctx: Arc<Context>) {
-        debug!("Starting TCP listener");
-        let listener = TcpListener::bind("0.0.0.0:23416")
+    /// Waits up to `deadline` for every client to disconnect. Returns whether they all did.
+    pub(crate) async fn wait_for_clients(&self, deadline: Duration) -> bool {
+        let drained = async {
+            while !self.clients.lock().await.is_empty() {
+                async_std::task::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        timeout(deadline, drained).await.is_ok()
+    }
+
+    /// Updates the presence now, skipping the usual rate limit.
+    pub(crate) async fn flush_presence(&self, ctx: Arc<Context>) {
+        *self.last_presense_update.lock().await = SystemTime::UNIX_EPOCH;
+        let num_servers = self.clients.lock().await.len();
+        self.update_presence(ctx, num_servers).await;
+    }
+
+    /// Makes the caller the active listener. Returns its stop signal, along with the stop handle
+    /// of the listener it replaces, if any.
+    pub(crate) async fn replace_listener(&self) -> (Receiver<()>, Option<Sender<()>>) {
+        let (sender, receiver) = channel::bounded(1);
+        let previous = self.listener_stop.lock().await.replace(sender);
+        (receiver, previous)
+    }
+
+    pub(crate) async fn listen(
+        &self,
+        listeners: Vec<Listener>,
+        ctx: Arc<Context>,
+        stop: Receiver<()>,
+    ) {
+        self.listen_with(listeners, Arc::new(DiscordDispatch(ctx)), stop)
             .await
-            .expect("Failed to bind");
-        listener
-            .incoming()
-            .for_each_concurrent(None, |tcpstream| {
-                let ctx2 = ctx.clone();
-                let clients2 = self.clients.clone();
-                async move {
-                    let f = ctx2.clone();
-                    let c = clients2.clone();
-                    let stream = tcpstream.unwrap();
-                    let peer_addr = stream.peer_addr().unwrap();
-                    info!("Received connection from: {}", peer_addr);
-
-                    let settings = Arc::new(DiscordSettings {
-                        tcpstream: RwLock::new(stream.clone()),
-                        channel: RwLock::new(ChannelId(0)),
-                        prefix: Mutex::new("".to_string()),
-                        cycle_time: Mutex::new(0),
-                        enabled: Mutex::new(false),
-                        num_messages: Mutex::new(0),
-                        total_data: Mutex::new(0),
-                    });
-
-                    c.lock().await.insert(0, settings.clone());
-
-                    let num_servers = c.lock().await.len();
-                    self.update_presence(ctx2.clone(), num_servers).await;
-
-                    let _loop_res = self.connection_loop(stream, settings.clone(), f).await;
-                    c.lock()
-                        .await
-                        .retain(|item| !Arc::<DiscordSettings>::ptr_eq(item, &settings));
+    }
+
+    /// Accepts connections on all of `listeners` until `stop` fires, acting on the responses they
+    /// send through `dispatch`.
+    async fn listen_with(
+        &self,
+        listeners: Vec<Listener>,
+        dispatch: Arc<dyn Dispatch>,
+        stop: Receiver<()>,
+    ) {
+        let addrs: Vec<String> = listeners.iter().map(Listener::describe).collect();
+        let incoming =
+            futures::stream::select_all(listeners.into_iter().map(Listener::into_incoming));
+        accept_until(incoming, stop, |stream| {
+            self.handle_connection(stream, dispatch.clone())
+        })
+        .await;
+        debug!("Stopped listener on {}", addrs.join(", "));
+    }
 
-                    let num_servers = c.lock().await.len();
-                    self.update_presence(ctx2.clone(), num_servers).await;
+    async fn handle_connection(&self, stream: ClientStream, dispatch: Arc<dyn Dispatch>) {
+        let c = self.clients.clone();
+        let peer_addr = match stream.peer() {
+            Ok(peer) => peer,
+            Err(error) => {
+                warn!("Dropping connection with no peer address: {error}");
+                return;
+            }
+        };
+        info!(peer:% = peer_addr; "Received connection from: {}", peer_addr);
 
-                    info!("Dropped connection from: {}", peer_addr);
+        let stream = match (&self.tls, stream) {
+            (Some(acceptor), ClientStream::Tcp(tcp)) => {
+                match accept_tls(acceptor, tcp, tls_handshake_timeout()).await {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        warn!(peer:% = peer_addr; "TLS handshake with {peer_addr} failed: {error}");
+                        return;
+                    }
                 }
-            })
+            }
+            (_, stream) => stream,
+        };
+
+        let mut reader = Peekable::new(stream.clone());
+        let codec = peek_codec(&mut reader, codec_detect_timeout()).await;
+        debug!(peer:% = peer_addr; "{peer_addr} speaks {codec:?}");
+
+        if let Some(token) = auth_token() {
+            let authenticated = match codec {
+                Codec::Protobuf => authenticate(&mut reader, &token, auth_timeout()).await,
+                Codec::JsonLines => authenticate_json(&mut reader, &token, auth_timeout()).await,
+            };
+            if !authenticated {
+                warn!(
+                    peer:% = peer_addr;
+                    "{peer_addr} failed to authenticate, dropping connection"
+                );
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            }
+        }
+
+        let mut settings = DiscordSettings::new(stream.clone(), peer_addr.clone());
+        settings.codec = codec;
+        let settings = Arc::new(settings);
+        if !self.try_add_client(settings.clone(), max_clients()).await {
+            info!(peer:% = peer_addr; "Client limit reached, closing connection from {peer_addr}");
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+        if let Some(url) = event_webhook_url() {
+            let event = ConnectionEvent::Connected {
+                ip: peer_addr.ip(),
+                timestamp: unix_time(settings.connected_at),
+            };
+            notify(url, event);
+        }
+
+        if let Ok(server_name) = env::var("GREETING") {
+            let greeting = build_greeting(server_name);
+            if let Err(error) = settings.send_request(&greeting).await {
+                error!(peer:% = peer_addr; "Failed to send greeting to {peer_addr}: {error}");
+            }
+        }
+
+        let num_servers = c.lock().await.len();
+        dispatch.clients_changed(self, num_servers).await;
+
+        let _loop_res = self
+            .connection_loop(reader, settings.clone(), dispatch.as_ref())
             .await;
+        c.lock()
+            .await
+            .retain(|item| !Arc::<DiscordSettings>::ptr_eq(item, &settings));
+
+        let num_servers = c.lock().await.len();
+        dispatch.clients_changed(self, num_servers).await;
+
+        self.record_disconnect(&peer_addr, &settings).await;
+    }
+
+    /// Adds a client unless `max_clients` are already connected. Returns whether it was added.
+    async fn try_add_client(
+        &self,
+        settings: Arc<DiscordSettings>,
+        max_clients: Option<usize>,
+    ) -> bool {
+        let mut clients = self.clients.lock().await;
+        if max_clients.is_some_and(|max| clients.len() >= max) {
+            return false;
+        }
+        clients.insert(0, settings);
+        true
+    }
+
+    async fn record_forwarded(&self, size: u64) {
+        let mut messages = self.forwarded_messages.lock().await;
+        *messages = messages.saturating_add(1);
+        let mut bytes = self.forwarded_bytes.lock().await;
+        *bytes = bytes.saturating_add(size);
+    }
+
+    /// Snapshot of the server for the metrics endpoint.
+    pub(crate) async fn metrics(&self) -> Metrics {
+        let mut channels: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+        let clients = self.clients.lock().await;
+        for client in clients.iter() {
+            let channel = client.channel.read().await.0;
+            let counters = channels.entry(channel).or_default();
+            counters.0 = counters.0.saturating_add(*client.num_messages.lock().await);
+            counters.1 = counters.1.saturating_add(*client.total_data.lock().await);
+        }
+        Metrics {
+            connected_clients: clients.len(),
+            forwarded_messages: *self.forwarded_messages.lock().await,
+            forwarded_bytes: *self.forwarded_bytes.lock().await,
+            channels,
+            clients: self.totals().await,
+        }
+    }
+
+    async fn record_total(&self, settings: &DiscordSettings, size: u64) {
+        let ip = settings.peer.ip();
+        let mut totals = self.totals.lock().await;
+        let total = totals.entry(ip).or_default();
+        total.num_messages = total.num_messages.saturating_add(1);
+        total.total_data = total.total_data.saturating_add(size);
+    }
+
+    pub(crate) async fn totals(&self) -> BTreeMap<String, Totals> {
+        self.totals.lock().await.clone()
+    }
+
+    /// Continues counting from totals saved by an earlier run.
+    pub(crate) async fn restore_totals(&self, totals: BTreeMap<String, Totals>) {
+        *self.totals.lock().await = totals;
+    }
+
+    /// Logs and counts the end of a connection, telling clients that said goodbye apart from
+    /// connections that were dropped.
+    async fn record_disconnect(&self, peer_addr: &Peer, settings: &DiscordSettings) {
+        match settings.disconnect_reason.lock().await.as_deref() {
+            Some(reason) => {
+                info!(peer:% = peer_addr; "Client {peer_addr} disconnected: {reason}");
+                let mut clean = self.clean_disconnects.lock().await;
+                *clean = clean.saturating_add(1);
+            }
+            None => {
+                info!(peer:% = peer_addr; "Dropped connection from: {}", peer_addr);
+                let mut dropped = self.dropped_connections.lock().await;
+                *dropped = dropped.saturating_add(1);
+            }
+        }
+        if let Some(url) = event_webhook_url() {
+            let now = SystemTime::now();
+            let event = ConnectionEvent::Disconnected {
+                ip: peer_addr.ip(),
+                timestamp: unix_time(now),
+                duration_secs: now
+                    .duration_since(settings.connected_at)
+                    .unwrap_or_default()
+                    .as_secs(),
+                messages: *settings.num_messages.lock().await,
+                bytes: *settings.total_data.lock().await,
+            };
+            notify(url, event);
+        }
     }
 
     async fn update_presence(&self, ctx: Arc<Context>, num_servers: usize) {
         let mut last_update = self.last_presense_update.lock().await;
         let now = SystemTime::now();
-        if now.duration_since(*last_update).unwrap().as_secs() < 60 {
+        if now.duration_since(*last_update).unwrap_or_default() < presence_throttle() {
             return;
         }
 
-        let cloud = env::var("CLOUD_SERVER");
-        if cloud.is_ok() {
-            let presence = format!("to {} instances", num_servers);
+        if is_cloud_server() {
+            let presence = presence_text().replace("{count}", &num_servers.to_string());
+            let kind = ActivityKind::from_env().unwrap_or(ActivityKind::Streaming);
             ctx.set_presence(
-                Some(Activity::streaming(presence, "https://octoprint.org")),
+                Some(kind.activity(presence, &presence_url())),
                 OnlineStatus::Online,
             )
             .await;
@@ -136,47 +777,211 @@ impl Server {
 
     async fn connection_loop(
         &self,
-        mut stream: TcpStream,
+        stream: Peekable<ClientStream>,
         settings: Arc<DiscordSettings>,
-        ctx: Arc<Context>,
+        dispatch: &dyn Dispatch,
     ) {
-        loop {
-            let length_buf = &mut [0u8; 4];
-            match stream.read_exact(length_buf).await {
-                Ok(_) => {}
-                Err(message) => {
-                    debug!("Read length failed with [{message}]");
+        // Frames are read independently of handling them, so a lost connection is noticed (and
+        // the connection's cancellation token fired) while a long multi-part send is still
+        // running. A client that closes cleanly after its last response still has it sent.
+        let (sender, receiver) = channel::bounded(1);
+        let peer = settings.peer();
+        let reader = async {
+            let closed_cleanly = match settings.codec {
+                Codec::Protobuf => self.read_loop(stream, &peer, sender, idle_timeout()).await,
+                Codec::JsonLines => {
+                    let stream = BufReader::new(stream);
+                    self.read_json_lines(stream, &peer, sender, idle_timeout())
+                        .await
+                }
+            };
+            if !closed_cleanly {
+                settings.cancel.cancel();
+            }
+        };
+        let process = async {
+            let window = edit_debounce();
+            let mut held = Debouncer::default();
+            loop {
+                let next = match held.next_deadline() {
+                    Some(deadline) => {
+                        let wait = deadline.saturating_duration_since(Instant::now());
+                        timeout(wait, receiver.recv()).await.ok()
+                    }
+                    None => Some(receiver.recv().await),
+                };
+                let (ready, done) = match next {
+                    Some(Ok((response, frame_length))) => (
+                        held.offer(response, frame_length, window, Instant::now()),
+                        false,
+                    ),
+                    // The reader is done, but the edits held back are still sent.
+                    Some(Err(_)) => (held.take_all(), true),
+                    None => (held.take_due(Instant::now()), false),
+                };
+                for (response, frame_length) in ready {
+                    let result = self
+                        .handle_task(settings.clone(), response, frame_length, dispatch)
+                        .await;
+                    if result.is_err() {
+                        debug!("Failed to send response");
+                        // Unblock the reader so the connection is dropped.
+                        let _ = settings.stream.read().await.shutdown(Shutdown::Both);
+                        return;
+                    }
+                }
+                if done {
                     return;
                 }
             }
-            let length = LittleEndian::read_u32(length_buf) as usize;
+        };
+        let processor = async {
+            process.await;
+            // A reader waiting to pass on a frame isn't woken by the shutdown, only by the channel
+            // closing.
+            receiver.close();
+        };
+        let session = join(reader, processor);
+        let heartbeat = async {
+            heartbeat(&settings, ping_interval(), max_missed_pongs()).await;
+            // The session ends once the reader notices the shutdown.
+            future::pending::<()>().await
+        };
+        future::select(Box::pin(session), Box::pin(heartbeat)).await;
+        // Replies to the last responses, and a goodbye, may still be waiting to be written.
+        settings.flush_and_close(write_timeout()).await;
+    }
+
+    /// Reads frames until the stream ends, passing each response on with the length of the
+    /// frame it arrived in. A client that sends nothing for `idle_timeout` is dropped. Returns
+    /// whether the stream ended cleanly, between frames.
+    async fn read_loop<R: async_std::io::Read + Unpin>(
+        &self,
+        mut stream: R,
+        peer: &str,
+        sender: Sender<(messages::Response, usize)>,
+        idle_timeout: Duration,
+    ) -> bool {
+        let prefix_timeout = length_prefix_timeout();
+        let max_frame_size = max_frame_size();
+        loop {
+            let prefix = match timeout(idle_timeout, read_length(&mut stream, prefix_timeout)).await
+            {
+                Ok(Ok(prefix)) => prefix,
+                Ok(Err(message)) if message.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    info!(peer = peer; "{peer} closed the connection");
+                    return true;
+                }
+                Ok(Err(message)) => {
+                    info!(peer = peer; "Read length from {peer} failed with [{message}]");
+                    return false;
+                }
+                Err(_) => {
+                    info!(peer = peer; "Dropping {peer}, idle for {idle_timeout:?}");
+                    return false;
+                }
+            };
+            let compressed = prefix & GZIP_FLAG != 0;
+            let length = prefix & !GZIP_FLAG;
+            if length > max_frame_size {
+                warn!(
+                    peer = peer;
+                    "{peer} sent a {length} byte frame, over the {max_frame_size} byte limit, dropping connection"
+                );
+                return false;
+            }
             debug!("Incoming response, {length} bytes long.");
 
             let mut buf = vec![0u8; length];
-            match stream.read_exact(&mut buf).await {
-                Ok(_) => {}
-                Err(message) => {
-                    debug!("Read data failed with [{message}]");
-                    return;
+            match timeout(idle_timeout, stream.read_exact(&mut buf)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(message)) => {
+                    info!(peer = peer; "Read data from {peer} failed with [{message}]");
+                    return false;
+                }
+                Err(_) => {
+                    info!(
+                        peer = peer;
+                        "Dropping {peer}, idle for {idle_timeout:?} part way through a frame"
+                    );
+                    return false;
                 }
             }
 
-            let result = messages::Response::parse_from_bytes(buf.as_slice());
-            if result.is_err() {
-                debug!(
-                    "Parse data failed with [{}]",
-                    result.err().unwrap().to_string()
-                );
-                return;
+            // The frame has been read in full, so a bad one can be skipped without losing sync.
+            if compressed {
+                buf = match gunzip(&buf, max_frame_size) {
+                    Ok(inflated) => inflated,
+                    Err(error) => {
+                        warn!(
+                            peer = peer;
+                            "Skipping {length} byte frame from {peer}, gunzip failed with [{error}]"
+                        );
+                        continue;
+                    }
+                };
+            }
+            let response = match messages::Response::parse_from_bytes(buf.as_slice()) {
+                Ok(response) => response,
+                Err(error) => {
+                    warn!(
+                        peer = peer;
+                        "Skipping {length} byte frame from {peer}, parse failed with [{error}]"
+                    );
+                    continue;
+                }
+            };
+
+            if sender.send((response, length)).await.is_err() {
+                // Whatever stopped handling responses has dealt with the connection already.
+                return true;
             }
-            let response = result.unwrap();
+        }
+    }
 
-            let result = self
-                .handle_task(settings.clone(), response, ctx.clone())
-                .await;
-            if result.is_err() {
-                debug!("Failed to send response");
-                return;
+    /// Like `read_loop`, for clients that send one JSON encoded response per line.
+    async fn read_json_lines<R: async_std::io::Read + Unpin>(
+        &self,
+        mut reader: BufReader<R>,
+        peer: &str,
+        sender: Sender<(messages::Response, usize)>,
+        idle_timeout: Duration,
+    ) -> bool {
+        let max_frame_size = max_frame_size();
+        loop {
+            let line = match timeout(idle_timeout, read_line(&mut reader, max_frame_size)).await {
+                Ok(Ok(Some(line))) => line,
+                Ok(Ok(None)) => {
+                    info!(peer = peer; "{peer} closed the connection");
+                    return true;
+                }
+                Ok(Err(message)) => {
+                    info!(peer = peer; "Read line from {peer} failed with [{message}]");
+                    return false;
+                }
+                Err(_) => {
+                    info!(peer = peer; "Dropping {peer}, idle for {idle_timeout:?}");
+                    return false;
+                }
+            };
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let length = line.len();
+            debug!("Incoming response, {length} bytes long.");
+            let response: messages::Response = match Codec::JsonLines.decode(&line) {
+                Ok(response) => response,
+                Err(error) => {
+                    warn!(
+                        peer = peer;
+                        "Skipping {length} byte line from {peer}, parse failed with [{error}]"
+                    );
+                    continue;
+                }
+            };
+
+            if sender.send((response, length)).await.is_err() {
+                return true;
             }
         }
     }
@@ -185,150 +990,727 @@ impl Server {
         &self,
         settings: Arc<DiscordSettings>,
         response: messages::Response,
-        ctx: Arc<Context>,
+        frame_length: usize,
+        dispatch: &dyn Dispatch,
     ) -> Result<(), ()> {
-        *settings.num_messages.lock().await += 1;
-        *settings.total_data.lock().await += response.compute_size();
+        if drops_presence(&response, is_cloud_server()) {
+            let mut dropped = settings.dropped_presence.lock().await;
+            *dropped = dropped.saturating_add(1);
+            return Ok(());
+        }
+        let channel = settings.target_channel(&response.route).await;
+        if lacks_channel(&response, channel) {
+            // Dropping the response, rather than the connection, lets the client still send its
+            // Settings.
+            let peer = settings.peer();
+            warn!(
+                peer = peer.as_str();
+                "Dropping response from {peer}, no channel is configured yet. Clients must send Settings with a channel_id first."
+            );
+            return Ok(());
+        }
+        if is_rate_limited(&response) {
+            let cost = response.fanout_channels.len().max(1) as u32;
+            let mut bucket = settings.rate_limit.lock().await;
+            if !bucket.try_take(cost, Instant::now()) {
+                bucket.dropped += 1;
+                if bucket.dropped == 1 {
+                    let peer = settings.peer();
+                    warn!(
+                        peer = peer.as_str();
+                        "Client {peer} is over its rate limit of {} messages per minute, dropping messages until it slows down",
+                        bucket.per_minute
+                    );
+                }
+                return Ok(());
+            }
+            if bucket.dropped > 0 {
+                let peer = settings.peer();
+                warn!(
+                    peer = peer.as_str();
+                    "Dropped {} messages from {peer} over its rate limit",
+                    bucket.dropped
+                );
+                bucket.dropped = 0;
+            }
+        }
+        let size = accounted_size(stats_size(), frame_length, response.compute_size());
+        settings.record_message(size).await;
+        self.record_forwarded(size).await;
+        self.record_total(&settings, size).await;
+        if is_dry_run() {
+            if let Some(summary) = dry_run_summary(&response, channel) {
+                info!("Dry run, not sending {summary}");
+                return Ok(());
+            }
+        }
+        let dm_user = UserId(response.dm_user);
+        if dm_user.0 != 0
+            && !may_message(
+                is_cloud_server(),
+                &settings.allowed_users.read().await,
+                dm_user,
+            )
+        {
+            // Like a response without a channel, only the response is dropped.
+            let peer = settings.peer();
+            warn!(
+                peer = peer.as_str();
+                "Dropping response from {peer}, user {dm_user} is not on its allowlist or direct messages are off"
+            );
+            return Ok(());
+        }
+        let correlation_key = response.correlation_key;
+        let fanout_channels: Vec<ChannelId> = response
+            .fanout_channels
+            .iter()
+            .map(|channel| ChannelId(*channel))
+            .collect();
         match response.field {
             None => Ok(()),
             Some(messages::response::Field::File(protofile)) => {
-                let filename = protofile.filename.clone();
-                let filedata = protofile.data.as_slice();
-                let files = split_file(filename, filedata);
-                for file in files {
-                    let result = settings
-                        .channel
-                        .read()
-                        .await
-                        .send_files(&ctx, vec![file.1], |m| m.content(file.0))
-                        .await;
-                    if result.is_err() {
-                        let error = result.err().unwrap();
-                        error!("{error}");
-                        return Err(());
-                    }
+                if !fanout_channels.is_empty() {
+                    let results = fan_out(&fanout_channels, |channel| {
+                        dispatch.send_file(self, channel, &protofile, &settings.cancel)
+                    })
+                    .await;
+                    return self.send_ack(&settings, results).await;
                 }
-                Ok(())
+                let posted = async {
+                    let channel = self
+                        .destination(dispatch, &settings, &response.route, dm_user)
+                        .await?;
+                    let sent = dispatch
+                        .send_file(self, channel, &protofile, &settings.cancel)
+                        .await?;
+                    Ok((channel, sent))
+                };
+                self.report_posted(&settings, correlation_key, posted.await)
+                    .await
             }
 
             Some(messages::response::Field::Embed(response_embed)) => {
-                let embeds = build_embeds(response_embed);
-                for e in embeds {
-                    let mentions = extract_mentions(&e);
-
-                    if e.snapshot.is_some() {
-                        let snapshot = e.snapshot.clone().unwrap();
-                        let filename_url = format!("attachment://{}", snapshot.filename);
-                        let filedata = snapshot.data.as_slice();
-                        let files = vec![AttachmentType::Bytes {
-                            data: Cow::from(filedata),
-                            filename: snapshot.filename,
-                        }];
-                        let result = settings
-                            .channel
-                            .read()
-                            .await
-                            .send_files(&ctx, files, |m| {
-                                m.embed(|f| {
-                                    f.title(e.title)
-                                        .description(e.description)
-                                        .color(e.color)
-                                        .author(|a| a.name(e.author));
-                                    for field in e.textfield {
-                                        f.field(field.title, field.text, field.inline);
-                                    }
-                                    f.image(filename_url.clone());
-                                    f
-                                })
-                                .content(mentions)
-                            })
-                            .await;
-                        if result.is_err() {
-                            let error = result.err().unwrap();
-                            error!("{error}");
-                            return Err(());
-                        }
-                    } else {
-                        let result = settings
-                            .channel
-                            .read()
-                            .await
-                            .send_message(&ctx, |m| {
-                                m.embed(|f| {
-                                    f.title(e.title)
-                                        .description(e.description)
-                                        .color(e.color)
-                                        .author(|a| a.name(e.author));
-                                    for field in e.textfield {
-                                        f.field(field.title, field.text, field.inline);
-                                    }
-                                    f
-                                })
-                                .content(mentions)
-                            })
-                            .await;
-                        if result.is_err() {
-                            let error = result.err().unwrap();
-                            error!("{error}");
-                            return Err(());
-                        }
-                    }
+                let plain_text = *settings.plain_text.lock().await;
+                if !fanout_channels.is_empty() {
+                    let results = fan_out(&fanout_channels, |channel| {
+                        let embed = response_embed.clone();
+                        dispatch.send_embed(self, channel, embed, plain_text, &settings.cancel)
+                    })
+                    .await;
+                    return self.send_ack(&settings, results).await;
                 }
-                Ok(())
+                let posted = async {
+                    let channel = self
+                        .destination(dispatch, &settings, &response.route, dm_user)
+                        .await?;
+                    let sent = dispatch
+                        .send_embed(self, channel, response_embed, plain_text, &settings.cancel)
+                        .await?;
+                    Ok((channel, sent))
+                };
+                self.report_posted(&settings, correlation_key, posted.await)
+                    .await
             }
 
             Some(messages::response::Field::Presence(presence)) => {
-                let cloud = env::var("CLOUD_SERVER");
-                if cloud.is_err() {
-                    let activity = Activity::playing(presence.presence);
-                    ctx.shard.set_presence(Some(activity), OnlineStatus::Online);
-                }
+                let kind = ActivityKind::from_env().unwrap_or(ActivityKind::Playing);
+                let activity = kind.activity(presence.presence, &presence_url());
+                dispatch.set_presence(activity).await;
                 Ok(())
             }
 
             Some(messages::response::Field::Settings(new_settings)) => {
-                *settings.channel.write().await = ChannelId(new_settings.channel_id);
-                *settings.prefix.lock().await = new_settings.command_prefix;
-                *settings.cycle_time.lock().await = new_settings.cycle_time;
-                *settings.enabled.lock().await = new_settings.presence_enabled;
-                Ok(())
+                let applied = settings.apply_settings(new_settings).await;
+                let reply = messages::Request {
+                    message: Some(messages::request::Message::SettingsApplied(applied)),
+                    ..Default::default()
+                };
+                settings
+                    .send_request(&reply)
+                    .await
+                    .map_err(|error| error!("Failed to confirm settings: {error}"))
             }
-        }
-    }
 
-    pub(crate) async fn send_command(&self, channel: ChannelId, user: UserId, command: String) {
-        let mut request = messages::Request::default();
-        request.user = user.0;
-        request.message = Some(messages::request::Message::Command(command));
-        let data = request.write_to_bytes().unwrap();
+            Some(messages::response::Field::StatsQuery(_)) => {
+                // Replies go over the client's own connection, so a client only sees its stats.
+                let reply = settings.stats_reply().await;
+                settings
+                    .send_request(&reply)
+                    .await
+                    .map_err(|error| error!("Failed to send stats: {error}"))
+            }
 
-        self._send_data(channel, data).await
-    }
+            Some(messages::response::Field::Auth(_)) => {
+                // Only meaningful as the handshake, which is read before frames get here.
+                debug!("Ignoring Auth after handshake");
+                Ok(())
+            }
 
-    async fn _send_data(&self, channel: ChannelId, data: Vec<u8>) {
-        let length = data.len() as u32;
-        let length_buf = &mut [0u8; 4];
-        LittleEndian::write_u32(length_buf, length);
+            Some(messages::response::Field::Ping(_)) => {
+                let pong = messages::Request {
+                    message: Some(messages::request::Message::Pong(messages::Pong::new())),
+                    ..Default::default()
+                };
+                settings
+                    .send_request(&pong)
+                    .await
+                    .map_err(|error| error!("Failed to send pong: {error}"))
+            }
 
-        let c = self.clients.lock().await;
+            Some(messages::response::Field::Pong(_)) => {
+                *settings.missed_pongs.lock().await = Some(0);
+                Ok(())
+            }
 
-        let mut found = 0;
-        for client in c.as_slice() {
-            if channel.0 != 0 && channel.0 == client.channel.read().await.0 {
-                let mut tcpstream = client.tcpstream.write().await;
+            Some(messages::response::Field::EditEmbed(edit)) => {
+                let posted = async {
+                    let channel = self
+                        .destination(dispatch, &settings, &response.route, dm_user)
+                        .await?;
+                    let sent = dispatch.edit_embed(self, &settings, channel, edit).await?;
+                    Ok((channel, vec![sent]))
+                };
+                self.report_posted(&settings, correlation_key, posted.await)
+                    .await
+            }
 
-                if tcpstream.write_all(length_buf).await.is_err() {
-                    error!("Failed to send length");
-                    continue;
+            Some(messages::response::Field::DeleteMessage(delete)) => {
+                let target = delete_target(
+                    &mut *settings.edited_messages.lock().await,
+                    &mut *settings.sent_messages.lock().await,
+                    &delete,
+                );
+                let Some((channel, message_id)) = target else {
+                    debug!(
+                        "Nothing to delete for [{}] or message {}",
+                        delete.key, delete.message_id
+                    );
+                    return Ok(());
+                };
+                if let Err(error) = dispatch.delete_message(channel, message_id).await {
+                    let peer = settings.peer();
+                    error!(peer = peer.as_str(); "Failed to delete message {message_id} for {peer}: {error}");
+                }
+                Ok(())
+            }
+
+            Some(messages::response::Field::Disconnect(disconnect)) => {
+                settings.disconnect(disconnect.reason).await;
+                Ok(())
+            }
+        }
+    }
+
+    /// The channel a response goes to: the DM channel of `dm_user` when set, otherwise the
+    /// client's channel for `route`. Whether the client may message `dm_user` is checked before.
+    async fn destination(
+        &self,
+        dispatch: &dyn Dispatch,
+        settings: &DiscordSettings,
+        route: &str,
+        dm_user: UserId,
+    ) -> serenity::Result<ChannelId> {
+        if dm_user.0 == 0 {
+            return Ok(settings.target_channel(route).await);
+        }
+        if let Some(channel) = self.dm_channels.lock().await.get(&dm_user) {
+            return Ok(*channel);
+        }
+        let channel = dispatch.create_dm_channel(dm_user).await?;
+        self.dm_channels.lock().await.insert(dm_user, channel);
+        Ok(channel)
+    }
+
+    async fn send_protofile(
+        &self,
+        ctx: &Context,
+        channel: ChannelId,
+        protofile: &messages::ProtoFile,
+        cancel: &CancellationToken,
+    ) -> serenity::Result<Vec<MessageId>> {
+        self.stop_typing(channel).await;
+        let inlined = if inline_file_split() {
+            inline_file_parts(protofile, inline_file_max_bytes())
+        } else {
+            inline_file(protofile, inline_file_max_bytes()).map(|block| vec![block])
+        };
+        if let Some(blocks) = inlined {
+            return send_parts(blocks, cancel, |block| async move {
+                let message = retried(|| channel.say(ctx, &block)).await?;
+                Ok(message.id)
+            })
+            .await;
+        }
+        let filename = protofile.filename.clone();
+        let filedata = protofile.data.as_slice();
+        let files = split_file(filename.clone(), filedata, attachment_size_limit());
+        let total = files.len();
+        let (sent, error) = send_parts_retrying(files, cancel, |file| async move {
+            channel
+                .send_files(ctx, vec![file.1], |m| m.content(file.0))
+                .await
+                .map(|message| message.id)
+        })
+        .await;
+        match error {
+            None => Ok(sent),
+            // Nothing was posted, so there is no partial upload to explain.
+            Some(e) if sent.is_empty() => Err(e),
+            Some(e) => {
+                let count = sent.len();
+                warn!("Upload of {filename} failed after {count} of {total} parts: {e}");
+                let notice = incomplete_upload_notice(&filename, count, total);
+                timed_send(channel.say(ctx, notice), send_timeout()).await?;
+                Ok(sent)
+            }
+        }
+    }
+
+    async fn send_embed(
+        &self,
+        ctx: &Context,
+        channel: ChannelId,
+        response_embed: messages::EmbedContent,
+        plain_text: bool,
+        cancel: &CancellationToken,
+    ) -> serenity::Result<Vec<MessageId>> {
+        self.stop_typing(channel).await;
+        let mut response_embed = response_embed;
+        response_embed.color = effective_color(
+            &response_embed,
+            channel,
+            &channel_colors(),
+            default_embed_color(),
+        );
+        let markers = Markers::from_env();
+        let pin = response_embed.pin;
+        let crosspost = response_embed.crosspost;
+        let sent = match render_embed(response_embed, plain_text, &markers) {
+            Rendered::Embeds(embeds) => {
+                send_parts(embeds, cancel, |e| {
+                    self.send_single_embed(ctx, channel, e, &markers)
+                })
+                .await?
+            }
+            Rendered::PlainText(messages) => {
+                send_parts(messages, cancel, |m| async move {
+                    let message = retried(|| channel.say(ctx, &m)).await?;
+                    Ok(message.id)
+                })
+                .await?
+            }
+        };
+        // An embed split over several messages, as embeds or as plain text, is pinned once, by
+        // its first part, but crossposted whole so following servers get all of it.
+        if pin {
+            if let Some(&first) = sent.first() {
+                self.auto_pin(ctx, channel, first).await;
+            }
+        }
+        if crosspost {
+            self.crosspost(ctx, channel, &sent).await;
+        }
+        Ok(sent)
+    }
+
+    async fn send_single_embed(
+        &self,
+        ctx: &Context,
+        channel: ChannelId,
+        e: messages::EmbedContent,
+        markers: &Markers,
+    ) -> serenity::Result<MessageId> {
+        let mentions = extract_mentions(&e);
+        let mut allowed_users = None;
+        let mut contents = if mentions.len() <= DISCORD_MAX_CONTENT {
+            vec![mentions]
+        } else {
+            match mentions_overflow() {
+                MentionsOverflow::Split => split_content(&mentions, DISCORD_MAX_CONTENT, markers),
+                MentionsOverflow::Cap => {
+                    debug!("Capping {} bytes of mentions", mentions.len());
+                    allowed_users = Some(mentioned_users(&mentions));
+                    vec![cap_mentions(&mentions, DISCORD_MAX_CONTENT)]
+                }
+            }
+        }
+        .into_iter();
+        let mentions = contents.next().unwrap_or_default();
+
+        let (snapshots, notices) =
+            attachable_snapshots(e.snapshots.clone(), attachment_size_limit());
+        let image = snapshots
+            .first()
+            .map(|snapshot| format!("attachment://{}", snapshot.filename));
+        let embed = self.create_embed(e, image);
+        let message = if snapshots.is_empty() {
+            retried(|| {
+                channel.send_message(ctx, |m| {
+                    fill_message(m, embed.clone(), mentions.clone(), allowed_users.clone())
+                })
+            })
+            .await?
+        } else {
+            let files: Vec<_> = snapshots
+                .into_iter()
+                .map(|snapshot| AttachmentType::Bytes {
+                    data: Cow::from(snapshot.data),
+                    filename: snapshot.filename,
+                })
+                .collect();
+            retried(|| {
+                channel.send_files(ctx, files.clone(), |m| {
+                    fill_message(m, embed.clone(), mentions.clone(), allowed_users.clone())
+                })
+            })
+            .await?
+        };
+        // The embed is out, so a follow-up that fails only loses itself: failing the whole part
+        // would have the embed posted again.
+        for content in contents.chain(notices) {
+            if let Err(error) = retried(|| channel.say(ctx, &content)).await {
+                warn!(
+                    "Failed to send follow-up to message {}: {error}",
+                    message.id
+                );
+                break;
+            }
+        }
+        Ok(message.id)
+    }
+
+    /// Edits the message previously sent for the key of `edit`, or sends a new one if there is
+    /// none or it has since been deleted.
+    async fn send_edit_embed(
+        &self,
+        ctx: &Context,
+        settings: &DiscordSettings,
+        channel: ChannelId,
+        edit: messages::EditEmbed,
+    ) -> serenity::Result<MessageId> {
+        let mut response_embed = edit.embed.unwrap_or_default();
+        response_embed.snapshots.clear();
+        response_embed.color = effective_color(
+            &response_embed,
+            channel,
+            &channel_colors(),
+            default_embed_color(),
+        );
+        // An edit replaces a single message, so anything that overflows it is dropped.
+        let e = build_embeds(response_embed).remove(0);
+
+        let previous = settings
+            .edited_messages
+            .lock()
+            .await
+            .get(&edit.key)
+            .copied();
+        let policy = RetryPolicy::from_env();
+        if let Some(message_id) = edit_target(previous, channel) {
+            let embed = self.create_embed(e.clone(), None);
+            let edited = with_retries(&policy, send_timeout(), || {
+                channel.edit_message(ctx, message_id, |m| m.set_embed(embed.clone()))
+            })
+            .await;
+            match edited {
+                Ok(message) => return Ok(message.id),
+                Err(error) if is_unknown_message(&error) => {
+                    info!("Message for [{}] was deleted, sending a new one", edit.key);
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        let embed = self.create_embed(e, None);
+        let message = with_retries(&policy, send_timeout(), || {
+            channel.send_message(ctx, |m| m.set_embed(embed.clone()))
+        })
+        .await?;
+        settings
+            .edited_messages
+            .lock()
+            .await
+            .insert(edit.key, (channel, message.id));
+        Ok(message.id)
+    }
+
+    /// Builds the Discord embed for `e` and runs it through the registered transforms.
+    fn create_embed(&self, e: messages::EmbedContent, image: Option<String>) -> CreateEmbed {
+        let mut embed = CreateEmbed::default();
+        embed
+            .title(e.title)
+            .description(e.description)
+            .color(e.color)
+            .author(|a| a.name(e.author));
+        for field in e.textfield {
+            embed.field(field.title, field.text, field.inline);
+        }
+        if !e.footer_text.is_empty() {
+            embed.footer(|f| {
+                f.text(e.footer_text);
+                if !e.footer_icon_url.is_empty() {
+                    f.icon_url(e.footer_icon_url);
+                }
+                f
+            });
+        }
+        if !e.timestamp.is_empty() {
+            match Timestamp::parse(&e.timestamp) {
+                Ok(timestamp) => {
+                    embed.timestamp(timestamp);
+                }
+                Err(error) => warn!(
+                    "Ignoring invalid embed timestamp {:?}: {error}",
+                    e.timestamp
+                ),
+            }
+        }
+        if let Some(image) = image {
+            embed.image(image);
+        }
+        for transform in &self.transforms {
+            transform.transform_embed(&mut embed);
+        }
+        embed
+    }
+
+    /// Reports the per-channel outcome of a fan-out back to the client.
+    async fn send_ack(
+        &self,
+        settings: &DiscordSettings,
+        results: Vec<messages::DeliveryResult>,
+    ) -> Result<(), ()> {
+        let ack = messages::Ack {
+            results,
+            ..Default::default()
+        };
+        let request = messages::Request {
+            message: Some(messages::request::Message::Ack(ack)),
+            ..Default::default()
+        };
+        settings
+            .send_request(&request)
+            .await
+            .map_err(|error| error!("Failed to send ack: {error}"))
+    }
+
+    /// Reports the outcome of posting a response: the messages posted, or why it failed. A response
+    /// Discord didn't take only loses itself, the connection is kept as the next may go through.
+    async fn report_posted(
+        &self,
+        settings: &DiscordSettings,
+        correlation_key: String,
+        posted: serenity::Result<(ChannelId, Vec<MessageId>)>,
+    ) -> Result<(), ()> {
+        match posted {
+            Ok((channel, sent)) => {
+                self.send_message_sent(settings, correlation_key, channel, sent)
+                    .await
+            }
+            Err(error) => {
+                let peer = settings.peer();
+                error!(peer = peer.as_str(); "Failed to post response from {peer}: {error}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Tells the client which messages were posted for its response, so it can edit or delete
+    /// them later. Only responses with a correlation key are answered.
+    async fn send_message_sent(
+        &self,
+        settings: &DiscordSettings,
+        correlation_key: String,
+        channel: ChannelId,
+        sent: Vec<MessageId>,
+    ) -> Result<(), ()> {
+        if sent.is_empty() || correlation_key.is_empty() {
+            // Abandoned before anything was posted, so there is nothing to refer to, or the client
+            // isn't waiting for it.
+            return Ok(());
+        }
+        remember_sent(&mut *settings.sent_messages.lock().await, channel, &sent);
+        settings
+            .send_request(&message_sent(correlation_key, channel, &sent))
+            .await
+            .map_err(|error| error!("Failed to send message ids: {error}"))
+    }
+
+    /// Pins a freshly sent message, unpinning the previous auto-pinned message in the same channel
+    /// (unless `KEEP_PREVIOUS_PINS` is set) so the channel doesn't run into Discord's 50 pin limit.
+    async fn auto_pin(&self, ctx: &Context, channel: ChannelId, message: MessageId) {
+        if let Err(error) = channel.pin(ctx, message).await {
+            warn!("Failed to pin message {message} in channel {channel}: {error}");
+            return;
+        }
+
+        let previous = replace_pin(&mut *self.pinned.lock().await, channel, message);
+        if env::var("KEEP_PREVIOUS_PINS").is_ok() {
+            return;
+        }
+        if let Some(previous) = previous {
+            if let Err(error) = channel.unpin(ctx, previous).await {
+                warn!("Failed to unpin message {previous}: {error}");
+            }
+        }
+    }
+
+    /// Publishes messages sent to an announcement channel, so they show up in following servers.
+    async fn crosspost(&self, ctx: &Context, channel: ChannelId, messages: &[MessageId]) {
+        let kind = cached_or_fetch(
+            ctx.cache.guild_channel(channel).map(|c| c.kind),
+            || async {
+                Ok(match ctx.http.get_channel(channel.0).await? {
+                    Channel::Guild(guild_channel) => guild_channel.kind,
+                    _ => ChannelType::Unknown,
+                })
+            },
+            cache_fetch_timeout(),
+        )
+        .await;
+        if !should_crosspost(kind) {
+            debug!("Channel {channel} is not an announcement channel, not crossposting");
+            return;
+        }
+        for &message in messages {
+            if let Err(error) = channel.crosspost(ctx, message).await {
+                warn!("Failed to crosspost message {message}: {error}");
+            }
+        }
+    }
+
+    /// Forwards a command to the clients of `channel`, normalized first. Clients with a command
+    /// prefix only receive commands starting with it, with the prefix stripped. Returns how many
+    /// clients received it.
+    pub(crate) async fn send_command(
+        &self,
+        channel: ChannelId,
+        user: UserId,
+        command: String,
+    ) -> usize {
+        let command = normalize_command(&command, max_command_length());
+        self._send_each(Origin::Channel(channel), Some(user), |prefix| {
+            let command = strip_command_prefix(prefix, &command)?;
+            let mut request = messages::Request::default();
+            request.user = user.0;
+            request.message = Some(messages::request::Message::Command(command));
+            Some(request)
+        })
+        .await
+    }
+
+    /// Forwards a command sent to the bot as a direct message to the clients that opted into
+    /// direct messages.
+    pub(crate) async fn send_direct_command(&self, user: UserId, command: String) -> usize {
+        let command = normalize_command(&command, max_command_length());
+        self._send_each(Origin::DirectMessage(user), Some(user), |prefix| {
+            let command = strip_command_prefix(prefix, &command)?;
+            let request = messages::Request {
+                user: user.0,
+                direct_message: true,
+                message: Some(messages::request::Message::Command(command)),
+                ..Default::default()
+            };
+            Some(request)
+        })
+        .await
+    }
+
+    pub(crate) async fn send_reaction(
+        &self,
+        channel: ChannelId,
+        user: UserId,
+        message: MessageId,
+        emoji: String,
+        added: bool,
+    ) {
+        let request = build_reaction_request(user, message, emoji, added);
+        self._send_data(channel, Some(user), request).await
+    }
+
+    pub(crate) async fn send_message_edit(
+        &self,
+        channel: ChannelId,
+        user: UserId,
+        message: MessageId,
+        content: String,
+    ) {
+        let content = normalize_command(&content, max_command_length());
+        let request = build_message_edit_request(user, message, content);
+        self._send_data(channel, Some(user), request).await
+    }
+
+    pub(crate) async fn send_message_delete(&self, channel: ChannelId, message: MessageId) {
+        let request = build_message_delete_request(message);
+        self._send_data(channel, None, request).await
+    }
+
+    async fn _send_data(
+        &self,
+        channel: ChannelId,
+        from: Option<UserId>,
+        request: messages::Request,
+    ) {
+        self._send_each(Origin::Channel(channel), from, |_| Some(request.clone()))
+            .await;
+    }
+
+    /// Sends to every client of `origin` the request `request_for` builds from its command prefix,
+    /// if it builds any. When `from` is set, clients that don't accept that user are skipped.
+    /// Returns how many clients the request was sent to.
+    async fn _send_each<F>(&self, origin: Origin, from: Option<UserId>, request_for: F) -> usize
+    where
+        F: Fn(&str) -> Option<messages::Request>,
+    {
+        // The clients lock is only held to take a snapshot, and never while writing: a client that
+        // is slow to read would otherwise stall accepting, removing and sending to every other
+        // client. Per-client locks are always taken after the clients lock is released.
+        let clients = self.clients.lock().await.clone();
+
+        let mut found = 0;
+        for client in clients {
+            if client.receives(origin).await {
+                if let Some(user) = from {
+                    if !is_allowed(&client.allowed_users.read().await, user) {
+                        continue;
+                    }
                 }
-                if tcpstream.write_all(&data).await.is_err() {
-                    error!("Failed to send message");
+                let request = match request_for(&client.prefix.lock().await) {
+                    Some(request) => request,
+                    None => continue,
+                };
+                if let Err(error) = client.send_request(&request).await {
+                    debug!("Not sending message to {}: {error}", client.peer());
                     continue;
                 }
                 found += 1;
             }
         }
         info!("Sent message to {found} clients");
+        found
+    }
+
+    /// Shows the bot typing in `channel` until a response is sent there or the typing timeout
+    /// elapses, whichever comes first.
+    pub(crate) async fn start_typing(&self, http: &Arc<Http>, channel: ChannelId) {
+        let typing = match channel.start_typing(http) {
+            Ok(typing) => typing,
+            Err(error) => {
+                warn!("Failed to start typing in {channel}: {error}");
+                return;
+            }
+        };
+        let id = self.typing.lock().await.start(channel, typing);
+        let pending = self.typing.clone();
+        task::spawn(async move {
+            task::sleep(typing_timeout()).await;
+            pending.lock().await.expire(channel, id);
+        });
+    }
+
+    async fn stop_typing(&self, channel: ChannelId) {
+        // Dropping the indicator stops it.
+        self.typing.lock().await.stop(channel);
     }
 
     pub(crate) async fn send_file(
@@ -337,8 +1719,10 @@ impl Server {
         user: UserId,
         filename: String,
         file: Vec<u8>,
+        content_type: Option<&str>,
     ) {
         let req_file = messages::ProtoFile {
+            is_text: is_text(content_type, &file),
             data: file,
             filename,
             ..Default::default()
@@ -350,71 +1734,4074 @@ impl Server {
             ..Default::default()
         };
 
-        let data = request.write_to_bytes().unwrap();
-
-        self._send_data(channel, data).await
+        self._send_data(channel, Some(user), request).await
     }
 
     pub(crate) async fn send_stats(&self, channel: ChannelId, ctx: Context) {
         let mut wtr = Writer::from_writer(vec![]);
+        let mut approximate = false;
         let c = self.clients.lock().await;
         for client in c.as_slice() {
-            wtr.serialize(client.get_stats().await).unwrap();
+            let stats = client.get_stats().await;
+            approximate |= stats.is_approximate();
+            wtr.serialize(stats).unwrap();
         }
         wtr.flush().unwrap();
 
+        let compress = env::var("COMPRESS_STATS").is_ok();
+        let threshold = env::var("STATS_COMPRESS_THRESHOLD")
+            .ok()
+            .and_then(|t| t.parse().ok());
+        let (filename, data) = stats_attachment(wtr.into_inner().unwrap(), compress, threshold);
         let files = vec![AttachmentType::Bytes {
-            data: Cow::from(wtr.into_inner().unwrap()),
-            filename: String::from("stats.csv"),
+            data: Cow::from(data),
+            filename,
         }];
-        let result = channel.send_files(&ctx, files, |m| m).await;
+        let uptime = SystemTime::now()
+            .duration_since(self.started_at)
+            .unwrap_or_default();
+        let mut content = format!("Process uptime: {} seconds", uptime.as_secs());
+        if approximate {
+            content.push_str("\nStats may be approximate, some counters are near their limit. Use /reset-stats to reset them.");
+        }
+        let result = channel
+            .send_files(&ctx, files, |m| m.content(content))
+            .await;
         if result.is_err() {
             let error = result.err().unwrap();
             error!("{error}");
         }
     }
-}
 
-fn extract_mentions(e: &EmbedContent) -> String {
-    let mut mentions = String::new();
-    let re = Regex::new(r"(<@[0-9a-zA-Z]*>)").unwrap();
-    for (_, [mention]) in re.captures_iter(e.title.as_str()).map(|c| c.extract()) {
-        mentions = mentions + mention + " ";
+    /// Sends a human readable summary of the client stats as an embed, as an alternative to the
+    /// CSV attachment.
+    pub(crate) async fn send_stats_embed(&self, channel: ChannelId, ctx: Context) {
+        let mut stats = vec![];
+        for client in self.clients.lock().await.as_slice() {
+            stats.push(client.get_stats().await);
+        }
+
+        let mut embed = stats_summary(&stats);
+        for (title, count) in [
+            ("Clean disconnects", *self.clean_disconnects.lock().await),
+            (
+                "Dropped connections",
+                *self.dropped_connections.lock().await,
+            ),
+        ] {
+            embed.textfield.push(messages::TextField {
+                title: title.to_string(),
+                text: count.to_string(),
+                inline: true,
+                ..Default::default()
+            });
+        }
+        let cancel = CancellationToken::default();
+        if let Err(error) = self.send_embed(&ctx, channel, embed, false, &cancel).await {
+            error!("{error}");
+        }
+    }
+
+    /// Lists the connected clients, with the channel and command prefix each has configured.
+    pub(crate) async fn send_clients(&self, channel: ChannelId, ctx: Context) {
+        let mut clients = vec![];
+        for client in self.clients.lock().await.as_slice() {
+            clients.push((
+                client.get_stats().await,
+                *client.channel.read().await,
+                client.prefix.lock().await.clone(),
+            ));
+        }
+
+        let embed = clients_summary(&clients);
+        let cancel = CancellationToken::default();
+        if let Err(error) = self.send_embed(&ctx, channel, embed, false, &cancel).await {
+            error!("{error}");
+        }
+    }
+
+    /// Zeroes the counters of every client, or only of those `target` names: all clients from an
+    /// IP, the way totals are kept, or a single one by the `ip:port` shown in `/clients`. Their
+    /// running totals are cleared too, so the next save doesn't bring them back. Returns the
+    /// number of connected clients that were reset.
+    pub(crate) async fn reset_stats(&self, target: Option<&str>) -> usize {
+        let c = self.clients.lock().await;
+        let mut totals = self.totals.lock().await;
+        match target {
+            None => totals.clear(),
+            // Clients no longer connected may have totals too.
+            Some(ip) => {
+                totals.remove(ip);
+            }
+        }
+        let mut reset = 0;
+        for client in c.as_slice() {
+            if let Some(target) = target {
+                if client.peer.ip() != target && client.peer() != target {
+                    continue;
+                }
+            }
+            client.reset_stats().await;
+            totals.remove(&client.peer.ip());
+            reset += 1;
+        }
+        info!("Reset stats for {reset} clients");
+        reset
     }
-    for (_, [mention]) in re
-        .captures_iter(e.description.as_str())
-        .map(|c| c.extract())
+}
+
+const DEFAULT_LISTENER_DRAIN_SECS: u64 = 300;
+
+/// How long a replaced listener keeps accepting connections, read from `LISTENER_DRAIN_SECS`.
+pub(crate) fn listener_drain() -> Duration {
+    let secs = env::var("LISTENER_DRAIN_SECS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_LISTENER_DRAIN_SECS);
+    Duration::from_secs(secs)
+}
+
+/// Accepts connections from `incoming`, handing each to `handle`, until `stop` fires. The
+/// listeners behind `incoming` are closed as soon as it stops, but connections they already
+/// accepted keep running until they end.
+async fn accept_until<S, I, F, Fut>(incoming: I, stop: Receiver<()>, mut handle: F)
+where
+    I: Stream<Item = std::io::Result<S>> + Unpin,
+    F: FnMut(S) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut connections = FuturesUnordered::new();
     {
-        mentions = mentions + mention + " ";
+        let mut incoming = incoming
+            .take_until(Box::pin(async move {
+                let _ = stop.recv().await;
+            }))
+            .fuse();
+        loop {
+            futures::select! {
+                stream = incoming.next() => match stream {
+                    Some(Ok(stream)) => connections.push(handle(stream)),
+                    Some(Err(error)) => error!("Failed to accept connection: {error}"),
+                    None => break,
+                },
+                _ = connections.select_next_some() => {}
+            }
+        }
     }
-    mentions
+    while connections.next().await.is_some() {}
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::messages::EmbedContent;
-    use crate::server::extract_mentions;
+/// An embed prepared for sending, either as rich embeds or as plain text messages.
+enum Rendered {
+    Embeds(Vec<messages::EmbedContent>),
+    PlainText(Vec<String>),
+}
 
-    #[test]
-    fn test_extract_mentions_empty() {
-        let e = EmbedContent::new();
-        let mentions = extract_mentions(&e);
-        assert_eq!("", mentions);
+fn render_embed(embed: messages::EmbedContent, plain_text: bool, markers: &Markers) -> Rendered {
+    if plain_text {
+        Rendered::PlainText(split_content(
+            &flatten_embed(&embed),
+            DISCORD_MAX_CONTENT,
+            markers,
+        ))
+    } else {
+        Rendered::Embeds(build_embeds(embed))
     }
+}
 
-    #[test]
-    fn test_extract_mentions_title() {
-        let mut e = EmbedContent::new();
-        e.title = "<@12345678910> <@Everyone>".to_string();
-        let mentions = extract_mentions(&e);
-        assert_eq!("<@12345678910> <@Everyone> ", mentions);
+const DEFAULT_SEND_TIMEOUT_MS: u64 = 30000;
+
+/// How long a single send to Discord may take before it is abandoned, read from
+/// `SEND_TIMEOUT_MS`.
+fn send_timeout() -> Duration {
+    let millis = env::var("SEND_TIMEOUT_MS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_SEND_TIMEOUT_MS);
+    Duration::from_millis(millis)
+}
+
+/// Runs a send to Discord, failing it if Discord doesn't answer within `send_timeout`. A timed out
+/// send is reported to the client like any other failed send.
+async fn timed_send<T>(
+    send: impl Future<Output = serenity::Result<T>>,
+    send_timeout: Duration,
+) -> serenity::Result<T> {
+    match timeout(send_timeout, send).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!("Send abandoned after {send_timeout:?}");
+            Err(serenity::Error::Other(SEND_TIMED_OUT))
+        }
     }
+}
 
-    #[test]
-    fn test_extract_mentions_description() {
-        let mut e = EmbedContent::new();
-        e.description = "<@12345678910> <@Everyone>".to_string();
-        let mentions = extract_mentions(&e);
-        assert_eq!("<@12345678910> <@Everyone> ", mentions);
+const SEND_TIMED_OUT: &str = "send timed out";
+const DEFAULT_SEND_RETRIES: u32 = 2;
+const DEFAULT_SEND_RETRY_DELAY_MS: u64 = 500;
+
+/// How sends that fail for a transient reason are retried.
+struct RetryPolicy {
+    /// Retries after the first attempt, from `SEND_RETRIES`.
+    retries: u32,
+    /// Delay before the first retry, doubling for every retry after it, from
+    /// `SEND_RETRY_DELAY_MS`.
+    delay: Duration,
+}
+
+impl RetryPolicy {
+    fn from_env() -> RetryPolicy {
+        RetryPolicy {
+            retries: env::var("SEND_RETRIES")
+                .ok()
+                .and_then(|r| r.parse().ok())
+                .unwrap_or(DEFAULT_SEND_RETRIES),
+            delay: Duration::from_millis(
+                env::var("SEND_RETRY_DELAY_MS")
+                    .ok()
+                    .and_then(|d| d.parse().ok())
+                    .unwrap_or(DEFAULT_SEND_RETRY_DELAY_MS),
+            ),
+        }
+    }
+}
+
+/// Whether a failed send may succeed if tried again: network errors, rate limits and server errors
+/// on Discord's side. A send that timed out may still have been posted, so it isn't retried.
+fn is_transient(error: &serenity::Error) -> bool {
+    match error {
+        serenity::Error::Http(http) => match http.as_ref() {
+            HttpError::UnsuccessfulRequest(response) => {
+                response.status_code.is_server_error() || response.status_code.as_u16() == 429
+            }
+            HttpError::Request(_) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Runs a single Discord call with the retry policy and send timeout from the environment.
+async fn retried<T, F, Fut>(send: F) -> serenity::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = serenity::Result<T>>,
+{
+    with_retries(&RetryPolicy::from_env(), send_timeout(), send).await
+}
+
+/// Runs `send` under the send timeout, retrying with exponential backoff while it fails for a
+/// transient reason, up to the retries allowed by `policy`. `send` must make a single call to
+/// Discord, so a retry never repeats one that already went through.
+async fn with_retries<T, F, Fut>(
+    policy: &RetryPolicy,
+    send_timeout: Duration,
+    mut send: F,
+) -> serenity::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = serenity::Result<T>>,
+{
+    let mut delay = policy.delay;
+    let mut attempt = 0;
+    loop {
+        match timed_send(send(), send_timeout).await {
+            Err(e) if attempt < policy.retries && is_transient(&e) => {
+                attempt += 1;
+                warn!("Send failed with [{e}], retry {attempt} in {delay:?}");
+                async_std::task::sleep(delay).await;
+                delay = delay.saturating_mul(2);
+            }
+            result => return result,
+        }
+    }
+}
+
+const DEFAULT_PING_INTERVAL_SECS: u64 = 60;
+const DEFAULT_MAX_MISSED_PONGS: u32 = 3;
+
+/// How often clients are pinged, read from `PING_INTERVAL_SECS`. Zero disables pings.
+fn ping_interval() -> Duration {
+    let secs = env::var("PING_INTERVAL_SECS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_PING_INTERVAL_SECS);
+    Duration::from_secs(secs)
+}
+
+/// Pings in a row a client may leave unanswered before it is dropped, read from
+/// `MAX_MISSED_PONGS`.
+fn max_missed_pongs() -> u32 {
+    env::var("MAX_MISSED_PONGS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_MAX_MISSED_PONGS)
+}
+
+/// Pings the client every `interval`, shutting the connection down and returning once it has
+/// missed `max_missed` pongs in a row. Clients that never answered a ping may predate them, so
+/// they are only pinged, never dropped.
+async fn heartbeat(settings: &DiscordSettings, interval: Duration, max_missed: u32) {
+    if interval.is_zero() {
+        return;
+    }
+    let ping = messages::Request {
+        message: Some(messages::request::Message::Ping(messages::Ping::new())),
+        ..Default::default()
+    };
+    loop {
+        async_std::task::sleep(interval).await;
+        let missed = settings.missed_pongs.lock().await.as_mut().map(|missed| {
+            *missed += 1;
+            *missed
+        });
+        if missed.is_some_and(|missed| missed > max_missed) {
+            info!("Client missed {max_missed} pongs, dropping connection");
+            settings.cancel.cancel();
+            let _ = settings.stream.read().await.shutdown(Shutdown::Both);
+            return;
+        }
+        if let Err(error) = settings.send_request(&ping).await {
+            debug!("Failed to send ping: {error}");
+        }
+    }
+}
+
+/// Token clients must send before anything else, from `DISCORDSHIM_AUTH_TOKEN`. Without one, any
+/// client is accepted.
+pub(crate) fn auth_token() -> Option<String> {
+    env::var("DISCORDSHIM_AUTH_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+}
+
+const DEFAULT_AUTH_TIMEOUT_SECS: u64 = 10;
+
+/// How long a new client may take to authenticate, read from `AUTH_TIMEOUT_SECS`. Much shorter
+/// than the idle timeout, so connections that never authenticate don't hold a slot for long.
+fn auth_timeout() -> Duration {
+    let secs = env::var("AUTH_TIMEOUT_SECS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_AUTH_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+/// Reads the first frame from the client, which must be an Auth carrying `token`, within
+/// `handshake_timeout`.
+async fn authenticate<R: async_std::io::Read + Unpin>(
+    stream: &mut R,
+    token: &str,
+    handshake_timeout: Duration,
+) -> bool {
+    let handshake = async {
+        let length = read_length(stream, length_prefix_timeout()).await?;
+        if length > max_frame_size() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "handshake frame too large",
+            ));
+        }
+        let mut buf = vec![0u8; length];
+        stream.read_exact(&mut buf).await?;
+        Ok(buf)
+    };
+    let buf = match timeout(handshake_timeout, handshake).await {
+        Ok(Ok(buf)) => buf,
+        Ok(Err(error)) => {
+            debug!("Handshake failed with [{error}]");
+            return false;
+        }
+        Err(_) => {
+            debug!("Handshake timed out");
+            return false;
+        }
+    };
+    is_auth(Codec::Protobuf.decode(&buf), token)
+}
+
+/// Like `authenticate`, for a client that sends JSON lines.
+async fn authenticate_json<R: async_std::io::Read + Unpin>(
+    stream: &mut R,
+    token: &str,
+    handshake_timeout: Duration,
+) -> bool {
+    // Read a byte at a time, so no part of the lines after the handshake is buffered and lost.
+    let mut reader = BufReader::with_capacity(1, stream);
+    let line = match timeout(handshake_timeout, read_line(&mut reader, max_frame_size())).await {
+        Ok(Ok(Some(line))) => line,
+        Ok(Ok(None)) => {
+            debug!("Handshake failed, the connection was closed");
+            return false;
+        }
+        Ok(Err(error)) => {
+            debug!("Handshake failed with [{error}]");
+            return false;
+        }
+        Err(_) => {
+            debug!("Handshake timed out");
+            return false;
+        }
+    };
+    is_auth(Codec::JsonLines.decode(&line), token)
+}
+
+/// Whether a decoded handshake is an Auth with `token`.
+fn is_auth(handshake: Result<messages::Response, String>, token: &str) -> bool {
+    match handshake.map(|response| response.field) {
+        Ok(Some(messages::response::Field::Auth(auth))) => {
+            constant_time_eq(auth.token.as_bytes(), token.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+/// Fails a write that takes longer than `write_timeout`.
+async fn within<F>(write_timeout: Duration, write: F) -> std::io::Result<()>
+where
+    F: Future<Output = std::io::Result<()>>,
+{
+    match timeout(write_timeout, write).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("write took longer than {write_timeout:?}"),
+        )),
+    }
+}
+
+const DEFAULT_OUTBOUND_QUEUE: usize = 64;
+
+/// How many frames may wait to be written to each client, read from `OUTBOUND_QUEUE`.
+fn outbound_queue() -> usize {
+    env::var("OUTBOUND_QUEUE")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_OUTBOUND_QUEUE)
+        .max(1)
+}
+
+/// Which frame gives way when a client's outbound queue is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Overflow {
+    DropOldest,
+    DropNewest,
+}
+
+impl Overflow {
+    /// Read from `OUTBOUND_OVERFLOW`, `oldest` or `newest`, the default.
+    fn from_env() -> Overflow {
+        match env::var("OUTBOUND_OVERFLOW").as_deref() {
+            Ok("oldest") => Overflow::DropOldest,
+            _ => Overflow::DropNewest,
+        }
+    }
+}
+
+/// Whether the client can do without `request` when it isn't keeping up: forwarded attachments,
+/// reactions and deletions, and pings, which the next one stands in for. Commands and replies to
+/// the client are never dropped.
+fn is_droppable(request: &messages::Request) -> bool {
+    use messages::request::Message;
+    matches!(
+        request.message,
+        Some(
+            Message::File(_) | Message::Reaction(_) | Message::MessageDelete(_) | Message::Ping(_)
+        )
+    )
+}
+
+/// An encoded request waiting to be written.
+struct Frame {
+    data: Vec<u8>,
+    droppable: bool,
+}
+
+type Frames = Arc<std::sync::Mutex<VecDeque<Frame>>>;
+
+/// Frames waiting for a client's writer task, so producers never wait on a slow client. Once
+/// `capacity` frames are waiting, droppable frames give way as `overflow` says, while the others
+/// are still queued.
+struct OutboundQueue {
+    frames: Frames,
+    capacity: usize,
+    overflow: Overflow,
+    // Rung when a frame is queued. The writer stops once it is closed and the queue is empty.
+    doorbell: Sender<()>,
+}
+
+impl OutboundQueue {
+    /// Returns the queue along with the ends the writer takes frames from.
+    fn new(capacity: usize, overflow: Overflow) -> (OutboundQueue, Frames, Receiver<()>) {
+        let frames = Frames::default();
+        let (doorbell, rung) = channel::bounded(1);
+        let queue = OutboundQueue {
+            frames: frames.clone(),
+            capacity,
+            overflow,
+            doorbell,
+        };
+        (queue, frames, rung)
+    }
+
+    /// Queues `frame` without waiting. Returns whether a frame was dropped because the queue was
+    /// full, or fails once the queue is closed.
+    fn push(&self, frame: Frame) -> std::io::Result<bool> {
+        if self.doorbell.is_closed() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "the client's writer has stopped",
+            ));
+        }
+        let mut frames = lock_frames(&self.frames);
+        let mut dropped = false;
+        if frame.droppable && frames.len() >= self.capacity {
+            let oldest = frames.iter().position(|queued| queued.droppable);
+            match (self.overflow, oldest) {
+                (Overflow::DropOldest, Some(oldest)) => {
+                    frames.remove(oldest);
+                    dropped = true;
+                }
+                _ => return Ok(true),
+            }
+        }
+        frames.push_back(frame);
+        drop(frames);
+        let _ = self.doorbell.try_send(());
+        Ok(dropped)
+    }
+
+    /// Stops taking frames. The writer still writes those already queued.
+    fn close(&self) {
+        self.doorbell.close();
+    }
+}
+
+fn lock_frames(frames: &Frames) -> std::sync::MutexGuard<'_, VecDeque<Frame>> {
+    frames
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Writes queued frames to the client in order, until the queue is closed and empty. A write that
+/// fails or takes longer than `write_timeout` may have left part of a frame behind, so the stream
+/// can't be trusted to be in sync any more: the connection is closed, which ends the client's
+/// connection loop, and `cancel` fires to stop sends still running for it.
+async fn write_frames(
+    mut stream: ClientStream,
+    peer: Peer,
+    frames: Frames,
+    rung: Receiver<()>,
+    write_timeout: Duration,
+    cancel: Arc<CancellationToken>,
+) {
+    loop {
+        let next = lock_frames(&frames).pop_front();
+        let Some(frame) = next else {
+            if rung.recv().await.is_err() && lock_frames(&frames).is_empty() {
+                return;
+            }
+            continue;
+        };
+        if let Err(error) = within(write_timeout, stream.write_all(&frame.data)).await {
+            error!(peer:% = peer; "Failed to write to {peer}, dropping client: {error}");
+            rung.close();
+            cancel.cancel();
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+    }
+}
+
+/// Binds the Unix socket from `UNIX_SOCKET`, if set. Failing to is logged, and TCP clients are
+/// still accepted.
+pub(crate) async fn unix_listener() -> Option<Listener> {
+    let path = unix_socket_path()?;
+    match bind_unix(&path).await {
+        Ok(listener) => {
+            debug!("Starting Unix socket listener on {}", path.display());
+            Some(listener.into())
+        }
+        Err(error) => {
+            error!("Failed to listen on {}: {error}", path.display());
+            None
+        }
+    }
+}
+
+/// Compares without stopping at the first difference, so timing doesn't reveal how much of a
+/// guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Most clients connected at once, from `MAX_CLIENTS`. Unlimited when unset.
+fn max_clients() -> Option<usize> {
+    env::var("MAX_CLIENTS").ok().and_then(|m| m.parse().ok())
+}
+
+/// Whether commands show the bot typing until a response arrives, on when `TYPING_INDICATOR` is
+/// set.
+pub(crate) fn typing_indicator() -> bool {
+    env::var("TYPING_INDICATOR").is_ok()
+}
+
+const DEFAULT_TYPING_TIMEOUT_SECS: u64 = 30;
+
+/// Longest the typing indicator is shown without a response, from `TYPING_TIMEOUT_SECS`.
+fn typing_timeout() -> Duration {
+    let secs = env::var("TYPING_TIMEOUT_SECS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_TYPING_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+/// How long an EditEmbed is held back so that a newer one for the same key can replace it, from
+/// `EDIT_DEBOUNCE_MS`. Off when unset or 0.
+fn edit_debounce() -> Duration {
+    let millis = env::var("EDIT_DEBOUNCE_MS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(0);
+    Duration::from_millis(millis)
+}
+
+/// The message an EditEmbed updates: its route, DM user and key.
+type EditTarget = (String, u64, String);
+
+/// EditEmbed responses held back, so a burst of progress updates for one message is sent as
+/// only the latest of them. Any other response first sends what is held, so responses are still
+/// handled in the order they were sent.
+#[derive(Default)]
+struct Debouncer {
+    held: HashMap<EditTarget, (Instant, messages::Response, usize)>,
+}
+
+impl Debouncer {
+    /// Returns the responses to handle now that `response` arrived. An EditEmbed is held until
+    /// `window` after the first of a burst, replacing any held for the same message. Anything else
+    /// comes after the held edits, less those for a message it deletes.
+    fn offer(
+        &mut self,
+        response: messages::Response,
+        frame_length: usize,
+        window: Duration,
+        now: Instant,
+    ) -> Vec<(messages::Response, usize)> {
+        if window.is_zero() {
+            return vec![(response, frame_length)];
+        }
+        match &response.field {
+            Some(messages::response::Field::EditEmbed(edit)) => {
+                let target = (response.route.clone(), response.dm_user, edit.key.clone());
+                let deadline = match self.held.get(&target) {
+                    Some((deadline, ..)) => {
+                        debug!("Superseding held edit for [{}]", edit.key);
+                        *deadline
+                    }
+                    None => now + window,
+                };
+                self.held.insert(target, (deadline, response, frame_length));
+                return vec![];
+            }
+            Some(messages::response::Field::DeleteMessage(delete)) if !delete.key.is_empty() => {
+                // The message is going away, so there is no point in editing it first.
+                let target = (response.route.clone(), response.dm_user, delete.key.clone());
+                self.held.remove(&target);
+            }
+            _ => {}
+        }
+        let mut ready = self.take_all();
+        ready.push((response, frame_length));
+        ready
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.held.values().map(|(deadline, ..)| *deadline).min()
+    }
+
+    /// Takes the held responses whose window is over.
+    fn take_due(&mut self, now: Instant) -> Vec<(messages::Response, usize)> {
+        let due: Vec<EditTarget> = self
+            .held
+            .iter()
+            .filter(|(_, (deadline, ..))| *deadline <= now)
+            .map(|(target, _)| target.clone())
+            .collect();
+        due.iter()
+            .filter_map(|target| self.held.remove(target))
+            .map(|(_, response, frame_length)| (response, frame_length))
+            .collect()
+    }
+
+    /// Takes every held response, those whose burst started first first.
+    fn take_all(&mut self) -> Vec<(messages::Response, usize)> {
+        let mut held: Vec<_> = self.held.drain().map(|(_, held)| held).collect();
+        held.sort_by_key(|(deadline, ..)| *deadline);
+        held.into_iter()
+            .map(|(_, response, frame_length)| (response, frame_length))
+            .collect()
+    }
+}
+
+/// Messages per minute each client may send to Discord, from `RATE_LIMIT_PER_MINUTE`. Unlimited
+/// when unset or 0.
+fn rate_limit_per_minute() -> u32 {
+    env::var("RATE_LIMIT_PER_MINUTE")
+        .ok()
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Whether `response` counts against the client's rate limit. Only what ends up as messages in
+/// Discord does, so control traffic such as Settings and Ping always gets through.
+fn is_rate_limited(response: &messages::Response) -> bool {
+    matches!(
+        response.field,
+        Some(messages::response::Field::Embed(_))
+            | Some(messages::response::Field::File(_))
+            | Some(messages::response::Field::EditEmbed(_))
+            | Some(messages::response::Field::DeleteMessage(_))
+    )
+}
+
+/// A client's budget of messages, refilled continuously at `per_minute` up to a burst of a
+/// minute's worth. A rate of 0 never runs out.
+struct TokenBucket {
+    per_minute: u32,
+    tokens: f64,
+    refilled_at: Instant,
+    // Messages dropped since the bucket last had room, to log a run of them once.
+    dropped: u64,
+}
+
+impl TokenBucket {
+    fn new(per_minute: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            per_minute,
+            tokens: f64::from(per_minute),
+            refilled_at: now,
+            dropped: 0,
+        }
+    }
+
+    /// Takes `cost` tokens if there are enough. A cost above the burst is capped at it, so a large
+    /// fan-out isn't refused forever.
+    fn try_take(&mut self, cost: u32, now: Instant) -> bool {
+        if self.per_minute == 0 {
+            return true;
+        }
+        let capacity = f64::from(self.per_minute);
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * capacity / 60.0).min(capacity);
+        self.refilled_at = now;
+        let cost = f64::from(cost.min(self.per_minute));
+        if self.tokens < cost {
+            return false;
+        }
+        self.tokens -= cost;
+        true
+    }
+}
+
+/// Typing indicators pending a response, at most one per channel. Each start gets an id, so a
+/// timeout only expires the indicator it was started for.
+struct TypingIndicators<T> {
+    next_id: u64,
+    pending: HashMap<ChannelId, (u64, T)>,
+}
+
+impl<T> Default for TypingIndicators<T> {
+    fn default() -> Self {
+        TypingIndicators {
+            next_id: 0,
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<T> TypingIndicators<T> {
+    /// Tracks `indicator` for `channel`, replacing any earlier one, and returns its id.
+    fn start(&mut self, channel: ChannelId, indicator: T) -> u64 {
+        self.next_id += 1;
+        self.pending.insert(channel, (self.next_id, indicator));
+        self.next_id
+    }
+
+    fn stop(&mut self, channel: ChannelId) -> Option<T> {
+        self.pending
+            .remove(&channel)
+            .map(|(_, indicator)| indicator)
+    }
+
+    /// Stops the indicator of `channel` if it is still the one started with `id`.
+    fn expire(&mut self, channel: ChannelId, id: u64) -> Option<T> {
+        match self.pending.get(&channel) {
+            Some((current, _)) if *current == id => self.stop(channel),
+            _ => None,
+        }
+    }
+}
+
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+
+/// How long a shutdown waits for clients to finish, read from `SHUTDOWN_TIMEOUT_SECS`.
+pub(crate) fn shutdown_timeout() -> Duration {
+    let secs = env::var("SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+const DEFAULT_BIND: &str = "0.0.0.0:23416";
+
+/// Address the client listener binds to, read from `DISCORDSHIM_BIND`.
+pub(crate) fn bind_address() -> Result<SocketAddr, String> {
+    let bind = env::var("DISCORDSHIM_BIND").unwrap_or_else(|_| DEFAULT_BIND.to_string());
+    parse_bind(&bind).map_err(|error| format!("{error} in DISCORDSHIM_BIND"))
+}
+
+/// Parses `host:port`, `[v6]:port` or a bare `:port`, which binds to all interfaces.
+pub(crate) fn parse_bind(bind: &str) -> Result<SocketAddr, String> {
+    let bind = bind.trim();
+    let full = match bind.strip_prefix(':') {
+        Some(port) => format!("0.0.0.0:{port}"),
+        None => bind.to_string(),
+    };
+    if let Ok(addr) = full.parse() {
+        return Ok(addr);
+    }
+    // Not an IP literal, so try resolving it as a host name such as localhost.
+    std::net::ToSocketAddrs::to_socket_addrs(full.as_str())
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("Invalid address [{bind}], expected host:port or :port"))
+}
+
+/// Sends each part of a multi-part message in turn, stopping early once the client has
+/// disconnected. A part may take several calls to Discord, so `send` retries each of them itself.
+/// Returns what sending each part that was sent returned.
+async fn send_parts<T, R, F, Fut>(
+    parts: Vec<T>,
+    cancel: &CancellationToken,
+    mut send: F,
+) -> serenity::Result<Vec<R>>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = serenity::Result<R>>,
+{
+    let total = parts.len();
+    let mut sent = Vec::with_capacity(total);
+    for part in parts {
+        // The first part always goes out, a response that arrived in full is never dropped.
+        if !sent.is_empty() && cancel.is_cancelled() {
+            info!(
+                "Client disconnected, abandoning {} of {total} parts",
+                total - sent.len()
+            );
+            return Ok(sent);
+        }
+        sent.push(send(part).await?);
+    }
+    Ok(sent)
+}
+
+/// Like `send_parts`, for parts that each take a single call to Discord: transient errors are
+/// retried here, and a part that still fails is tried once more before giving up on the rest. Returns what sending each part that was sent
+/// returned, along with the error that stopped the sequence, if any.
+async fn send_parts_retrying<T, R, F, Fut>(
+    parts: Vec<T>,
+    cancel: &CancellationToken,
+    mut send: F,
+) -> (Vec<R>, Option<serenity::Error>)
+where
+    T: Clone,
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = serenity::Result<R>>,
+{
+    let total = parts.len();
+    let send_timeout = send_timeout();
+    let policy = RetryPolicy::from_env();
+    let mut sent = Vec::with_capacity(total);
+    for part in parts {
+        // The first part always goes out, a response that arrived in full is never dropped.
+        if !sent.is_empty() && cancel.is_cancelled() {
+            info!(
+                "Client disconnected, abandoning {} of {total} parts",
+                total - sent.len()
+            );
+            return (sent, None);
+        }
+        match with_retries(&policy, send_timeout, || send(part.clone())).await {
+            Ok(result) => sent.push(result),
+            Err(e) => {
+                warn!(
+                    "Sending part {} of {total} failed with [{e}], retrying",
+                    sent.len() + 1
+                );
+                match with_retries(&policy, send_timeout, || send(part.clone())).await {
+                    Ok(result) => sent.push(result),
+                    Err(e) => return (sent, Some(e)),
+                }
+            }
+        }
+    }
+    (sent, None)
+}
+
+/// Splits snapshots into those that can be attached and notices for those that can't. An image
+/// can't be split, so one over `size_limit` is explained instead of failing the send, as are any
+/// beyond Discord's attachment count.
+fn attachable_snapshots(
+    snapshots: Vec<messages::ProtoFile>,
+    size_limit: usize,
+) -> (Vec<messages::ProtoFile>, Vec<String>) {
+    let mut attachable = vec![];
+    let mut notices = vec![];
+    for snapshot in snapshots {
+        if snapshot.data.len() > size_limit {
+            warn!("Snapshot {} is too large to attach", snapshot.filename);
+            notices.push(oversized_attachment_notice(
+                &snapshot.filename,
+                snapshot.data.len(),
+                size_limit,
+            ));
+        } else if attachable.len() >= DISCORD_MAX_ATTACHMENTS {
+            warn!(
+                "Snapshot {} is over the attachment count",
+                snapshot.filename
+            );
+            notices.push(format!(
+                "{} was not attached, a message can have at most {DISCORD_MAX_ATTACHMENTS} attachments.",
+                snapshot.filename
+            ));
+        } else {
+            attachable.push(snapshot);
+        }
+    }
+    (attachable, notices)
+}
+
+fn oversized_attachment_notice(filename: &str, size: usize, limit: usize) -> String {
+    format!("{filename} was not attached, it is {size} bytes and the attachment limit is {limit} bytes.")
+}
+
+fn incomplete_upload_notice(filename: &str, sent: usize, total: usize) -> String {
+    format!("Upload of {filename} incomplete: only {sent} of {total} parts were sent.")
+}
+
+/// Sends to each channel in turn, collecting the outcome of every channel rather than stopping at
+/// the first failure. Sends are sequential, leaving serenity's per-channel rate limiting in charge
+/// of pacing them.
+async fn fan_out<R, F, Fut>(channels: &[ChannelId], mut send: F) -> Vec<messages::DeliveryResult>
+where
+    F: FnMut(ChannelId) -> Fut,
+    Fut: Future<Output = serenity::Result<R>>,
+{
+    let mut results = vec![];
+    for channel in channels {
+        let mut result = messages::DeliveryResult {
+            channel_id: channel.0,
+            success: true,
+            ..Default::default()
+        };
+        if let Err(error) = send(*channel).await {
+            warn!("Fan-out to channel {channel} failed: {error}");
+            result.success = false;
+            result.error = error.to_string();
+        }
+        results.push(result);
+    }
+    results
+}
+
+fn message_sent(
+    correlation_key: String,
+    channel: ChannelId,
+    sent: &[MessageId],
+) -> messages::Request {
+    let message_sent = messages::MessageSent {
+        correlation_key,
+        channel_id: channel.0,
+        message_ids: sent.iter().map(|message| message.0).collect(),
+        ..Default::default()
+    };
+    messages::Request {
+        message: Some(messages::request::Message::MessageSent(message_sent)),
+        ..Default::default()
+    }
+}
+
+/// Records `message` as the auto-pinned message for `channel`, returning the one it replaces.
+fn replace_pin(
+    pinned: &mut HashMap<ChannelId, MessageId>,
+    channel: ChannelId,
+    message: MessageId,
+) -> Option<MessageId> {
+    pinned
+        .insert(channel, message)
+        .filter(|previous| *previous != message)
+}
+
+/// Only messages in announcement channels can be crossposted; a channel whose kind couldn't be
+/// found is assumed not to be one.
+fn should_crosspost(kind: Option<ChannelType>) -> bool {
+    kind == Some(ChannelType::News)
+}
+
+const DEFAULT_PRESENCE_THROTTLE_SECS: u64 = 60;
+const DEFAULT_PRESENCE_TEXT: &str = "to {count} instances";
+const DEFAULT_PRESENCE_URL: &str = "https://octoprint.org";
+
+/// Least time between cloud presence updates, read from `PRESENCE_THROTTLE_SECS`.
+fn presence_throttle() -> Duration {
+    let secs = env::var("PRESENCE_THROTTLE_SECS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_PRESENCE_THROTTLE_SECS);
+    Duration::from_secs(secs)
+}
+
+/// Cloud presence text from `PRESENCE_TEXT`, where `{count}` stands for the connected clients.
+fn presence_text() -> String {
+    env::var("PRESENCE_TEXT").unwrap_or_else(|_| DEFAULT_PRESENCE_TEXT.to_string())
+}
+
+/// URL shown with a streaming presence, from `PRESENCE_URL`.
+fn presence_url() -> Url {
+    let default = || Url::parse(DEFAULT_PRESENCE_URL).unwrap();
+    match env::var("PRESENCE_URL") {
+        Ok(url) => Url::parse(&url).unwrap_or_else(|error| {
+            warn!("Invalid PRESENCE_URL [{url}]: {error}");
+            default()
+        }),
+        Err(_) => default(),
+    }
+}
+
+/// The kind of activity the bot's presence shows, from `PRESENCE_ACTIVITY`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ActivityKind {
+    Playing,
+    Watching,
+    Listening,
+    Streaming,
+}
+
+impl ActivityKind {
+    fn parse(kind: &str) -> Option<ActivityKind> {
+        match kind.trim().to_lowercase().as_str() {
+            "playing" => Some(ActivityKind::Playing),
+            "watching" => Some(ActivityKind::Watching),
+            "listening" => Some(ActivityKind::Listening),
+            "streaming" => Some(ActivityKind::Streaming),
+            _ => None,
+        }
+    }
+
+    /// The configured kind, or `None` to keep the caller's default.
+    fn from_env() -> Option<ActivityKind> {
+        let kind = env::var("PRESENCE_ACTIVITY").ok()?;
+        let parsed = ActivityKind::parse(&kind);
+        if parsed.is_none() {
+            warn!("Unknown PRESENCE_ACTIVITY [{kind}], using the default");
+        }
+        parsed
+    }
+
+    fn activity(self, name: String, url: &Url) -> Activity {
+        match self {
+            ActivityKind::Playing => Activity::playing(name),
+            ActivityKind::Watching => Activity::watching(name),
+            ActivityKind::Listening => Activity::listening(name),
+            ActivityKind::Streaming => Activity::streaming(name, url.as_str()),
+        }
+    }
+}
+
+/// With `DRY_RUN` set, responses that would post to Discord are only logged.
+fn is_dry_run() -> bool {
+    env::var("DRY_RUN").is_ok()
+}
+
+/// Describes what `response` would post, with its destination, colors and mentions resolved the
+/// way they would be when sending. `None` for responses that don't post anything.
+fn dry_run_summary(response: &messages::Response, channel: ChannelId) -> Option<String> {
+    let destination = if !response.fanout_channels.is_empty() {
+        format!("channels {:?}", response.fanout_channels)
+    } else if response.dm_user != 0 {
+        format!("a direct message to user {}", response.dm_user)
+    } else {
+        format!("channel {channel}")
+    };
+    let describe_embed = |embed: &EmbedContent| {
+        let mut embed = embed.clone();
+        embed.color = effective_color(&embed, channel, &channel_colors(), default_embed_color());
+        let mentions = extract_mentions(&embed);
+        let snapshots: Vec<String> = embed
+            .snapshots
+            .iter()
+            .map(|s| format!("{} ({} bytes)", s.filename, s.data.len()))
+            .collect();
+        let title = embed.title.clone();
+        let description = embed.description.clone();
+        let fields = embed.textfield.len();
+        let messages = build_embeds(embed);
+        format!(
+            "title [{title}], description [{description}], {fields} fields, color #{:06x}, snapshots {snapshots:?}, mentions [{}], in {} messages",
+            messages[0].color,
+            mentions.trim_end(),
+            messages.len()
+        )
+    };
+    match &response.field {
+        Some(messages::response::Field::Embed(embed)) => {
+            Some(format!("embed to {destination}: {}", describe_embed(embed)))
+        }
+        Some(messages::response::Field::EditEmbed(edit)) => Some(format!(
+            "edit of [{}] to {destination}: {}",
+            edit.key,
+            describe_embed(&edit.embed)
+        )),
+        Some(messages::response::Field::File(file)) => Some(format!(
+            "file to {destination}: {} ({} bytes)",
+            file.filename,
+            file.data.len()
+        )),
+        Some(messages::response::Field::DeleteMessage(delete)) => Some(format!(
+            "deletion of [{}] or message {}",
+            delete.key, delete.message_id
+        )),
+        _ => None,
+    }
+}
+
+/// The global discordshim is shared between many clients, so doesn't support their presence.
+fn is_cloud_server() -> bool {
+    env::var("CLOUD_SERVER").is_ok()
+}
+
+/// Whether `response` would be sent to `channel`, the client's channel for its route, while that
+/// is still unset because the client hasn't sent its Settings.
+fn lacks_channel(response: &messages::Response, channel: ChannelId) -> bool {
+    let sent_to_channel = matches!(
+        response.field,
+        Some(messages::response::Field::Embed(_))
+            | Some(messages::response::Field::File(_))
+            | Some(messages::response::Field::EditEmbed(_))
+    ) || matches!(
+        &response.field,
+        Some(messages::response::Field::DeleteMessage(delete)) if delete.key.is_empty()
+    );
+    let sent_to_channel =
+        sent_to_channel && response.fanout_channels.is_empty() && response.dm_user == 0;
+    sent_to_channel && channel.0 == 0
+}
+
+fn drops_presence(response: &messages::Response, cloud: bool) -> bool {
+    cloud && matches!(response.field, Some(messages::response::Field::Presence(_)))
+}
+
+const STATS_TOP_TALKERS: usize = 5;
+
+/// Lists each client's address with its channel, command prefix and message count.
+fn clients_summary(clients: &[(Stats, ChannelId, String)]) -> EmbedContent {
+    let textfield = clients
+        .iter()
+        .map(|(stats, channel, prefix)| {
+            let prefix = if prefix.is_empty() { "none" } else { prefix };
+            messages::TextField {
+                title: stats.ip.clone(),
+                text: format!(
+                    "Channel: {}\nPrefix: {prefix}\nMessages: {}",
+                    channel.0, stats.num_messages
+                ),
+                inline: true,
+                ..Default::default()
+            }
+        })
+        .collect();
+    let description = if clients.is_empty() {
+        "No clients connected".to_string()
+    } else {
+        format!("{} clients connected", clients.len())
+    };
+
+    EmbedContent {
+        title: "DiscordShim clients".to_string(),
+        description,
+        textfield,
+        ..Default::default()
+    }
+}
+
+/// Summarises the stats of all clients, listing the clients that sent the most data.
+fn stats_summary(stats: &[Stats]) -> EmbedContent {
+    let total_messages = stats
+        .iter()
+        .fold(0u64, |total, s| total.saturating_add(s.num_messages));
+    let total_data = stats
+        .iter()
+        .fold(0u64, |total, s| total.saturating_add(s.total_data));
+
+    let mut talkers: Vec<&Stats> = stats.iter().collect();
+    talkers.sort_by_key(|s| std::cmp::Reverse(s.total_data));
+    let top_talkers = talkers
+        .iter()
+        .take(STATS_TOP_TALKERS)
+        .map(|s| {
+            format!(
+                "{}: {} messages, {} bytes",
+                s.ip, s.num_messages, s.total_data
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let field = |title: &str, text: String| messages::TextField {
+        title: title.to_string(),
+        text,
+        inline: true,
+        ..Default::default()
+    };
+    let mut textfield = vec![
+        field("Total clients", stats.len().to_string()),
+        field("Total messages", total_messages.to_string()),
+        field("Total data", format!("{total_data} bytes")),
+    ];
+    if !top_talkers.is_empty() {
+        let mut talkers_field = field("Top talkers", top_talkers);
+        talkers_field.inline = false;
+        textfield.push(talkers_field);
+    }
+
+    EmbedContent {
+        title: "DiscordShim stats".to_string(),
+        textfield,
+        ..Default::default()
+    }
+}
+
+/// Gzips the stats CSV when compression is forced, or when it exceeds the optional size threshold.
+fn stats_attachment(csv: Vec<u8>, compress: bool, threshold: Option<usize>) -> (String, Vec<u8>) {
+    let over_threshold = threshold.is_some_and(|t| csv.len() > t);
+    if !compress && !over_threshold {
+        return (String::from("stats.csv"), csv);
+    }
+
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(&csv).unwrap();
+    (String::from("stats.csv.gz"), encoder.finish().unwrap())
+}
+
+/// How much of a file is inspected when deciding whether it is text.
+const TEXT_SNIFF_BYTES: usize = 8192;
+
+/// Makes an attachment name safe for clients to write to disk: directory components, control
+/// characters and characters Windows rejects are removed, as are leading dots.
+fn sanitize_filename(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, ':' | '*' | '?' | '"' | '<' | '>' | '|'))
+        .collect();
+    let cleaned = cleaned.trim().trim_start_matches('.');
+    if cleaned.is_empty() {
+        "attachment".to_string()
+    } else {
+        cleaned.to_string()
+    }
+}
+
+/// Sanitizes the attachment names of a message, numbering repeated names so that each is unique.
+pub(crate) fn attachment_filenames<'a>(names: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut taken = HashSet::new();
+    let mut filenames = vec![];
+    for name in names {
+        let name = sanitize_filename(name);
+        let (stem, extension) = match name.rsplit_once('.') {
+            Some((stem, extension)) => (stem.to_string(), format!(".{extension}")),
+            None => (name.clone(), String::new()),
+        };
+        let mut unique = name;
+        let mut n = 1;
+        while !taken.insert(unique.clone()) {
+            unique = format!("{stem}_{n}{extension}");
+            n += 1;
+        }
+        filenames.push(unique);
+    }
+    filenames
+}
+
+/// Guesses whether an attachment is text, so clients that only handle text (logs, G-code) can
+/// skip anything else. Media content types are always binary, otherwise the start of the data
+/// must be NUL free UTF-8.
+fn is_text(content_type: Option<&str>, data: &[u8]) -> bool {
+    if let Some(content_type) = content_type {
+        let content_type = content_type.to_ascii_lowercase();
+        if ["image/", "audio/", "video/"]
+            .iter()
+            .any(|media| content_type.starts_with(media))
+        {
+            return false;
+        }
+    }
+
+    let sample = &data[..data.len().min(TEXT_SNIFF_BYTES)];
+    if sample.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(sample) {
+        Ok(_) => true,
+        // The sample may end part way through a multi-byte character.
+        Err(error) => error.error_len().is_none(),
+    }
+}
+
+fn build_greeting(server_name: String) -> messages::Request {
+    let greeting = messages::Greeting {
+        server_name,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        ..Default::default()
+    };
+
+    messages::Request {
+        message: Some(messages::request::Message::Greeting(greeting)),
+        ..Default::default()
+    }
+}
+
+/// Users allowed to send commands and files to any client, from `ALLOWED_USERS` as a comma
+/// separated list of ids. Empty allows everyone.
+pub(crate) fn allowed_users() -> Vec<UserId> {
+    env::var("ALLOWED_USERS")
+        .map(|users| parse_user_ids(&users))
+        .unwrap_or_default()
+}
+
+fn parse_user_ids(users: &str) -> Vec<UserId> {
+    users
+        .split(',')
+        .map(str::trim)
+        .filter(|user| !user.is_empty())
+        .filter_map(|user| match user.parse() {
+            Ok(id) => Some(UserId(id)),
+            Err(_) => {
+                warn!("Ignoring invalid user id [{user}]");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Whether `user` may send commands, where an empty allowlist allows everyone.
+pub(crate) fn is_allowed(allowed: &[UserId], user: UserId) -> bool {
+    allowed.is_empty() || allowed.contains(&user)
+}
+
+/// Whether a client with the `allowed` allowlist may send `user` direct messages.
+fn may_message(cloud: bool, allowed: &[UserId], user: UserId) -> bool {
+    !cloud && allowed.contains(&user)
+}
+
+const DEFAULT_MAX_COMMAND_LENGTH: usize = 4000;
+
+/// Longest command forwarded to clients, in characters, read from `MAX_COMMAND_LENGTH`. The
+/// default is the longest message Discord lets anyone send; `0` leaves the default in place.
+fn max_command_length() -> usize {
+    env::var("MAX_COMMAND_LENGTH")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_MAX_COMMAND_LENGTH)
+}
+
+/// Makes a command safe for clients that split it on whitespace or lines: turns each run of
+/// control characters (newlines, tabs, escapes) into a single space, trims the result and cuts it
+/// off after `max_length` characters. Everything else, unicode and emoji included, is left as is.
+fn normalize_command(command: &str, max_length: usize) -> String {
+    let mut normalized = String::with_capacity(command.len());
+    let mut in_control = false;
+    for c in command.chars() {
+        if c.is_control() {
+            if !in_control {
+                normalized.push(' ');
+            }
+            in_control = true;
+        } else {
+            normalized.push(c);
+            in_control = false;
+        }
+    }
+    let mut normalized = normalized.trim().to_string();
+    if let Some((cut, _)) = normalized.char_indices().nth(max_length) {
+        normalized.truncate(cut);
+        normalized.truncate(normalized.trim_end().len());
+    }
+    normalized
+}
+
+/// The command to forward to a client with `prefix`, or `None` if the command isn't for it.
+fn strip_command_prefix(prefix: &str, command: &str) -> Option<String> {
+    if prefix.is_empty() {
+        return Some(command.to_string());
+    }
+    // Settings trims the prefix, so allow for the space that usually follows it.
+    command
+        .strip_prefix(prefix)
+        .map(|rest| rest.trim_start().to_string())
+}
+
+fn build_message_edit_request(
+    user: UserId,
+    message: MessageId,
+    content: String,
+) -> messages::Request {
+    let edit = messages::MessageEdit {
+        message_id: message.0,
+        content,
+        ..Default::default()
+    };
+
+    messages::Request {
+        user: user.0,
+        message: Some(messages::request::Message::MessageEdit(edit)),
+        ..Default::default()
+    }
+}
+
+fn build_message_delete_request(message: MessageId) -> messages::Request {
+    let delete = messages::MessageDelete {
+        message_id: message.0,
+        ..Default::default()
+    };
+
+    messages::Request {
+        message: Some(messages::request::Message::MessageDelete(delete)),
+        ..Default::default()
+    }
+}
+
+fn build_reaction_request(
+    user: UserId,
+    message: MessageId,
+    emoji: String,
+    added: bool,
+) -> messages::Request {
+    let reaction = messages::Reaction {
+        message_id: message.0,
+        emoji,
+        added,
+        ..Default::default()
+    };
+
+    messages::Request {
+        user: user.0,
+        message: Some(messages::request::Message::Reaction(reaction)),
+        ..Default::default()
+    }
+}
+
+/// The message to edit for a key, if it was sent to the channel the client is now using.
+fn edit_target(previous: Option<(ChannelId, MessageId)>, channel: ChannelId) -> Option<MessageId> {
+    previous
+        .filter(|(sent_to, _)| *sent_to == channel)
+        .map(|(_, message_id)| message_id)
+}
+
+/// The message a DeleteMessage refers to. A key is looked up, and forgotten, among the messages
+/// sent for EditEmbed, so the next embed for it starts a new message. Otherwise `message_id` must
+/// be among the messages reported to the client in a MessageSent, so a client can't delete
+/// messages it didn't post.
+fn delete_target(
+    edited_messages: &mut HashMap<String, (ChannelId, MessageId)>,
+    sent_messages: &mut VecDeque<(ChannelId, MessageId)>,
+    delete: &messages::DeleteMessage,
+) -> Option<(ChannelId, MessageId)> {
+    if !delete.key.is_empty() {
+        return edited_messages.remove(&delete.key);
+    }
+    let at = sent_messages
+        .iter()
+        .position(|(_, message)| message.0 == delete.message_id && message.0 != 0)?;
+    sent_messages.remove(at)
+}
+
+/// Most messages remembered per client for deleting by id.
+const MAX_SENT_MESSAGES: usize = 1000;
+
+/// Remembers `sent` as messages the client may delete, forgetting the oldest beyond
+/// `MAX_SENT_MESSAGES`.
+fn remember_sent(
+    sent_messages: &mut VecDeque<(ChannelId, MessageId)>,
+    channel: ChannelId,
+    sent: &[MessageId],
+) {
+    sent_messages.extend(sent.iter().map(|message| (channel, *message)));
+    let excess = sent_messages.len().saturating_sub(MAX_SENT_MESSAGES);
+    sent_messages.drain(..excess);
+}
+
+/// Deletes `message` from `channel`. A message that is already gone is not an error.
+async fn delete_message(
+    ctx: &Context,
+    channel: ChannelId,
+    message: MessageId,
+) -> serenity::Result<()> {
+    match timed_send(channel.delete_message(ctx, message), send_timeout()).await {
+        Err(error) if is_unknown_message(&error) => {
+            debug!("Message {message} was already deleted");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Whether Discord rejected a request because the message no longer exists.
+fn is_unknown_message(error: &serenity::Error) -> bool {
+    const UNKNOWN_MESSAGE: isize = 10008;
+    match error {
+        serenity::Error::Http(http) => match http.as_ref() {
+            HttpError::UnsuccessfulRequest(response) => {
+                response.error.code == UNKNOWN_MESSAGE || response.status_code == 404
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn fill_message<'a, 'b>(
+    m: &'b mut CreateMessage<'a>,
+    embed: CreateEmbed,
+    content: String,
+    allowed_users: Option<Vec<UserId>>,
+) -> &'b mut CreateMessage<'a> {
+    // Listing users replaces Discord's default of notifying every kind of mention, so role and
+    // everyone mentions have to be asked for again.
+    let roles = content.contains("<@&");
+    let everyone = content.contains("@everyone") || content.contains("@here");
+    m.set_embed(embed).content(content);
+    if let Some(users) = allowed_users {
+        m.allowed_mentions(|am| {
+            am.users(users);
+            if roles {
+                am.parse(ParseValue::Roles);
+            }
+            if everyone {
+                am.parse(ParseValue::Everyone);
+            }
+            am
+        });
+    }
+    m
+}
+
+const MAX_CYCLE_TIME: i32 = 24 * 60 * 60;
+const MAX_COMMAND_PREFIX: usize = 32;
+
+/// Clamps client settings to values the server supports. Presence can't be enabled on the cloud
+/// server, where presence updates are dropped.
+fn validate_settings(mut settings: messages::Settings, cloud: bool) -> messages::Settings {
+    settings.cycle_time = settings.cycle_time.clamp(0, MAX_CYCLE_TIME);
+    settings.command_prefix = settings
+        .command_prefix
+        .trim()
+        .chars()
+        .take(MAX_COMMAND_PREFIX)
+        .collect();
+    settings.presence_enabled &= !cloud;
+    settings.forward_dms &= !cloud;
+    settings
+}
+
+/// Color used for embeds that don't set one, from `DEFAULT_EMBED_COLOR`.
+fn default_embed_color() -> Option<i32> {
+    env::var("DEFAULT_EMBED_COLOR")
+        .ok()
+        .and_then(|color| parse_color(&color))
+}
+
+/// Per-channel embed colors from `CHANNEL_COLORS`, written as `channel:color` pairs separated by
+/// commas.
+fn channel_colors() -> HashMap<ChannelId, i32> {
+    env::var("CHANNEL_COLORS")
+        .map(|colors| parse_channel_colors(&colors))
+        .unwrap_or_default()
+}
+
+fn parse_channel_colors(colors: &str) -> HashMap<ChannelId, i32> {
+    let mut parsed = HashMap::new();
+    for entry in colors.split(',').filter(|entry| !entry.trim().is_empty()) {
+        let color = entry.split_once(':').and_then(|(channel, color)| {
+            Some((ChannelId(channel.trim().parse().ok()?), parse_color(color)?))
+        });
+        match color {
+            Some((channel, color)) => {
+                parsed.insert(channel, color);
+            }
+            None => warn!("Ignoring invalid CHANNEL_COLORS entry [{entry}]"),
+        }
+    }
+    parsed
+}
+
+/// A color set by the client wins, then the channel's color, then the global default. A `color`
+/// of 0 can't be told apart from none at all, so a client picks black with `color_hex`.
+fn effective_color(
+    embed: &messages::EmbedContent,
+    channel: ChannelId,
+    channel_colors: &HashMap<ChannelId, i32>,
+    global: Option<i32>,
+) -> i32 {
+    parse_color(&embed.color_hex)
+        .or((embed.color != 0).then_some(embed.color))
+        .or_else(|| channel_colors.get(&channel).copied())
+        .or(global)
+        .unwrap_or(0)
+}
+
+/// Which size of a response is added to `total_data`, from `STATS_SIZE`.
+#[derive(Clone, Copy)]
+enum StatsSize {
+    /// The number of bytes the frame took on the wire.
+    Wire,
+    /// The protobuf size of the decoded response.
+    Logical,
+}
+
+fn stats_size() -> StatsSize {
+    match env::var("STATS_SIZE").as_deref() {
+        Ok("logical") => StatsSize::Logical,
+        _ => StatsSize::Wire,
+    }
+}
+
+fn accounted_size(stats_size: StatsSize, frame_length: usize, logical: u64) -> u64 {
+    match stats_size {
+        StatsSize::Wire => frame_length as u64,
+        StatsSize::Logical => logical,
+    }
+}
+
+/// How mentions that don't fit in a single message are handled, from `MENTIONS_OVERFLOW`.
+enum MentionsOverflow {
+    /// Keep as many whole mentions as fit, listing every mentioned user in `allowed_mentions`.
+    Cap,
+    /// Send the remaining mentions in follow-up messages.
+    Split,
+}
+
+fn mentions_overflow() -> MentionsOverflow {
+    match env::var("MENTIONS_OVERFLOW").as_deref() {
+        Ok("split") => MentionsOverflow::Split,
+        _ => MentionsOverflow::Cap,
+    }
+}
+
+/// Keeps as many whole mentions from `mentions` as fit in `limit` bytes.
+fn cap_mentions(mentions: &str, limit: usize) -> String {
+    let mut capped = String::new();
+    for mention in mentions.split_whitespace() {
+        if capped.len() + mention.len() + 1 > limit {
+            break;
+        }
+        capped = capped + mention + " ";
+    }
+    capped
+}
+
+/// Most users Discord accepts in `allowed_mentions`.
+const MAX_ALLOWED_USERS: usize = 100;
+
+/// The users mentioned in `mentions`, once each and no more than `MAX_ALLOWED_USERS`.
+fn mentioned_users(mentions: &str) -> Vec<UserId> {
+    let re = Regex::new(r"<@!?([0-9]+)>").unwrap();
+    let mut seen = HashSet::new();
+    re.captures_iter(mentions)
+        .filter_map(|c| c[1].parse().ok())
+        .map(UserId)
+        .filter(|user| seen.insert(*user))
+        .take(MAX_ALLOWED_USERS)
+        .collect()
+}
+
+/// Collects the user, nickname and role mentions and `@everyone`/`@here` from the title and
+/// description, once each, so they notify when sent as the message content.
+fn extract_mentions(e: &EmbedContent) -> String {
+    let mut mentions = String::new();
+    let mut seen = HashSet::new();
+    let re = Regex::new(r"(<@[!&]?[0-9a-zA-Z]*>|@everyone\b|@here\b)").unwrap();
+    for text in [&e.title, &e.description] {
+        for (_, [mention]) in re.captures_iter(text).map(|c| c.extract()) {
+            if seen.insert(mention) {
+                mentions = mentions + mention + " ";
+            }
+        }
+    }
+    mentions
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::embedbuilder::{Markers, DISCORD_MAX_ATTACHMENTS, DISCORD_MAX_CONTENT};
+    use crate::framing::{
+        gunzip, read_length, read_line, write_frame, write_gzip_frame, Codec, GZIP_FLAG,
+    };
+    use crate::messages;
+    use crate::messages::{EmbedContent, ProtoFile};
+    use crate::persist::Totals;
+    use crate::server::{
+        accept_until, accounted_size, attachable_snapshots, attachment_filenames, authenticate,
+        build_greeting, build_message_delete_request, build_message_edit_request,
+        build_reaction_request, cap_mentions, clients_summary, delete_target, drops_presence,
+        dry_run_summary, edit_target, effective_color, extract_mentions, fan_out, fill_message,
+        heartbeat, incomplete_upload_notice, is_allowed, is_droppable, is_rate_limited, is_text,
+        is_unknown_message, lacks_channel, may_message, mentioned_users, message_sent,
+        normalize_command, oversized_attachment_notice, parse_bind, parse_channel_colors,
+        parse_user_ids, remember_sent, render_embed, replace_pin, send_parts, send_parts_retrying,
+        should_crosspost, stats_attachment, stats_summary, strip_command_prefix, timed_send,
+        validate_settings, with_retries, write_frames, ActivityKind, CancellationToken, Debouncer,
+        DiscordSettings, Dispatch, Frame, Frames, OutboundQueue, Overflow, Rendered, RetryPolicy,
+        Server, Stats, StatsSize, TokenBucket, TypingIndicators, FEATURES, MAX_CYCLE_TIME,
+        MAX_SENT_MESSAGES, SEND_TIMED_OUT, STATS_CEILING, TEXT_SNIFF_BYTES,
+    };
+    use crate::stream::{Listener, Peekable, Peer};
+    use crate::transform::FooterTransform;
+    use async_std::channel;
+    use async_std::io::{BufReader, ReadExt, WriteExt};
+    use async_std::net::{TcpListener, TcpStream};
+    use flate2::read::GzDecoder;
+    use futures::future::{self, join};
+    use protobuf::{Message, MessageField};
+    use serenity::async_trait;
+    use serenity::builder::{CreateEmbed, CreateMessage};
+    use serenity::http::error::ErrorResponse;
+    use serenity::http::{HttpError, StatusCode};
+    use serenity::model::channel::ChannelType;
+    use serenity::model::gateway::ActivityType;
+    use serenity::model::id::{ChannelId, MessageId, UserId};
+    use std::collections::{BTreeMap, HashMap, VecDeque};
+    use std::io::Read;
+    use std::net::Shutdown;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, SystemTime};
+    use url::Url;
+
+    async fn connected_client() -> (DiscordSettings, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let peer = Peer::Ip(stream.peer_addr().unwrap());
+        (DiscordSettings::new(stream, peer), client)
+    }
+
+    async fn connected_settings() -> DiscordSettings {
+        connected_client().await.0
+    }
+
+    async fn recv_request(client: &mut TcpStream) -> messages::Request {
+        let length = read_length(client, Duration::from_secs(1)).await.unwrap();
+        let mut buf = vec![0u8; length];
+        client.read_exact(&mut buf).await.unwrap();
+        messages::Request::parse_from_bytes(&buf).unwrap()
+    }
+
+    #[test]
+    fn test_extract_mentions_empty() {
+        let e = EmbedContent::new();
+        let mentions = extract_mentions(&e);
+        assert_eq!("", mentions);
+    }
+
+    #[test]
+    fn test_extract_mentions_title() {
+        let mut e = EmbedContent::new();
+        e.title = "<@12345678910> <@Everyone>".to_string();
+        let mentions = extract_mentions(&e);
+        assert_eq!("<@12345678910> <@Everyone> ", mentions);
+    }
+
+    #[test]
+    fn test_extract_mentions_description() {
+        let mut e = EmbedContent::new();
+        e.description = "<@12345678910> <@Everyone>".to_string();
+        let mentions = extract_mentions(&e);
+        assert_eq!("<@12345678910> <@Everyone> ", mentions);
+    }
+
+    #[test]
+    fn test_extract_mentions_roles_and_nicknames() {
+        let mut e = EmbedContent::new();
+        e.description = "Printer <@!12345> needs <@&67890>".to_string();
+        let mentions = extract_mentions(&e);
+        assert_eq!("<@!12345> <@&67890> ", mentions);
+    }
+
+    #[test]
+    fn test_extract_mentions_everyone_and_here() {
+        let mut e = EmbedContent::new();
+        e.title = "@everyone".to_string();
+        e.description = "@here, mail me at printer@heresy.org".to_string();
+        let mentions = extract_mentions(&e);
+        assert_eq!("@everyone @here ", mentions);
+    }
+
+    #[test]
+    fn test_extract_mentions_deduplicated() {
+        let mut e = EmbedContent::new();
+        e.title = "<@12345> <@&67890>".to_string();
+        e.description = "<@12345> and <@&67890> again, <@!12345>".to_string();
+        let mentions = extract_mentions(&e);
+        assert_eq!("<@12345> <@&67890> <@!12345> ", mentions);
+    }
+
+    #[async_std::test]
+    async fn test_reset_stats_all() {
+        let server = Server::new();
+        for _ in 0..2 {
+            let settings = connected_settings().await;
+            *settings.num_messages.lock().await = 10;
+            *settings.total_data.lock().await = 1000;
+            server.clients.lock().await.push(Arc::new(settings));
+        }
+
+        let totals = BTreeMap::from([("10.0.0.2".to_string(), Totals::default())]);
+        server.restore_totals(totals).await;
+
+        assert_eq!(2, server.reset_stats(None).await);
+        for client in server.clients.lock().await.iter() {
+            let stats = client.get_stats().await;
+            assert_eq!(0, stats.num_messages);
+            assert_eq!(0, stats.total_data);
+        }
+        assert!(server.totals().await.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_reset_stats_single_client() {
+        let server = Server::new();
+        let mut ips = vec![];
+        for _ in 0..2 {
+            let settings = connected_settings().await;
+            *settings.num_messages.lock().await = 10;
+            ips.push(settings.get_stats().await.ip);
+            server.clients.lock().await.push(Arc::new(settings));
+        }
+        let totals = BTreeMap::from([
+            ("127.0.0.1".to_string(), Totals::default()),
+            ("10.0.0.2".to_string(), Totals::default()),
+        ]);
+        server.restore_totals(totals).await;
+
+        assert_eq!(1, server.reset_stats(Some(&ips[0])).await);
+        {
+            let c = server.clients.lock().await;
+            assert_eq!(0, c[0].get_stats().await.num_messages);
+            assert_eq!(10, c[1].get_stats().await.num_messages);
+        }
+        let totals = server.totals().await;
+        assert_eq!(vec!["10.0.0.2"], totals.keys().collect::<Vec<_>>());
+
+        // By IP, every client from it is reset, and so are the totals of one no longer connected.
+        assert_eq!(0, server.reset_stats(Some("10.0.0.2")).await);
+        assert!(server.totals().await.is_empty());
+        assert_eq!(2, server.reset_stats(Some("127.0.0.1")).await);
+        let c = server.clients.lock().await;
+        assert_eq!(0, c[1].get_stats().await.num_messages);
+    }
+
+    #[test]
+    fn test_build_reaction_request_added() {
+        let request =
+            build_reaction_request(UserId(1234), MessageId(5678), "\u{1f44d}".to_string(), true);
+        assert_eq!(1234, request.user);
+        match request.message {
+            Some(messages::request::Message::Reaction(reaction)) => {
+                assert_eq!(5678, reaction.message_id);
+                assert_eq!("\u{1f44d}", reaction.emoji);
+                assert!(reaction.added);
+            }
+            _ => panic!("Expected a reaction request"),
+        }
+    }
+
+    #[test]
+    fn test_build_reaction_request_removed() {
+        let request = build_reaction_request(
+            UserId(1234),
+            MessageId(5678),
+            "<:custom:42>".to_string(),
+            false,
+        );
+        match request.message {
+            Some(messages::request::Message::Reaction(reaction)) => {
+                assert_eq!("<:custom:42>", reaction.emoji);
+                assert!(!reaction.added);
+            }
+            _ => panic!("Expected a reaction request"),
+        }
+    }
+
+    #[test]
+    fn test_build_message_edit_request() {
+        let request =
+            build_message_edit_request(UserId(1234), MessageId(5678), "!status".to_string());
+        assert_eq!(1234, request.user);
+        match request.message {
+            Some(messages::request::Message::MessageEdit(edit)) => {
+                assert_eq!(5678, edit.message_id);
+                assert_eq!("!status", edit.content);
+            }
+            _ => panic!("Expected a message edit request"),
+        }
+    }
+
+    #[test]
+    fn test_build_message_delete_request() {
+        let request = build_message_delete_request(MessageId(5678));
+        match request.message {
+            Some(messages::request::Message::MessageDelete(delete)) => {
+                assert_eq!(5678, delete.message_id);
+            }
+            _ => panic!("Expected a message delete request"),
+        }
+    }
+
+    #[test]
+    fn test_stats_attachment_uncompressed() {
+        let csv = b"ip,num_messages,total_data\n127.0.0.1:1234,1,2\n".to_vec();
+        let (filename, data) = stats_attachment(csv.clone(), false, Some(csv.len()));
+        assert_eq!("stats.csv", filename);
+        assert_eq!(csv, data);
+    }
+
+    #[test]
+    fn test_stats_attachment_compressed_round_trip() {
+        let csv = b"ip,num_messages,total_data\n127.0.0.1:1234,1,2\n".repeat(100);
+        for (compress, threshold) in [(true, None), (false, Some(10))] {
+            let (filename, data) = stats_attachment(csv.clone(), compress, threshold);
+            assert_eq!("stats.csv.gz", filename);
+
+            let mut decompressed = vec![];
+            GzDecoder::new(data.as_slice())
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(csv, decompressed);
+        }
+    }
+
+    #[test]
+    fn test_replace_pin_unpins_previous() {
+        let mut pinned = HashMap::new();
+        assert_eq!(None, replace_pin(&mut pinned, ChannelId(1), MessageId(10)));
+        assert_eq!(
+            Some(MessageId(10)),
+            replace_pin(&mut pinned, ChannelId(1), MessageId(11))
+        );
+        assert_eq!(Some(&MessageId(11)), pinned.get(&ChannelId(1)));
+    }
+
+    #[test]
+    fn test_replace_pin_per_channel() {
+        let mut pinned = HashMap::new();
+        assert_eq!(None, replace_pin(&mut pinned, ChannelId(1), MessageId(10)));
+        assert_eq!(None, replace_pin(&mut pinned, ChannelId(2), MessageId(20)));
+        assert_eq!(None, replace_pin(&mut pinned, ChannelId(2), MessageId(20)));
+        assert_eq!(Some(&MessageId(10)), pinned.get(&ChannelId(1)));
+    }
+
+    #[async_std::test]
+    async fn test_record_message_saturates() {
+        let settings = connected_settings().await;
+        *settings.num_messages.lock().await = u64::MAX;
+        *settings.total_data.lock().await = u64::MAX - 10;
+
+        settings.record_message(100).await;
+        let stats = settings.get_stats().await;
+        assert_eq!(u64::MAX, stats.num_messages);
+        assert_eq!(u64::MAX, stats.total_data);
+        assert!(stats.is_approximate());
+
+        settings.reset_stats().await;
+        assert!(!settings.get_stats().await.is_approximate());
+    }
+
+    #[async_std::test]
+    async fn test_record_message_below_ceiling() {
+        let settings = connected_settings().await;
+        *settings.total_data.lock().await = STATS_CEILING - 101;
+
+        settings.record_message(100).await;
+        let stats = settings.get_stats().await;
+        assert_eq!(1, stats.num_messages);
+        assert_eq!(STATS_CEILING - 1, stats.total_data);
+        assert!(!stats.is_approximate());
+    }
+
+    #[async_std::test]
+    async fn test_fan_out_reports_each_channel() {
+        let channels = vec![ChannelId(1), ChannelId(2), ChannelId(3)];
+        let attempts = Mutex::new(vec![]);
+
+        let results = fan_out(&channels, |channel| {
+            attempts.lock().unwrap().push(channel);
+            async move {
+                if channel == ChannelId(2) {
+                    return Err(serenity::Error::Other("Missing access"));
+                }
+                Ok(())
+            }
+        })
+        .await;
+
+        assert_eq!(channels, *attempts.lock().unwrap());
+        assert_eq!(3, results.len());
+        assert_eq!(
+            vec![1, 2, 3],
+            results.iter().map(|r| r.channel_id).collect::<Vec<_>>()
+        );
+        assert!(results[0].success);
+        assert!(!results[1].success);
+        assert_eq!("Missing access", results[1].error);
+        assert!(results[2].success);
+    }
+
+    #[async_std::test]
+    async fn test_greeting_sent_on_connect() {
+        let (settings, mut client) = connected_client().await;
+        settings
+            .send_request(&build_greeting("Test Shim".to_string()))
+            .await
+            .unwrap();
+
+        match recv_request(&mut client).await.message {
+            Some(messages::request::Message::Greeting(greeting)) => {
+                assert_eq!("Test Shim", greeting.server_name);
+                assert_eq!(env!("CARGO_PKG_VERSION"), greeting.version);
+                assert_eq!(FEATURES, greeting.features.as_slice());
+            }
+            _ => panic!("Expected a greeting"),
+        }
+    }
+
+    #[async_std::test]
+    async fn test_send_parts_stops_after_disconnect() {
+        let cancel = CancellationToken::default();
+        let sent = Mutex::new(vec![]);
+
+        let result = send_parts(vec![1, 2, 3, 4], &cancel, |part| {
+            sent.lock().unwrap().push(part);
+            if part == 2 {
+                // The client disconnects while the second part is being sent.
+                cancel.cancel();
+            }
+            async { Ok(()) }
+        })
+        .await;
+
+        assert_eq!(2, result.unwrap().len());
+        assert_eq!(vec![1, 2], *sent.lock().unwrap());
+
+        // Cancelled before it started, the response still gets its first part out.
+        let result = send_parts(vec![1, 2], &cancel, |part| async move { Ok(part) }).await;
+        assert_eq!(vec![1], result.unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_clean_close_keeps_last_response() {
+        let server = Server::new();
+        let (settings, mut client) = connected_client().await;
+        let stream = settings.stream.read().await.clone();
+        let data = messages::Response::new().write_to_bytes().unwrap();
+        write_frame(&mut client, &data).await.unwrap();
+        drop(client);
+
+        let (sender, receiver) = channel::bounded(1);
+        let reader = server.read_loop(stream, "client", sender, Duration::from_secs(5));
+        let (closed_cleanly, received) = join(reader, receiver.recv()).await;
+        assert!(closed_cleanly);
+        assert!(received.is_ok());
+    }
+
+    #[async_std::test]
+    async fn test_send_parts_all_sent() {
+        let cancel = CancellationToken::default();
+        let result = send_parts(vec![1, 2, 3], &cancel, |part| async move { Ok(part * 10) }).await;
+        assert_eq!(vec![10, 20, 30], result.unwrap());
+    }
+
+    #[test]
+    fn test_create_embed_applies_transforms() {
+        let mut server = Server::new();
+        server.transforms = vec![Box::new(FooterTransform {
+            text: "Footer".to_string(),
+        })];
+        let e = EmbedContent {
+            title: "Title".to_string(),
+            ..Default::default()
+        };
+
+        let embed = server.create_embed(e, None);
+        assert_eq!("Title", embed.0["title"]);
+        assert_eq!("Footer", embed.0["footer"]["text"]);
+    }
+
+    #[test]
+    fn test_cap_mentions_over_limit() {
+        let mut e = EmbedContent::new();
+        e.description = (100000000000000000u64..100000000000000200u64)
+            .map(|id| format!("<@{id}>"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mentions = extract_mentions(&e);
+        assert!(mentions.len() > DISCORD_MAX_CONTENT);
+
+        let capped = cap_mentions(&mentions, DISCORD_MAX_CONTENT);
+        assert!(capped.len() <= DISCORD_MAX_CONTENT);
+        assert!(mentions.starts_with(&capped));
+        assert!(capped.ends_with("> "));
+
+        let users = mentioned_users(&mentions);
+        assert_eq!(100, users.len());
+        assert_eq!(UserId(100000000000000000), users[0]);
+        assert_eq!(UserId(100000000000000099), users[99]);
+
+        // A user mentioned both ways is listed once.
+        assert_eq!(vec![UserId(1)], mentioned_users("<@1> <@!1>"));
+    }
+
+    #[test]
+    fn test_fill_message_keeps_roles_and_everyone() {
+        let mut m = CreateMessage::default();
+        fill_message(
+            &mut m,
+            CreateEmbed::default(),
+            "<@1> <@&2> @here ".to_string(),
+            Some(vec![UserId(1)]),
+        );
+        let allowed = &m.0["allowed_mentions"];
+        assert_eq!(serde_json::json!(["1"]), allowed["users"]);
+        assert_eq!(serde_json::json!(["roles", "everyone"]), allowed["parse"]);
+
+        let mut m = CreateMessage::default();
+        fill_message(
+            &mut m,
+            CreateEmbed::default(),
+            "<@1> ".to_string(),
+            Some(vec![UserId(1)]),
+        );
+        assert!(m.0["allowed_mentions"].get("parse").is_none());
+    }
+
+    #[test]
+    fn test_cap_mentions_fits() {
+        let mentions = "<@12345678910> <@10987654321> ";
+        assert_eq!(mentions, cap_mentions(mentions, DISCORD_MAX_CONTENT));
+    }
+
+    #[test]
+    fn test_stats_summary() {
+        let stats: Vec<Stats> = (0..7)
+            .map(|i| Stats {
+                ip: format!("10.0.0.{i}:1234"),
+                num_messages: i,
+                total_data: i * 100,
+                dropped_presence: 0,
+                dropped_frames: 0,
+                connected_at: 0,
+                connected_seconds: 0,
+            })
+            .collect();
+
+        let embed = stats_summary(&stats);
+        assert_eq!("DiscordShim stats", embed.title);
+        assert_eq!(4, embed.textfield.len());
+        assert_eq!("7", embed.textfield[0].text);
+        assert_eq!("21", embed.textfield[1].text);
+        assert_eq!("2100 bytes", embed.textfield[2].text);
+
+        let talkers: Vec<&str> = embed.textfield[3].text.lines().collect();
+        assert_eq!(5, talkers.len());
+        assert_eq!("10.0.0.6:1234: 6 messages, 600 bytes", talkers[0]);
+        assert_eq!("10.0.0.2:1234: 2 messages, 200 bytes", talkers[4]);
+    }
+
+    #[test]
+    fn test_stats_summary_no_clients() {
+        let embed = stats_summary(&[]);
+        assert_eq!(3, embed.textfield.len());
+        assert_eq!("0", embed.textfield[0].text);
+    }
+
+    #[test]
+    fn test_clients_summary() {
+        let stats = |ip: &str, num_messages| Stats {
+            ip: ip.to_string(),
+            num_messages,
+            total_data: 0,
+            dropped_presence: 0,
+            dropped_frames: 0,
+            connected_at: 0,
+            connected_seconds: 0,
+        };
+        let clients = vec![
+            (
+                stats("10.0.0.1:1234", 3),
+                ChannelId(42),
+                "/print".to_string(),
+            ),
+            (stats("10.0.0.2:1234", 0), ChannelId(43), "".to_string()),
+        ];
+
+        let embed = clients_summary(&clients);
+        assert_eq!("2 clients connected", embed.description);
+        assert_eq!(2, embed.textfield.len());
+        assert_eq!("10.0.0.1:1234", embed.textfield[0].title);
+        assert_eq!(
+            "Channel: 42\nPrefix: /print\nMessages: 3",
+            embed.textfield[0].text
+        );
+        assert_eq!(
+            "Channel: 43\nPrefix: none\nMessages: 0",
+            embed.textfield[1].text
+        );
+
+        let embed = clients_summary(&[]);
+        assert_eq!("No clients connected", embed.description);
+        assert!(embed.textfield.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_stats_reply() {
+        let settings = connected_settings().await;
+        settings.record_message(100).await;
+        settings.record_message(50).await;
+
+        match settings.stats_reply().await.message {
+            Some(messages::request::Message::ClientStats(stats)) => {
+                assert_eq!(2, stats.num_messages);
+                assert_eq!(150, stats.total_data);
+                assert!(stats.uptime_seconds < 60);
+            }
+            _ => panic!("Expected client stats"),
+        }
+    }
+
+    #[test]
+    fn test_drops_presence_on_cloud() {
+        let presence = messages::Response {
+            field: Some(messages::response::Field::Presence(
+                messages::Presence::new(),
+            )),
+            ..Default::default()
+        };
+        let embed = messages::Response {
+            field: Some(messages::response::Field::Embed(EmbedContent::new())),
+            ..Default::default()
+        };
+
+        assert!(drops_presence(&presence, true));
+        assert!(!drops_presence(&presence, false));
+        assert!(!drops_presence(&embed, true));
+        assert!(!drops_presence(&messages::Response::new(), true));
+    }
+
+    #[test]
+    fn test_is_text() {
+        assert!(is_text(None, b"G28\nG1 X10 Y10\n"));
+        assert!(is_text(Some("text/plain; charset=utf-8"), b"Print started"));
+        assert!(is_text(None, "\u{1f5a8} printing".as_bytes()));
+        assert!(is_text(None, b""));
+    }
+
+    #[test]
+    fn test_is_text_binary() {
+        assert!(!is_text(None, b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR"));
+        assert!(!is_text(None, &[0xff, 0xfe, 0xfd, 0x41]));
+        assert!(!is_text(Some("image/png"), b"looks like text"));
+    }
+
+    #[test]
+    fn test_is_text_multibyte_at_sniff_boundary() {
+        let mut data = vec![b'a'; TEXT_SNIFF_BYTES - 1];
+        data.extend_from_slice("\u{00e9}".as_bytes());
+        assert!(is_text(None, &data));
+    }
+
+    #[async_std::test]
+    async fn test_accept_until_overlapping_listeners() {
+        let accepted = Arc::new(Mutex::new(vec![]));
+        let (old_stop, old_receiver) = async_std::channel::bounded(1);
+        let (_new_stop, new_receiver) = async_std::channel::bounded(1);
+
+        let mut addrs = vec![];
+        for (name, stop) in [("old", old_receiver), ("new", new_receiver)] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addrs.push(listener.local_addr().unwrap());
+            let accepted = accepted.clone();
+            async_std::task::spawn(accept_until(
+                Listener::from(listener).into_incoming(),
+                stop,
+                move |_stream| {
+                    accepted.lock().unwrap().push(name);
+                    async {}
+                },
+            ));
+        }
+
+        // Both listeners accept during the overlap window.
+        let _old_client = TcpStream::connect(addrs[0]).await.unwrap();
+        let _new_client = TcpStream::connect(addrs[1]).await.unwrap();
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        let mut names = accepted.lock().unwrap().clone();
+        names.sort();
+        assert_eq!(vec!["new", "old"], names);
+
+        // Once the old listener is stopped, only the new one accepts.
+        old_stop.send(()).await.unwrap();
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        assert!(TcpStream::connect(addrs[0]).await.is_err());
+        let _new_client = TcpStream::connect(addrs[1]).await.unwrap();
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        assert_eq!(3, accepted.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_should_crosspost() {
+        assert!(should_crosspost(Some(ChannelType::News)));
+        assert!(!should_crosspost(Some(ChannelType::Text)));
+        assert!(!should_crosspost(None));
+    }
+
+    #[async_std::test]
+    async fn test_accounts_wire_frame_length() {
+        let server = Server::new();
+        let (settings, mut client) = connected_client().await;
+        let stream = settings.stream.read().await.clone();
+        let mut response = messages::Response::new();
+        response.set_embed(EmbedContent {
+            title: "Title".to_string(),
+            ..Default::default()
+        });
+        let data = response.write_to_bytes().unwrap();
+        write_frame(&mut client, &data).await.unwrap();
+        drop(client);
+
+        let (sender, receiver) = channel::bounded(1);
+        let reader = server.read_loop(stream, "client", sender, Duration::from_secs(5));
+        let (received, frame_length) = join(reader, receiver.recv()).await.1.unwrap();
+        assert_eq!(data.len(), frame_length);
+
+        let size = accounted_size(StatsSize::Wire, frame_length, received.compute_size());
+        settings.record_message(size).await;
+        assert_eq!(data.len() as u64, settings.get_stats().await.total_data);
+        assert_eq!(
+            received.compute_size(),
+            accounted_size(StatsSize::Logical, 100, received.compute_size())
+        );
+    }
+
+    #[test]
+    fn test_parse_channel_colors() {
+        let colors = parse_channel_colors("1:#ff0000, 2:0x00ff00,bogus,3:zz");
+        assert_eq!(2, colors.len());
+        assert_eq!(0xff0000, colors[&ChannelId(1)]);
+        assert_eq!(0x00ff00, colors[&ChannelId(2)]);
+    }
+
+    #[test]
+    fn test_embed_color_precedence() {
+        let colors = HashMap::from([(ChannelId(1), 0x111111)]);
+        let unset = EmbedContent::new();
+        assert_eq!(
+            0x111111,
+            effective_color(&unset, ChannelId(1), &colors, Some(0x222222))
+        );
+        assert_eq!(
+            0x222222,
+            effective_color(&unset, ChannelId(2), &colors, Some(0x222222))
+        );
+        assert_eq!(0, effective_color(&unset, ChannelId(2), &colors, None));
+
+        let color = EmbedContent {
+            color: 0x333333,
+            ..Default::default()
+        };
+        assert_eq!(
+            0x333333,
+            effective_color(&color, ChannelId(1), &colors, Some(0x222222))
+        );
+        // Black is a color of its own, not the lack of one.
+        let black = EmbedContent {
+            color_hex: "#000000".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            0,
+            effective_color(&black, ChannelId(1), &colors, Some(0x222222))
+        );
+        let invalid = EmbedContent {
+            color_hex: "black".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            0x111111,
+            effective_color(&invalid, ChannelId(1), &colors, Some(0x222222))
+        );
+    }
+
+    #[async_std::test]
+    async fn test_send_parts_retrying_recovers_from_one_failure() {
+        let cancel = CancellationToken::default();
+        let attempts = Mutex::new(vec![]);
+
+        let (sent, error) = send_parts_retrying(vec![1, 2, 3], &cancel, |part| {
+            let mut attempts = attempts.lock().unwrap();
+            let failed_before = attempts.contains(&part);
+            attempts.push(part);
+            async move {
+                if part == 2 && !failed_before {
+                    return Err(serenity::Error::Other("flaky"));
+                }
+                Ok(())
+            }
+        })
+        .await;
+
+        assert_eq!(3, sent.len());
+        assert!(error.is_none());
+        assert_eq!(vec![1, 2, 2, 3], *attempts.lock().unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_send_parts_retrying_stops_mid_sequence() {
+        let cancel = CancellationToken::default();
+        let attempts = Mutex::new(vec![]);
+
+        let (sent, error) = send_parts_retrying(vec![1, 2, 3, 4, 5], &cancel, |part| {
+            attempts.lock().unwrap().push(part);
+            async move {
+                if part == 3 {
+                    return Err(serenity::Error::Other("down"));
+                }
+                Ok(())
+            }
+        })
+        .await;
+
+        assert_eq!(2, sent.len());
+        assert!(error.is_some());
+        assert_eq!(vec![1, 2, 3, 3], *attempts.lock().unwrap());
+        assert_eq!(
+            "Upload of log.txt incomplete: only 2 of 5 parts were sent.",
+            incomplete_upload_notice("log.txt", sent.len(), 5)
+        );
+    }
+
+    #[async_std::test]
+    async fn test_timed_send_abandons_hung_send() {
+        let hung = future::pending::<serenity::Result<()>>();
+        let result = timed_send(hung, Duration::from_millis(10)).await;
+        assert!(matches!(
+            result,
+            Err(serenity::Error::Other("send timed out"))
+        ));
+
+        let result = timed_send(async { Ok(1) }, Duration::from_millis(10)).await;
+        assert_eq!(1, result.unwrap());
+    }
+
+    #[test]
+    fn test_plain_text_setting_selects_plain_render() {
+        let e = EmbedContent {
+            title: "Print done".to_string(),
+            description: "benchy.gcode".to_string(),
+            ..Default::default()
+        };
+        let markers = Markers::default();
+
+        match render_embed(e.clone(), true, &markers) {
+            Rendered::PlainText(messages) => {
+                assert_eq!(vec!["**Print done**\nbenchy.gcode".to_string()], messages)
+            }
+            Rendered::Embeds(_) => panic!("Expected plain text"),
+        }
+        assert!(matches!(
+            render_embed(e, false, &markers),
+            Rendered::Embeds(_)
+        ));
+    }
+
+    #[test]
+    fn test_validate_settings_clamps() {
+        let settings = messages::Settings {
+            channel_id: 5,
+            presence_enabled: true,
+            cycle_time: -10,
+            command_prefix: format!(" {} ", "!".repeat(40)),
+            forward_dms: true,
+            ..Default::default()
+        };
+
+        let applied = validate_settings(settings.clone(), false);
+        assert_eq!(5, applied.channel_id);
+        assert_eq!(0, applied.cycle_time);
+        assert_eq!("!".repeat(32), applied.command_prefix);
+        assert!(applied.presence_enabled);
+        assert!(applied.forward_dms);
+
+        let applied = validate_settings(settings, true);
+        assert!(!applied.presence_enabled);
+        assert!(!applied.forward_dms);
+    }
+
+    #[async_std::test]
+    async fn test_settings_are_confirmed_with_clamped_values() {
+        let (settings, mut client) = connected_client().await;
+        let applied = settings
+            .apply_settings(messages::Settings {
+                channel_id: 5,
+                cycle_time: i32::MAX,
+                ..Default::default()
+            })
+            .await;
+        assert_eq!(ChannelId(5), *settings.channel.read().await);
+        assert_eq!(MAX_CYCLE_TIME, *settings.cycle_time.lock().await);
+
+        let reply = messages::Request {
+            message: Some(messages::request::Message::SettingsApplied(applied)),
+            ..Default::default()
+        };
+        settings.send_request(&reply).await.unwrap();
+        match recv_request(&mut client).await.message {
+            Some(messages::request::Message::SettingsApplied(confirmed)) => {
+                assert_eq!(5, confirmed.channel_id);
+                assert_eq!(MAX_CYCLE_TIME, confirmed.cycle_time);
+            }
+            _ => panic!("Expected settings confirmation"),
+        }
+    }
+
+    #[async_std::test]
+    async fn test_disconnect_is_recorded_with_reason() {
+        let server = Server::new();
+        let (settings, mut client) = connected_client().await;
+        let peer_addr = client.local_addr().unwrap();
+
+        settings.disconnect("shutting down".to_string()).await;
+        assert!(!settings.cancel.is_cancelled());
+        // Replies still reach the client until the connection is closed.
+        let reply = messages::Request {
+            message: Some(messages::request::Message::Command("status".to_string())),
+            ..Default::default()
+        };
+        settings.send_request(&reply).await.unwrap();
+        settings.flush_and_close(Duration::from_secs(1)).await;
+        assert_eq!(reply, recv_request(&mut client).await);
+        let mut buf = [0u8; 1];
+        assert_eq!(0, client.read(&mut buf).await.unwrap());
+        assert_eq!(
+            Some("shutting down"),
+            settings.disconnect_reason.lock().await.as_deref()
+        );
+
+        server
+            .record_disconnect(&Peer::Ip(peer_addr), &settings)
+            .await;
+        let dropped = connected_settings().await;
+        server
+            .record_disconnect(&Peer::Ip(peer_addr), &dropped)
+            .await;
+        assert_eq!(1, *server.clean_disconnects.lock().await);
+        assert_eq!(1, *server.dropped_connections.lock().await);
+    }
+
+    #[test]
+    fn test_parse_bind() {
+        assert_eq!(
+            "0.0.0.0:23416",
+            parse_bind("0.0.0.0:23416").unwrap().to_string()
+        );
+        assert_eq!("0.0.0.0:4000", parse_bind(":4000").unwrap().to_string());
+        assert_eq!("[::]:23416", parse_bind("[::]:23416").unwrap().to_string());
+        assert_eq!(
+            "127.0.0.1:80",
+            parse_bind(" 127.0.0.1:80 ").unwrap().to_string()
+        );
+        assert!(parse_bind("23416").is_err());
+        assert!(parse_bind(":notaport").is_err());
+        assert!(parse_bind("0.0.0.0:99999").is_err());
+    }
+
+    #[async_std::test]
+    async fn test_oversized_frame_drops_connection() {
+        let server = Server::new();
+        let (settings, mut client) = connected_client().await;
+        let stream = settings.stream.read().await.clone();
+        client.write_all(&[0xff, 0xff, 0xff, 0xff]).await.unwrap();
+
+        let (sender, receiver) = channel::bounded(1);
+        // Returns without allocating or waiting for the 4GB of data.
+        let closed_cleanly = server
+            .read_loop(stream, "client", sender, Duration::from_secs(5))
+            .await;
+        assert!(!closed_cleanly);
+        assert!(receiver.recv().await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_idle_client_is_dropped() {
+        let server = Server::new();
+        let (settings, mut client) = connected_client().await;
+        let stream = settings.stream.read().await.clone();
+
+        let (sender, receiver) = channel::bounded(1);
+        let reader = server.read_loop(stream, "client", sender, Duration::from_millis(200));
+        let client_writes = async {
+            // Each frame resets the idle timeout, so both frames arrive before it fires.
+            for _ in 0..2 {
+                async_std::task::sleep(Duration::from_millis(120)).await;
+                let data = messages::Response::new().write_to_bytes().unwrap();
+                write_frame(&mut client, &data).await.unwrap();
+            }
+        };
+        let received = async {
+            let mut count = 0;
+            while receiver.recv().await.is_ok() {
+                count += 1;
+            }
+            count
+        };
+        let (closed_cleanly, (), count) = futures::join!(reader, client_writes, received);
+        assert!(!closed_cleanly);
+        assert_eq!(2, count);
+    }
+
+    #[test]
+    fn test_edit_target() {
+        let previous = Some((ChannelId(1), MessageId(10)));
+        assert_eq!(Some(MessageId(10)), edit_target(previous, ChannelId(1)));
+        // The client moved channels, so a fresh message is sent there.
+        assert_eq!(None, edit_target(previous, ChannelId(2)));
+        assert_eq!(None, edit_target(None, ChannelId(1)));
+    }
+
+    #[test]
+    fn test_is_unknown_message_ignores_other_errors() {
+        assert!(!is_unknown_message(&serenity::Error::Other("down")));
+    }
+
+    #[async_std::test]
+    async fn test_heartbeat_drops_client_missing_pongs() {
+        let (settings, mut client) = connected_client().await;
+        *settings.missed_pongs.lock().await = Some(0);
+        let pinger = heartbeat(&settings, Duration::from_millis(10), 2);
+        let pings = async {
+            let mut pings = 0;
+            while let Ok(length) = read_length(&mut client, Duration::from_secs(1)).await {
+                let mut buf = vec![0u8; length];
+                client.read_exact(&mut buf).await.unwrap();
+                let request = messages::Request::parse_from_bytes(&buf).unwrap();
+                assert!(matches!(
+                    request.message,
+                    Some(messages::request::Message::Ping(_))
+                ));
+                pings += 1;
+            }
+            pings
+        };
+        let ((), pings) = join(pinger, pings).await;
+        assert_eq!(2, pings);
+    }
+
+    #[async_std::test]
+    async fn test_heartbeat_keeps_answering_client() {
+        let (settings, _client) = connected_client().await;
+        let pinger = heartbeat(&settings, Duration::from_millis(10), 1);
+        let ponger = async {
+            for _ in 0..5 {
+                async_std::task::sleep(Duration::from_millis(5)).await;
+                *settings.missed_pongs.lock().await = Some(0);
+            }
+        };
+        // Answered pings keep the heartbeat running, so only the client finishes.
+        let finished = future::select(Box::pin(pinger), Box::pin(ponger)).await;
+        assert!(matches!(finished, future::Either::Right(_)));
+    }
+
+    #[async_std::test]
+    async fn test_heartbeat_keeps_client_that_never_answered() {
+        let (settings, _client) = connected_client().await;
+        let pinger = heartbeat(&settings, Duration::from_millis(10), 1);
+        let waited = async_std::task::sleep(Duration::from_millis(100));
+        let finished = future::select(Box::pin(pinger), Box::pin(waited)).await;
+        assert!(matches!(finished, future::Either::Right(_)));
+        assert!(!settings.cancel.is_cancelled());
+    }
+
+    fn quick_retries(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            delay: Duration::from_millis(1),
+        }
+    }
+
+    fn server_error() -> serenity::Error {
+        let response = ErrorResponse {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            url: Url::parse("https://discord.com/api/v10/channels/1/messages").unwrap(),
+            error: serde_json::from_str(r#"{"code": 0, "message": "Internal Server Error"}"#)
+                .unwrap(),
+        };
+        serenity::Error::Http(Box::new(HttpError::UnsuccessfulRequest(response)))
+    }
+
+    #[async_std::test]
+    async fn test_with_retries_recovers_from_transient_errors() {
+        let attempts = Mutex::new(0);
+        let result = with_retries(&quick_retries(2), Duration::from_secs(1), || {
+            let mut attempts = attempts.lock().unwrap();
+            *attempts += 1;
+            let attempt = *attempts;
+            async move {
+                if attempt < 3 {
+                    return Err(server_error());
+                }
+                Ok(attempt)
+            }
+        })
+        .await;
+        assert_eq!(3, result.unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_with_retries_is_bounded() {
+        let attempts = Mutex::new(0);
+        let result: serenity::Result<()> =
+            with_retries(&quick_retries(2), Duration::from_secs(1), || {
+                *attempts.lock().unwrap() += 1;
+                async { Err(server_error()) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(3, *attempts.lock().unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_with_retries_gives_up_on_fatal_errors() {
+        let attempts = Mutex::new(0);
+        let result: serenity::Result<()> =
+            with_retries(&quick_retries(2), Duration::from_secs(1), || {
+                *attempts.lock().unwrap() += 1;
+                async { Err(serenity::Error::Other("forbidden")) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(1, *attempts.lock().unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_with_retries_gives_up_on_timeouts() {
+        // Discord may have posted a send that timed out, trying again could post it twice.
+        let attempts = Mutex::new(0);
+        let result: serenity::Result<()> =
+            with_retries(&quick_retries(2), Duration::from_secs(1), || {
+                *attempts.lock().unwrap() += 1;
+                async { Err(serenity::Error::Other(SEND_TIMED_OUT)) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(1, *attempts.lock().unwrap());
+    }
+
+    fn auth_frame(token: &str) -> Vec<u8> {
+        let mut response = messages::Response::new();
+        response.set_auth(messages::Auth {
+            token: token.to_string(),
+            ..Default::default()
+        });
+        response.write_to_bytes().unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_authenticate_accepts_matching_token() {
+        let (settings, mut client) = connected_client().await;
+        let mut stream = settings.stream.read().await.clone();
+        write_frame(&mut client, &auth_frame("secret"))
+            .await
+            .unwrap();
+        assert!(authenticate(&mut stream, "secret", Duration::from_secs(1)).await);
+    }
+
+    #[async_std::test]
+    async fn test_authenticate_rejects_bad_handshakes() {
+        let (settings, mut client) = connected_client().await;
+        let mut stream = settings.stream.read().await.clone();
+        write_frame(&mut client, &auth_frame("guess"))
+            .await
+            .unwrap();
+        assert!(!authenticate(&mut stream, "secret", Duration::from_secs(1)).await);
+
+        // Anything other than Auth first is rejected, even if it is a valid response.
+        let embed = messages::Response::new().write_to_bytes().unwrap();
+        write_frame(&mut client, &embed).await.unwrap();
+        assert!(!authenticate(&mut stream, "secret", Duration::from_secs(1)).await);
+
+        // As is a client that never sends anything.
+        assert!(!authenticate(&mut stream, "secret", Duration::from_millis(50)).await);
+    }
+
+    #[async_std::test]
+    async fn test_bad_frame_is_skipped() {
+        let server = Server::new();
+        let (settings, mut client) = connected_client().await;
+        let stream = settings.stream.read().await.clone();
+        write_frame(&mut client, &[0xff, 0xff, 0xff]).await.unwrap();
+        let mut response = messages::Response::new();
+        response.set_stats_query(messages::StatsQuery::new());
+        write_frame(&mut client, &response.write_to_bytes().unwrap())
+            .await
+            .unwrap();
+        drop(client);
+
+        let (sender, receiver) = channel::bounded(2);
+        server
+            .read_loop(stream, "client", sender, Duration::from_secs(5))
+            .await;
+        let (received, _) = receiver.recv().await.unwrap();
+        assert_eq!(response, received);
+        assert!(receiver.recv().await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_stats_include_connection_time() {
+        let settings = connected_settings().await;
+        let stats = settings.get_stats().await;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(now - stats.connected_at < 60);
+        assert!(stats.connected_seconds < 60);
+
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.serialize(stats).unwrap();
+        let csv = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        assert!(csv.starts_with(
+            "ip,num_messages,total_data,dropped_presence,dropped_frames,connected_at,connected_seconds\n"
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_routes() {
+        let settings = connected_settings().await;
+        settings
+            .apply_settings(messages::Settings {
+                channel_id: 1,
+                routes: vec![messages::ChannelRoute {
+                    name: "alerts".to_string(),
+                    channel_id: 2,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+            .await;
+
+        assert_eq!(ChannelId(1), settings.target_channel("").await);
+        assert_eq!(ChannelId(2), settings.target_channel("alerts").await);
+        assert_eq!(ChannelId(1), settings.target_channel("unknown").await);
+
+        assert!(settings.listens_to(ChannelId(1)).await);
+        assert!(settings.listens_to(ChannelId(2)).await);
+        assert!(!settings.listens_to(ChannelId(3)).await);
+        assert!(!settings.listens_to(ChannelId(0)).await);
+    }
+
+    #[test]
+    fn test_strip_command_prefix() {
+        assert_eq!(
+            Some("status".to_string()),
+            strip_command_prefix("!", "!status")
+        );
+        assert_eq!(None, strip_command_prefix("!", "hello everyone"));
+        assert_eq!(
+            Some("status".to_string()),
+            strip_command_prefix("/print", "/print status")
+        );
+        // Without a prefix every message is a command.
+        assert_eq!(Some("hello".to_string()), strip_command_prefix("", "hello"));
+    }
+
+    #[async_std::test]
+    async fn test_send_command_filters_by_prefix() {
+        let server = Server::new();
+        let (settings, mut client) = connected_client().await;
+        settings
+            .apply_settings(messages::Settings {
+                channel_id: 7,
+                command_prefix: "/print ".to_string(),
+                ..Default::default()
+            })
+            .await;
+        server.clients.lock().await.push(Arc::new(settings));
+
+        server
+            .send_command(ChannelId(7), UserId(1), "chatter".to_string())
+            .await;
+        server
+            .send_command(ChannelId(7), UserId(1), "/print status".to_string())
+            .await;
+        let request = recv_request(&mut client).await;
+        assert_eq!("status", request.command());
+    }
+
+    #[test]
+    fn test_allowed_users() {
+        let allowed = parse_user_ids("1, 2,,bogus");
+        assert_eq!(vec![UserId(1), UserId(2)], allowed);
+        assert!(is_allowed(&allowed, UserId(1)));
+        assert!(!is_allowed(&allowed, UserId(3)));
+        assert!(is_allowed(&[], UserId(3)));
+    }
+
+    #[async_std::test]
+    async fn test_send_command_skips_users_not_allowed() {
+        let server = Server::new();
+        let (settings, mut client) = connected_client().await;
+        settings
+            .apply_settings(messages::Settings {
+                channel_id: 7,
+                allowed_users: vec![2],
+                ..Default::default()
+            })
+            .await;
+        server.clients.lock().await.push(Arc::new(settings));
+
+        server
+            .send_command(ChannelId(7), UserId(1), "start print".to_string())
+            .await;
+        server
+            .send_command(ChannelId(7), UserId(2), "status".to_string())
+            .await;
+        let request = recv_request(&mut client).await;
+        assert_eq!(2, request.user);
+        assert_eq!("status", request.command());
+    }
+
+    #[test]
+    fn test_oversized_attachment_notice() {
+        assert_eq!(
+            "snapshot.png was not attached, it is 9000000 bytes and the attachment limit is 8388608 bytes.",
+            oversized_attachment_notice("snapshot.png", 9000000, 8388608)
+        );
+    }
+
+    #[async_std::test]
+    async fn test_shutdown_notifies_clients_and_stops_listener() {
+        let server = Server::new();
+        let (stop, _) = server.replace_listener().await;
+        let (settings, mut client) = connected_client().await;
+        server.clients.lock().await.push(Arc::new(settings));
+
+        server.shutdown().await;
+        assert!(stop.recv().await.is_ok());
+        match recv_request(&mut client).await.message {
+            Some(messages::request::Message::Disconnect(disconnect)) => {
+                assert_eq!("Server shutting down", disconnect.reason);
+            }
+            _ => panic!("Expected a disconnect"),
+        }
+
+        assert!(!server.wait_for_clients(Duration::from_millis(50)).await);
+        server.clients.lock().await.clear();
+        assert!(server.wait_for_clients(Duration::from_millis(50)).await);
+    }
+
+    #[async_std::test]
+    async fn test_client_cap() {
+        let server = Server::new();
+        for _ in 0..2 {
+            let settings = Arc::new(connected_settings().await);
+            assert!(server.try_add_client(settings, Some(2)).await);
+        }
+        let settings = Arc::new(connected_settings().await);
+        assert!(!server.try_add_client(settings.clone(), Some(2)).await);
+        assert_eq!(2, server.clients.lock().await.len());
+
+        assert!(server.try_add_client(settings, None).await);
+        assert_eq!(3, server.clients.lock().await.len());
+    }
+
+    #[test]
+    fn test_create_embed_footer_and_timestamp() {
+        let server = Server::new();
+        let e = EmbedContent {
+            footer_text: "Updated".to_string(),
+            footer_icon_url: "https://example.com/icon.png".to_string(),
+            timestamp: "2024-01-31T12:00:00Z".to_string(),
+            ..Default::default()
+        };
+        let embed = server.create_embed(e, None);
+        assert_eq!("Updated", embed.0["footer"]["text"]);
+        assert_eq!(
+            "https://example.com/icon.png",
+            embed.0["footer"]["icon_url"]
+        );
+        assert_eq!("2024-01-31T12:00:00.000Z", embed.0["timestamp"]);
+
+        let e = EmbedContent {
+            timestamp: "yesterday".to_string(),
+            ..Default::default()
+        };
+        let embed = server.create_embed(e, None);
+        assert!(!embed.0.contains_key("footer"));
+        assert!(!embed.0.contains_key("timestamp"));
+    }
+
+    fn snapshot(filename: &str, size: usize) -> ProtoFile {
+        ProtoFile {
+            filename: filename.to_string(),
+            data: vec![0; size],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_attachable_snapshots() {
+        let (attachable, notices) = attachable_snapshots(vec![], 100);
+        assert!(attachable.is_empty());
+        assert!(notices.is_empty());
+
+        let snapshots = vec![
+            snapshot("a.png", 10),
+            snapshot("big.png", 101),
+            snapshot("b.png", 100),
+        ];
+        let (attachable, notices) = attachable_snapshots(snapshots, 100);
+        let names: Vec<_> = attachable.iter().map(|s| s.filename.as_str()).collect();
+        assert_eq!(vec!["a.png", "b.png"], names);
+        assert_eq!(
+            vec![oversized_attachment_notice("big.png", 101, 100)],
+            notices
+        );
+
+        let snapshots = (0..DISCORD_MAX_ATTACHMENTS + 1)
+            .map(|i| snapshot(&format!("{i}.png"), 1))
+            .collect();
+        let (attachable, notices) = attachable_snapshots(snapshots, 100);
+        assert_eq!(DISCORD_MAX_ATTACHMENTS, attachable.len());
+        assert_eq!(1, notices.len());
+    }
+
+    #[test]
+    fn test_typing_indicators() {
+        let mut typing = TypingIndicators::default();
+        let first = typing.start(ChannelId(1), "first");
+        let second = typing.start(ChannelId(1), "second");
+        // The first command's timeout must not stop the indicator of the second.
+        assert_eq!(None, typing.expire(ChannelId(1), first));
+        assert_eq!(Some("second"), typing.expire(ChannelId(1), second));
+
+        typing.start(ChannelId(2), "response");
+        assert_eq!(Some("response"), typing.stop(ChannelId(2)));
+        assert_eq!(None, typing.stop(ChannelId(2)));
+    }
+
+    #[async_std::test]
+    async fn test_send_direct_command_to_opted_in_clients() {
+        let server = Server::new();
+        let (channel_only, _) = connected_client().await;
+        channel_only
+            .apply_settings(messages::Settings {
+                channel_id: 7,
+                ..Default::default()
+            })
+            .await;
+        let (everyone, _) = connected_client().await;
+        everyone
+            .apply_settings(messages::Settings {
+                channel_id: 7,
+                forward_dms: true,
+                ..Default::default()
+            })
+            .await;
+        let (dms, mut client) = connected_client().await;
+        dms.apply_settings(messages::Settings {
+            channel_id: 7,
+            forward_dms: true,
+            allowed_users: vec![3],
+            ..Default::default()
+        })
+        .await;
+        server.clients.lock().await.push(Arc::new(channel_only));
+        server.clients.lock().await.push(Arc::new(everyone));
+        server.clients.lock().await.push(Arc::new(dms));
+
+        let sent = server
+            .send_direct_command(UserId(4), "status".to_string())
+            .await;
+        assert_eq!(0, sent);
+        let sent = server
+            .send_direct_command(UserId(3), "status".to_string())
+            .await;
+        assert_eq!(1, sent);
+        let request = recv_request(&mut client).await;
+        assert_eq!("status", request.command());
+        assert_eq!(3, request.user);
+        assert!(request.direct_message);
+    }
+
+    #[async_std::test]
+    async fn test_totals_survive_connections() {
+        let server = Server::new();
+        let restored = Totals {
+            num_messages: 5,
+            total_data: 500,
+        };
+        server
+            .restore_totals(BTreeMap::from([("127.0.0.1".to_string(), restored)]))
+            .await;
+
+        let settings = connected_settings().await;
+        server.record_total(&settings, 100).await;
+        drop(settings);
+        let settings = connected_settings().await;
+        server.record_total(&settings, 100).await;
+
+        let totals = server.totals().await;
+        assert_eq!(7, totals["127.0.0.1"].num_messages);
+        assert_eq!(700, totals["127.0.0.1"].total_data);
+    }
+
+    #[test]
+    fn test_activity_kind() {
+        assert_eq!(
+            Some(ActivityKind::Watching),
+            ActivityKind::parse("Watching")
+        );
+        assert_eq!(
+            Some(ActivityKind::Listening),
+            ActivityKind::parse(" listening ")
+        );
+        assert_eq!(None, ActivityKind::parse("competing"));
+
+        let url = Url::parse("https://example.com/").unwrap();
+        let activity = ActivityKind::Watching.activity("3 printers".to_string(), &url);
+        assert_eq!(ActivityType::Watching, activity.kind);
+        assert_eq!("3 printers", activity.name);
+        assert!(activity.url.is_none());
+
+        let activity = ActivityKind::Streaming.activity("to 3 instances".to_string(), &url);
+        assert_eq!(ActivityType::Streaming, activity.kind);
+        assert_eq!(Some(url), activity.url);
+    }
+
+    #[async_std::test]
+    async fn test_gzip_frames() {
+        let server = Server::new();
+        let (settings, mut client) = connected_client().await;
+        let stream = settings.stream.read().await.clone();
+        settings
+            .apply_settings(messages::Settings {
+                channel_id: 7,
+                gzip: true,
+                ..Default::default()
+            })
+            .await;
+
+        // Outbound frames are compressed once the client asked for it.
+        server.clients.lock().await.push(Arc::new(settings));
+        server
+            .send_command(ChannelId(7), UserId(1), "status".repeat(100))
+            .await;
+        let prefix = read_length(&mut client, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_ne!(0, prefix & GZIP_FLAG);
+        let mut buf = vec![0u8; prefix & !GZIP_FLAG];
+        client.read_exact(&mut buf).await.unwrap();
+        let request =
+            messages::Request::parse_from_bytes(&gunzip(&buf, 1024 * 1024).unwrap()).unwrap();
+        assert_eq!("status".repeat(100), request.command());
+
+        // Inbound frames may be compressed or not.
+        let mut response = messages::Response::new();
+        response.set_embed(EmbedContent {
+            title: "Title".repeat(100),
+            ..Default::default()
+        });
+        let data = response.write_to_bytes().unwrap();
+        write_gzip_frame(&mut client, &data).await.unwrap();
+        write_frame(&mut client, &data).await.unwrap();
+        drop(client);
+
+        let (sender, receiver) = channel::bounded(2);
+        server
+            .read_loop(stream, "client", sender, Duration::from_secs(5))
+            .await;
+        let (compressed, compressed_length) = receiver.recv().await.unwrap();
+        let (plain, plain_length) = receiver.recv().await.unwrap();
+        assert_eq!(response, compressed);
+        assert_eq!(response, plain);
+        assert!(compressed_length < plain_length);
+    }
+
+    #[test]
+    fn test_attachment_filenames() {
+        assert_eq!(
+            vec!["passwd", "evil.sh", "report.txt", "attachment", "config"],
+            attachment_filenames([
+                "../../etc/passwd",
+                "..\\windows\\evil.sh",
+                "rep\0o<r>t?.txt",
+                "..",
+                ".config",
+            ])
+        );
+    }
+
+    #[test]
+    fn test_attachment_filenames_unique() {
+        assert_eq!(
+            vec!["image.png", "image_1.png", "image_2.png", "log", "log_1"],
+            attachment_filenames(["image.png", "a/image.png", "image.png", "log", "log"])
+        );
+    }
+
+    #[async_std::test]
+    async fn test_response_before_settings_lacks_channel() {
+        let settings = connected_settings().await;
+        let mut embed = messages::Response::new();
+        embed.set_embed(EmbedContent::new());
+        let mut file = messages::Response::new();
+        file.set_file(ProtoFile::new());
+
+        let unset = settings.target_channel("").await;
+        assert!(lacks_channel(&embed, unset));
+        assert!(lacks_channel(&file, unset));
+
+        // Responses that name their own destination, or aren't sent anywhere, are fine.
+        let mut fanout = embed.clone();
+        fanout.fanout_channels = vec![1];
+        assert!(!lacks_channel(&fanout, unset));
+        let mut dm = file.clone();
+        dm.dm_user = 1;
+        assert!(!lacks_channel(&dm, unset));
+        let mut ping = messages::Response::new();
+        ping.set_ping(messages::Ping::new());
+        assert!(!lacks_channel(&ping, unset));
+
+        settings
+            .apply_settings(messages::Settings {
+                channel_id: 7,
+                ..Default::default()
+            })
+            .await;
+        assert!(!lacks_channel(&embed, settings.target_channel("").await));
+    }
+
+    #[async_std::test]
+    async fn test_writer_drops_stalled_client() {
+        // The client never reads, so the socket buffers fill up and the write stalls.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let (queue, frames, rung) = OutboundQueue::new(1, Overflow::DropNewest);
+        queue.push(frame(64 * 1024 * 1024, false)).unwrap();
+
+        let cancel = Arc::new(CancellationToken::default());
+        let write_timeout = Duration::from_millis(100);
+        let peer = Peer::Ip(stream.peer_addr().unwrap());
+        write_frames(
+            stream.into(),
+            peer,
+            frames,
+            rung,
+            write_timeout,
+            cancel.clone(),
+        )
+        .await;
+        assert!(cancel.is_cancelled());
+        let error = queue.push(frame(4, false)).unwrap_err();
+        assert_eq!(std::io::ErrorKind::BrokenPipe, error.kind());
+    }
+
+    fn frame(len: usize, droppable: bool) -> Frame {
+        Frame {
+            data: vec![len as u8; len],
+            droppable,
+        }
+    }
+
+    fn queued(frames: &Frames) -> Vec<usize> {
+        frames
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|f| f.data.len())
+            .collect()
+    }
+
+    #[test]
+    fn test_outbound_queue_overflow() {
+        let (queue, frames, _rung) = OutboundQueue::new(2, Overflow::DropOldest);
+        assert!(!queue.push(frame(1, false)).unwrap());
+        assert!(!queue.push(frame(2, true)).unwrap());
+        assert!(queue.push(frame(3, true)).unwrap());
+        // Only droppable frames give way, anything else is queued regardless.
+        assert!(!queue.push(frame(4, false)).unwrap());
+        assert_eq!(vec![1, 3, 4], queued(&frames));
+
+        let (queue, frames, _rung) = OutboundQueue::new(2, Overflow::DropNewest);
+        assert!(!queue.push(frame(1, true)).unwrap());
+        assert!(!queue.push(frame(2, true)).unwrap());
+        assert!(queue.push(frame(3, true)).unwrap());
+        assert!(!queue.push(frame(4, false)).unwrap());
+        assert_eq!(vec![1, 2, 4], queued(&frames));
+
+        queue.close();
+        assert!(queue.push(frame(5, false)).is_err());
+
+        let command = messages::Request {
+            message: Some(messages::request::Message::Command("status".to_string())),
+            ..Default::default()
+        };
+        assert!(!is_droppable(&command));
+        assert!(!is_droppable(&build_greeting("shim".to_string())));
+        assert!(is_droppable(&build_message_delete_request(MessageId(1))));
+    }
+
+    #[async_std::test]
+    async fn test_full_outbound_queue_drops_frames() {
+        let (settings, mut client) = connected_client().await;
+        let file = messages::Request {
+            message: Some(messages::request::Message::File(ProtoFile {
+                data: vec![0; 64 * 1024],
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        // Nothing is read until far more than the socket buffers hold has been sent, and sending
+        // never waits for it.
+        let sent = async {
+            for _ in 0..1_000 {
+                settings.send_request(&file).await.unwrap();
+            }
+        };
+        async_std::future::timeout(Duration::from_secs(5), sent)
+            .await
+            .unwrap();
+        assert!(settings.get_stats().await.dropped_frames > 0);
+
+        assert_eq!(file, recv_request(&mut client).await);
+        settings.reset_stats().await;
+        assert_eq!(0, settings.get_stats().await.dropped_frames);
+    }
+
+    #[async_std::test]
+    async fn test_queued_frames_are_written_before_closing() {
+        let (settings, mut client) = connected_client().await;
+        let goodbye = messages::Request {
+            message: Some(messages::request::Message::Disconnect(Default::default())),
+            ..Default::default()
+        };
+        settings.send_request(&goodbye).await.unwrap();
+        settings.flush_and_close(Duration::from_secs(1)).await;
+
+        assert_eq!(goodbye, recv_request(&mut client).await);
+        let mut buf = [0u8; 1];
+        assert_eq!(0, client.read(&mut buf).await.unwrap());
+        assert!(settings.send_request(&goodbye).await.is_err());
+    }
+
+    #[test]
+    fn test_dry_run_summary() {
+        let mut embed = messages::Response::new();
+        embed.set_embed(EmbedContent {
+            title: "Print done <@&42>".to_string(),
+            color: 0xff0000,
+            snapshots: vec![ProtoFile {
+                filename: "snapshot.png".to_string(),
+                data: vec![0; 10],
+                ..Default::default()
+            }],
+            textfield: vec![Default::default(); 2],
+            ..Default::default()
+        });
+        assert_eq!(
+            Some(
+                "embed to channel 7: title [Print done <@&42>], description [], 2 fields, color #ff0000, snapshots [\"snapshot.png (10 bytes)\"], mentions [<@&42>], in 1 messages"
+                    .to_string()
+            ),
+            dry_run_summary(&embed, ChannelId(7))
+        );
+
+        let mut file = messages::Response::new();
+        file.set_file(ProtoFile {
+            filename: "print.gcode".to_string(),
+            data: vec![0; 3],
+            ..Default::default()
+        });
+        file.dm_user = 5;
+        assert_eq!(
+            Some("file to a direct message to user 5: print.gcode (3 bytes)".to_string()),
+            dry_run_summary(&file, ChannelId(7))
+        );
+
+        let mut ping = messages::Response::new();
+        ping.set_ping(messages::Ping::new());
+        assert_eq!(None, dry_run_summary(&ping, ChannelId(7)));
+    }
+
+    /// Records the responses it is handed, instead of sending them to Discord.
+    /// What a test's dispatch was asked to post.
+    #[derive(Debug, PartialEq)]
+    enum Posted {
+        File(ChannelId, ProtoFile),
+        Embed(ChannelId, EmbedContent),
+        Deleted(ChannelId, MessageId),
+    }
+
+    /// Records what would be posted instead of posting it, taking `delay` to post each, as a slow
+    /// Discord send would. Each is posted as a single message with id 1.
+    struct RecordingDispatch {
+        posted: channel::Sender<Posted>,
+        delay: Duration,
+        // Whether sends fail, after being recorded.
+        failing: bool,
+    }
+
+    impl RecordingDispatch {
+        fn new(posted: channel::Sender<Posted>) -> Self {
+            RecordingDispatch {
+                posted,
+                delay: Duration::ZERO,
+                failing: false,
+            }
+        }
+
+        async fn record(&self, posted: Posted) -> serenity::Result<Vec<MessageId>> {
+            let _ = self.posted.send(posted).await;
+            async_std::task::sleep(self.delay).await;
+            if self.failing {
+                return Err(serenity::Error::Other("send failed"));
+            }
+            Ok(vec![MessageId(1)])
+        }
+    }
+
+    #[async_trait]
+    impl Dispatch for RecordingDispatch {
+        async fn send_file(
+            &self,
+            _server: &Server,
+            channel: ChannelId,
+            file: &ProtoFile,
+            _cancel: &CancellationToken,
+        ) -> serenity::Result<Vec<MessageId>> {
+            self.record(Posted::File(channel, file.clone())).await
+        }
+
+        async fn send_embed(
+            &self,
+            _server: &Server,
+            channel: ChannelId,
+            embed: EmbedContent,
+            _plain_text: bool,
+            _cancel: &CancellationToken,
+        ) -> serenity::Result<Vec<MessageId>> {
+            self.record(Posted::Embed(channel, embed)).await
+        }
+
+        async fn edit_embed(
+            &self,
+            _server: &Server,
+            _settings: &DiscordSettings,
+            channel: ChannelId,
+            edit: messages::EditEmbed,
+        ) -> serenity::Result<MessageId> {
+            let embed = edit.embed.unwrap_or_default();
+            self.record(Posted::Embed(channel, embed)).await?;
+            Ok(MessageId(1))
+        }
+
+        async fn delete_message(
+            &self,
+            channel: ChannelId,
+            message: MessageId,
+        ) -> serenity::Result<()> {
+            let _ = self.posted.send(Posted::Deleted(channel, message)).await;
+            Ok(())
+        }
+
+        async fn create_dm_channel(&self, user: UserId) -> serenity::Result<ChannelId> {
+            Ok(ChannelId(user.0))
+        }
+
+        async fn set_presence(&self, _activity: serenity::model::gateway::Activity) {}
+
+        async fn clients_changed(&self, _server: &Server, _num_servers: usize) {}
+    }
+
+    /// Reads requests until one `expected` accepts, skipping the rest.
+    async fn recv_matching(
+        client: &mut TcpStream,
+        expected: impl Fn(&messages::Request) -> bool,
+    ) -> messages::Request {
+        loop {
+            let request = recv_request(client).await;
+            if expected(&request) {
+                return request;
+            }
+        }
+    }
+
+    fn raw_frame(response: &messages::Response) -> Vec<u8> {
+        let data = response.write_to_bytes().unwrap();
+        let mut frame = (data.len() as u32).to_le_bytes().to_vec();
+        frame.extend(data);
+        frame
+    }
+
+    #[async_std::test]
+    async fn test_protocol_end_to_end() {
+        let server = Arc::new(Server::new());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (recorded, posted) = channel::unbounded();
+        let (stop, stop_receiver) = channel::bounded(1);
+        let listening = {
+            let server = server.clone();
+            async_std::task::spawn(async move {
+                let dispatch = Arc::new(RecordingDispatch::new(recorded));
+                server
+                    .listen_with(vec![listener.into()], dispatch, stop_receiver)
+                    .await
+            })
+        };
+        let mut client = TcpStream::connect(addr).await.unwrap();
+
+        let mut settings = messages::Response::new();
+        settings.set_settings(messages::Settings {
+            channel_id: 7,
+            ..Default::default()
+        });
+        let embed = EmbedContent {
+            title: "Title".to_string(),
+            ..Default::default()
+        };
+        let mut embed_response = messages::Response::new();
+        embed_response.set_embed(embed.clone());
+        let mut ping = messages::Response::new();
+        ping.set_ping(messages::Ping::new());
+        let file = ProtoFile {
+            filename: "print.gcode".to_string(),
+            data: vec![7; 5000],
+            ..Default::default()
+        };
+        let mut file_response = messages::Response::new();
+        file_response.set_file(file.clone());
+
+        // Three frames in a single write, then one trickling in a byte at a time for its prefix.
+        let mut frames = raw_frame(&settings);
+        frames.extend(raw_frame(&embed_response));
+        frames.extend(raw_frame(&ping));
+        client.write_all(&frames).await.unwrap();
+        let frame = raw_frame(&file_response);
+        for byte in &frame[..4] {
+            client.write_all(&[*byte]).await.unwrap();
+        }
+        client.write_all(&frame[4..]).await.unwrap();
+
+        assert_eq!(
+            Posted::Embed(ChannelId(7), embed),
+            posted.recv().await.unwrap()
+        );
+        assert_eq!(
+            Posted::File(ChannelId(7), file),
+            posted.recv().await.unwrap()
+        );
+        recv_matching(&mut client, |request| request.has_pong()).await;
+
+        // Commands come back framed the same way.
+        server
+            .send_command(ChannelId(7), UserId(1), "status".to_string())
+            .await;
+        let request = recv_matching(&mut client, |request| request.has_command()).await;
+        assert_eq!("status", request.command());
+        assert_eq!(1, request.user);
+
+        drop(client);
+        stop.send(()).await.unwrap();
+        listening.await;
+        assert!(server.wait_for_clients(Duration::from_secs(5)).await);
+    }
+
+    #[test]
+    fn test_delete_target() {
+        let mut edited = HashMap::from([("progress".to_string(), (ChannelId(1), MessageId(10)))]);
+        let mut sent = VecDeque::new();
+        let by_key = messages::DeleteMessage {
+            key: "progress".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some((ChannelId(1), MessageId(10))),
+            delete_target(&mut edited, &mut sent, &by_key)
+        );
+        // The key is forgotten, so deleting it again does nothing.
+        assert!(edited.is_empty());
+        assert_eq!(None, delete_target(&mut edited, &mut sent, &by_key));
+
+        // Only messages reported to the client can be deleted by id.
+        let by_id = messages::DeleteMessage {
+            message_id: 20,
+            ..Default::default()
+        };
+        assert_eq!(None, delete_target(&mut edited, &mut sent, &by_id));
+        remember_sent(&mut sent, ChannelId(2), &[MessageId(20), MessageId(21)]);
+        assert_eq!(
+            Some((ChannelId(2), MessageId(20))),
+            delete_target(&mut edited, &mut sent, &by_id)
+        );
+        assert_eq!(None, delete_target(&mut edited, &mut sent, &by_id));
+        let empty = messages::DeleteMessage::new();
+        assert_eq!(None, delete_target(&mut edited, &mut sent, &empty));
+
+        let ids: Vec<_> = (1..=MAX_SENT_MESSAGES as u64 + 1).map(MessageId).collect();
+        remember_sent(&mut sent, ChannelId(2), &ids);
+        assert_eq!(MAX_SENT_MESSAGES, sent.len());
+        assert_eq!(Some(&(ChannelId(2), MessageId(2))), sent.front());
+    }
+
+    #[test]
+    fn test_message_sent() {
+        let request = message_sent(
+            "build-42".to_string(),
+            ChannelId(7),
+            &[MessageId(100), MessageId(101)],
+        );
+        let Some(messages::request::Message::MessageSent(sent)) = request.message else {
+            panic!("Expected MessageSent, got {request:?}");
+        };
+        assert_eq!("build-42", sent.correlation_key);
+        assert_eq!(7, sent.channel_id);
+        assert_eq!(vec![100, 101], sent.message_ids);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(60, start);
+        for _ in 0..60 {
+            assert!(bucket.try_take(1, start));
+        }
+        assert!(!bucket.try_take(1, start));
+        // One message per second comes back.
+        assert!(bucket.try_take(1, start + Duration::from_secs(1)));
+        assert!(!bucket.try_take(1, start + Duration::from_secs(1)));
+        // A fan-out wider than the burst only needs a full bucket.
+        assert!(bucket.try_take(100, start + Duration::from_secs(120)));
+
+        let mut unlimited = TokenBucket::new(0, start);
+        assert!((0..1000).all(|_| unlimited.try_take(1, start)));
+
+        let mut ping = messages::Response::new();
+        ping.set_ping(messages::Ping::new());
+        assert!(!is_rate_limited(&ping));
+        let mut embed = messages::Response::new();
+        embed.set_embed(EmbedContent::new());
+        assert!(is_rate_limited(&embed));
+    }
+
+    #[async_std::test]
+    async fn test_json_lines_end_to_end() {
+        let server = Arc::new(Server::new());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (recorded, posted) = channel::unbounded();
+        let (stop, stop_receiver) = channel::bounded(1);
+        let listening = {
+            let server = server.clone();
+            async_std::task::spawn(async move {
+                let dispatch = Arc::new(RecordingDispatch::new(recorded));
+                server
+                    .listen_with(vec![listener.into()], dispatch, stop_receiver)
+                    .await
+            })
+        };
+        let mut client = TcpStream::connect(addr).await.unwrap();
+
+        // Blank lines are skipped, as is a line that isn't a response.
+        client
+            .write_all(b"{\"settings\": {\"channelId\": \"7\"}}\n{\"embed\": {\"title\": \"Title\"}}\n\n{\"bogus\": 1}\n{\"ping\": {}}\n")
+            .await
+            .unwrap();
+        let embed = EmbedContent {
+            title: "Title".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Posted::Embed(ChannelId(7), embed),
+            posted.recv().await.unwrap()
+        );
+
+        // Replies and commands come back as JSON lines too.
+        let mut reader = BufReader::new(client.clone());
+        let mut replies = vec![];
+        for _ in 0..2 {
+            let line = read_line(&mut reader, 1024).await.unwrap().unwrap();
+            replies.push(Codec::JsonLines.decode::<messages::Request>(&line).unwrap());
+        }
+        // The embed has no correlation key, so no MessageSent comes back for it.
+        assert!(replies[0].has_settings_applied());
+        assert!(replies[1].has_pong());
+        server
+            .send_command(ChannelId(7), UserId(1), "status".to_string())
+            .await;
+        let line = read_line(&mut reader, 1024).await.unwrap().unwrap();
+        let request: messages::Request = Codec::JsonLines.decode(&line).unwrap();
+        assert_eq!("status", request.command());
+        assert_eq!(1, request.user);
+
+        drop(reader);
+        drop(client);
+        stop.send(()).await.unwrap();
+        listening.await;
+        assert!(server.wait_for_clients(Duration::from_secs(5)).await);
+    }
+
+    fn edit_response(key: &str, title: &str) -> messages::Response {
+        let mut response = messages::Response::new();
+        response.set_edit_embed(messages::EditEmbed {
+            key: key.to_string(),
+            embed: MessageField::some(EmbedContent {
+                title: title.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        response
+    }
+
+    #[test]
+    fn test_debouncer() {
+        let start = Instant::now();
+        let window = Duration::from_secs(2);
+        let mut held = Debouncer::default();
+
+        // Off, everything is handled right away.
+        let edit = edit_response("progress", "10%");
+        assert_eq!(1, held.offer(edit, 10, Duration::ZERO, start).len());
+
+        assert!(held
+            .offer(edit_response("progress", "10%"), 10, window, start)
+            .is_empty());
+        let later = start + Duration::from_secs(1);
+        assert!(held
+            .offer(edit_response("progress", "20%"), 10, window, later)
+            .is_empty());
+        assert!(held
+            .offer(edit_response("other", "50%"), 10, window, later)
+            .is_empty());
+
+        // The burst is due a window after its first edit, and only the latest edit is sent.
+        assert_eq!(Some(start + window), held.next_deadline());
+        assert!(held.take_due(later).is_empty());
+        let due = held.take_due(start + window);
+        assert_eq!(vec![(edit_response("progress", "20%"), 10)], due);
+
+        // Any other response comes after the edits held before it.
+        let mut ping = messages::Response::new();
+        ping.set_ping(messages::Ping::new());
+        let ready = held.offer(ping.clone(), 2, window, later);
+        assert_eq!(vec![(edit_response("other", "50%"), 10), (ping, 2)], ready);
+
+        // Deleting the message drops the edits held for it, and only for it: the same key on
+        // another route is another message.
+        assert!(held
+            .offer(edit_response("other", "60%"), 10, window, later)
+            .is_empty());
+        let mut routed = edit_response("other", "70%");
+        routed.route = "alerts".to_string();
+        assert!(held.offer(routed.clone(), 10, window, later).is_empty());
+        let mut delete = messages::Response::new();
+        delete.set_delete_message(messages::DeleteMessage {
+            key: "other".to_string(),
+            ..Default::default()
+        });
+        let ready = held.offer(delete.clone(), 8, window, later);
+        assert_eq!(vec![(routed, 10), (delete, 8)], ready);
+        assert!(held.take_all().is_empty());
+    }
+
+    #[test]
+    fn test_normalize_command() {
+        assert_eq!("status", normalize_command("  status \n", 100));
+        assert_eq!("print start", normalize_command("print\r\n\tstart", 100));
+        // An escape on its own can't recolour or rewrite the client's terminal.
+        assert_eq!(
+            "red [31malert",
+            normalize_command("red\u{1b}[31malert", 100)
+        );
+        // Legitimate text passes through untouched.
+        for text in ["Drucker läuft 🖨️", "👩‍🔧 done", "印刷 完了", "/print  status"]
+        {
+            assert_eq!(text, normalize_command(text, 100));
+        }
+        // Cut off on a character boundary.
+        assert_eq!("ab🖨", normalize_command("ab🖨️c", 3));
+        assert_eq!("", normalize_command("\n\t ", 100));
+        // Control characters at either end don't leave a space behind.
+        assert_eq!("status", normalize_command("\u{1b}status\u{7}", 100));
+        assert_eq!("ab", normalize_command("ab\ncd", 3));
+    }
+
+    #[async_std::test]
+    async fn test_shutdown_finishes_response_in_progress() {
+        let server = Arc::new(Server::new());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (recorded, posted) = channel::unbounded();
+        let (stop, stop_receiver) = channel::bounded(1);
+        let listening = {
+            let server = server.clone();
+            async_std::task::spawn(async move {
+                let dispatch = Arc::new(RecordingDispatch {
+                    delay: Duration::from_millis(200),
+                    ..RecordingDispatch::new(recorded)
+                });
+                server
+                    .listen_with(vec![listener.into()], dispatch, stop_receiver)
+                    .await
+            })
+        };
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut settings = messages::Response::new();
+        settings.set_settings(messages::Settings {
+            channel_id: 7,
+            ..Default::default()
+        });
+        let mut embed = messages::Response::new();
+        embed.set_embed(EmbedContent::new());
+        embed.correlation_key = "slow".to_string();
+        let mut frames = raw_frame(&settings);
+        frames.extend(raw_frame(&embed));
+        client.write_all(&frames).await.unwrap();
+        posted.recv().await.unwrap();
+        let connection = server.clients.lock().await[0].clone();
+
+        server.shutdown().await;
+        let mut received = vec![];
+        let mut prefix = [0u8; 4];
+        while client.read_exact(&mut prefix).await.is_ok() {
+            let mut data = vec![0u8; u32::from_le_bytes(prefix) as usize];
+            client.read_exact(&mut data).await.unwrap();
+            received.push(messages::Request::parse_from_bytes(&data).unwrap());
+        }
+        assert!(received[0].has_settings_applied());
+        assert!(received[1].has_disconnect());
+        assert_eq!("slow", received[2].message_sent().correlation_key);
+        assert_eq!(3, received.len());
+        assert!(!connection.cancel.is_cancelled());
+
+        stop.send(()).await.unwrap();
+        listening.await;
+        assert!(server.wait_for_clients(Duration::from_secs(5)).await);
+    }
+
+    #[async_std::test]
+    async fn test_uploads_respect_allowed_users() {
+        let server = Server::new();
+        let (settings, mut client) = connected_client().await;
+        settings
+            .apply_settings(messages::Settings {
+                channel_id: 7,
+                allowed_users: vec![1],
+                ..Default::default()
+            })
+            .await;
+        server.clients.lock().await.push(Arc::new(settings));
+
+        server
+            .send_file(ChannelId(7), UserId(2), "a.txt".to_string(), vec![1], None)
+            .await;
+        server
+            .send_message_edit(ChannelId(7), UserId(2), MessageId(5), "edit".to_string())
+            .await;
+        server
+            .send_reaction(
+                ChannelId(7),
+                UserId(2),
+                MessageId(5),
+                "👍".to_string(),
+                true,
+            )
+            .await;
+        server
+            .send_file(ChannelId(7), UserId(1), "b.txt".to_string(), vec![1], None)
+            .await;
+        let request = recv_request(&mut client).await;
+        assert_eq!(1, request.user);
+        assert_eq!("b.txt", request.file().filename);
+    }
+
+    #[test]
+    fn test_may_message() {
+        assert!(may_message(false, &[UserId(1)], UserId(1)));
+        assert!(!may_message(false, &[UserId(1)], UserId(2)));
+        assert!(!may_message(false, &[], UserId(1)));
+        assert!(!may_message(true, &[UserId(1)], UserId(1)));
+    }
+
+    #[async_std::test]
+    async fn test_delete_only_reported_messages() {
+        let server = Server::new();
+        let (settings, _client) = connected_client().await;
+        let settings = Arc::new(settings);
+        settings
+            .apply_settings(messages::Settings {
+                channel_id: 7,
+                ..Default::default()
+            })
+            .await;
+        let (recorded, posted) = channel::unbounded();
+        let dispatch = RecordingDispatch::new(recorded);
+
+        let mut delete = messages::Response::new();
+        delete.set_delete_message(messages::DeleteMessage {
+            message_id: 1,
+            ..Default::default()
+        });
+        let handled = server.handle_task(settings.clone(), delete.clone(), 0, &dispatch);
+        assert!(handled.await.is_ok());
+        assert!(posted.is_empty());
+
+        // Without a correlation key, the message isn't reported to the client.
+        let mut embed = messages::Response::new();
+        embed.set_embed(EmbedContent::new());
+        let handled = server.handle_task(settings.clone(), embed, 0, &dispatch);
+        assert!(handled.await.is_ok());
+        assert!(matches!(posted.recv().await, Ok(Posted::Embed(..))));
+        let handled = server.handle_task(settings.clone(), delete.clone(), 0, &dispatch);
+        assert!(handled.await.is_ok());
+        assert!(posted.is_empty());
+
+        let mut embed = messages::Response::new();
+        embed.set_embed(EmbedContent::new());
+        embed.correlation_key = "status".to_string();
+        let handled = server.handle_task(settings.clone(), embed, 0, &dispatch);
+        assert!(handled.await.is_ok());
+        assert!(matches!(posted.recv().await, Ok(Posted::Embed(..))));
+        let handled = server.handle_task(settings.clone(), delete, 0, &dispatch);
+        assert!(handled.await.is_ok());
+        assert_eq!(
+            Posted::Deleted(ChannelId(7), MessageId(1)),
+            posted.recv().await.unwrap()
+        );
+    }
+
+    #[async_std::test]
+    async fn test_connection_ends_after_failed_response() {
+        let server = Server::new();
+        let (settings, mut client) = connected_client().await;
+        let settings = Arc::new(settings);
+        settings
+            .apply_settings(messages::Settings {
+                channel_id: 7,
+                ..Default::default()
+            })
+            .await;
+        let (recorded, _posted) = channel::unbounded();
+        let dispatch = RecordingDispatch::new(recorded);
+        // Nothing can be queued for the client any more, so answering a ping fails.
+        settings.outbound.close();
+
+        // More frames than are passed on from the reader at once, so the reader is still waiting
+        // to pass one on when handling the first fails.
+        let mut ping = messages::Response::new();
+        ping.set_ping(messages::Ping::new());
+        let frames: Vec<u8> = (0..5).flat_map(|_| raw_frame(&ping)).collect();
+        client.write_all(&frames).await.unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let reader = Peekable::new(settings.stream.read().await.clone());
+        let session = server.connection_loop(reader, settings.clone(), &dispatch);
+        assert!(async_std::future::timeout(Duration::from_secs(5), session)
+            .await
+            .is_ok());
+    }
+
+    #[async_std::test]
+    async fn test_failed_post_keeps_connection() {
+        let server = Server::new();
+        let (settings, mut client) = connected_client().await;
+        let settings = Arc::new(settings);
+        settings
+            .apply_settings(messages::Settings {
+                channel_id: 7,
+                ..Default::default()
+            })
+            .await;
+        let (recorded, posted) = channel::unbounded();
+        let dispatch = RecordingDispatch {
+            failing: true,
+            ..RecordingDispatch::new(recorded)
+        };
+
+        let mut embed = messages::Response::new();
+        embed.set_embed(EmbedContent::new());
+        embed.correlation_key = "status".to_string();
+        let handled = server.handle_task(settings.clone(), embed, 0, &dispatch);
+        assert!(handled.await.is_ok());
+        assert!(matches!(posted.recv().await, Ok(Posted::Embed(..))));
+
+        // Nothing was posted, so nothing is reported, but the client is still answered.
+        let mut ping = messages::Response::new();
+        ping.set_ping(messages::Ping::new());
+        let handled = server.handle_task(settings.clone(), ping, 0, &dispatch);
+        assert!(handled.await.is_ok());
+        assert!(recv_request(&mut client).await.has_pong());
+    }
+
+    #[async_std::test]
+    async fn test_direct_message_to_user_not_allowed_is_dropped() {
+        let server = Server::new();
+        let (settings, _client) = connected_client().await;
+        let settings = Arc::new(settings);
+        settings
+            .apply_settings(messages::Settings {
+                channel_id: 7,
+                allowed_users: vec![1],
+                ..Default::default()
+            })
+            .await;
+        let (recorded, posted) = channel::unbounded();
+        let dispatch = RecordingDispatch::new(recorded);
+
+        let mut embed = messages::Response::new();
+        embed.set_embed(EmbedContent::new());
+        embed.dm_user = 2;
+        let handled = server.handle_task(settings.clone(), embed.clone(), 0, &dispatch);
+        assert!(handled.await.is_ok());
+        assert!(posted.is_empty());
+
+        embed.dm_user = 1;
+        let handled = server.handle_task(settings.clone(), embed, 0, &dispatch);
+        assert!(handled.await.is_ok());
+        assert!(matches!(
+            posted.recv().await,
+            Ok(Posted::Embed(ChannelId(1), _))
+        ));
     }
 }
@@ -0,0 +1,548 @@
+use async_std::future::timeout;
+use async_std::io::{self, Read, ReadExt, Write};
+use async_std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use futures::stream::{self, BoxStream, StreamExt};
+use futures_rustls::client;
+use futures_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use futures_rustls::rustls::{Certificate, ClientConfig, PrivateKey, ServerConfig, ServerName};
+use futures_rustls::server::TlsStream;
+use futures_rustls::{TlsAcceptor, TlsConnector};
+use rustls_pemfile::Item;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_TLS_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+/// Path of a Unix domain socket to accept local clients on, from `UNIX_SOCKET`.
+pub(crate) fn unix_socket_path() -> Option<PathBuf> {
+    env::var("UNIX_SOCKET")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Whether clients are accepted over TCP. On unless `DISABLE_TCP_LISTENER` is set, which only
+/// makes sense along with `UNIX_SOCKET`.
+pub(crate) fn tcp_listener() -> bool {
+    env::var("DISABLE_TCP_LISTENER").is_err()
+}
+
+/// Binds a Unix domain socket at `path`, replacing a socket left behind by an earlier run.
+pub(crate) async fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    UnixListener::bind(path).await
+}
+
+/// The TLS acceptor for the certificate chain in `TLS_CERT` and the private key in `TLS_KEY`, both
+/// PEM files. Without them TCP clients connect in plaintext.
+pub(crate) fn tls_acceptor() -> Result<Option<TlsAcceptor>, String> {
+    match tls_paths()? {
+        Some((cert, key)) => load_tls(&cert, &key).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The certificate TCP clients are served, the first in `TLS_CERT`, when TLS is on.
+pub(crate) fn served_certificate() -> Result<Option<Certificate>, String> {
+    match tls_paths()? {
+        Some((cert, _)) => Ok(read_certs(&cert)?.into_iter().next()),
+        None => Ok(None),
+    }
+}
+
+fn tls_paths() -> Result<Option<(PathBuf, PathBuf)>, String> {
+    let cert = env::var("TLS_CERT").ok().filter(|path| !path.is_empty());
+    let key = env::var("TLS_KEY").ok().filter(|path| !path.is_empty());
+    match (cert, key) {
+        (Some(cert), Some(key)) => Ok(Some((cert.into(), key.into()))),
+        (None, None) => Ok(None),
+        _ => Err("TLS_CERT and TLS_KEY must be set together".to_string()),
+    }
+}
+
+pub(crate) fn read_certs(cert: &Path) -> Result<Vec<Certificate>, String> {
+    let pem = fs::read(cert).map_err(|e| format!("Failed to read {}: {e}", cert.display()))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .map_err(|e| format!("Invalid certificate in {}: {e}", cert.display()))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate in {}", cert.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+pub(crate) fn load_tls(cert: &Path, key: &Path) -> Result<TlsAcceptor, String> {
+    let certs = read_certs(cert)?;
+
+    let pem = fs::read(key).map_err(|e| format!("Failed to read {}: {e}", key.display()))?;
+    let mut reader = pem.as_slice();
+    let private_key = loop {
+        match rustls_pemfile::read_one(&mut reader) {
+            Ok(Some(Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key))) => break key,
+            Ok(Some(_)) => continue,
+            Ok(None) => return Err(format!("No private key in {}", key.display())),
+            Err(e) => return Err(format!("Invalid private key in {}: {e}", key.display())),
+        }
+    };
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, PrivateKey(private_key))
+        .map_err(|e| format!("Unusable certificate or key: {e}"))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// How long a client may take to complete the TLS handshake, read from
+/// `TLS_HANDSHAKE_TIMEOUT_SECS`.
+pub(crate) fn tls_handshake_timeout() -> Duration {
+    let secs = env::var("TLS_HANDSHAKE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_TLS_HANDSHAKE_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+/// Completes the TLS handshake on an accepted TCP connection within `handshake_timeout`. The peer
+/// is taken from the TCP stream first, so a failed handshake can still be attributed.
+pub(crate) async fn accept_tls(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+    handshake_timeout: Duration,
+) -> io::Result<ClientStream> {
+    let peer = Peer::Ip(stream.peer_addr()?);
+    match timeout(handshake_timeout, acceptor.accept(stream)).await {
+        Ok(tls) => Ok(ClientStream::Tls(Arc::new(Mutex::new(tls?)), peer)),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no TLS handshake within {handshake_timeout:?}"),
+        )),
+    }
+}
+
+/// Connects over TLS to a server that must present `served`. The healthcheck runs next to the
+/// shim it checks, so rather than a CA and a name to check the certificate against, it knows the
+/// exact certificate to expect.
+pub(crate) async fn connect_tls(
+    addr: SocketAddr,
+    served: Certificate,
+) -> io::Result<client::TlsStream<TcpStream>> {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificate(served)))
+        .with_no_client_auth();
+    let tcp = TcpStream::connect(addr).await?;
+    TlsConnector::from(Arc::new(config))
+        .connect(ServerName::IpAddress(addr.ip()), tcp)
+        .await
+}
+
+/// Accepts only this certificate. The handshake signature is still checked against it, so the
+/// server must hold its key.
+struct PinnedCertificate(Certificate);
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, futures_rustls::rustls::Error> {
+        if *end_entity == self.0 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(futures_rustls::rustls::Error::General(
+                "not the certificate the shim serves".to_string(),
+            ))
+        }
+    }
+}
+
+/// The other end of a client connection.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Peer {
+    Ip(SocketAddr),
+    /// Unix socket clients have no address of their own, so they go by the socket's path.
+    Unix(String),
+}
+
+impl Peer {
+    /// What the client's totals are kept under: its IP, or the socket for Unix socket clients.
+    pub(crate) fn ip(&self) -> String {
+        match self {
+            Peer::Ip(addr) => addr.ip().to_string(),
+            Peer::Unix(_) => self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Ip(addr) => write!(f, "{addr}"),
+            Peer::Unix(path) => write!(f, "unix:{path}"),
+        }
+    }
+}
+
+/// A client connection, over TCP, TLS or a Unix domain socket.
+#[derive(Clone)]
+pub(crate) enum ClientStream {
+    Tcp(TcpStream),
+    /// Reads and writes each lock the session only while polled, so a connection can still be
+    /// read from and written to at the same time.
+    Tls(Arc<Mutex<TlsStream<TcpStream>>>, Peer),
+    Unix(UnixStream),
+}
+
+impl ClientStream {
+    /// The other end of the connection. A TCP connection that was reset may no longer have one.
+    pub(crate) fn peer(&self) -> io::Result<Peer> {
+        match self {
+            ClientStream::Tcp(stream) => stream.peer_addr().map(Peer::Ip),
+            ClientStream::Tls(_, peer) => Ok(peer.clone()),
+            ClientStream::Unix(stream) => {
+                let path = stream
+                    .local_addr()
+                    .ok()
+                    .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()));
+                Ok(Peer::Unix(path.unwrap_or_else(|| "unnamed".to_string())))
+            }
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.shutdown(how),
+            ClientStream::Tls(stream, _) => lock(stream).get_ref().0.shutdown(how),
+            ClientStream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl From<TcpStream> for ClientStream {
+    fn from(stream: TcpStream) -> Self {
+        ClientStream::Tcp(stream)
+    }
+}
+
+impl From<UnixStream> for ClientStream {
+    fn from(stream: UnixStream) -> Self {
+        ClientStream::Unix(stream)
+    }
+}
+
+impl Read for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Tls(stream, _) => Pin::new(&mut *lock(stream)).poll_read(cx, buf),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Tls(stream, _) => Pin::new(&mut *lock(stream)).poll_write(cx, buf),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Tls(stream, _) => Pin::new(&mut *lock(stream)).poll_flush(cx),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_close(cx),
+            ClientStream::Tls(stream, _) => Pin::new(&mut *lock(stream)).poll_close(cx),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+/// Locks a TLS session, which a panic part way through a poll leaves no worse off than any other
+/// failed read or write.
+fn lock(stream: &Mutex<TlsStream<TcpStream>>) -> std::sync::MutexGuard<'_, TlsStream<TcpStream>> {
+    stream
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Where clients connect.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// The connections the listener accepts. The listener is closed once the stream is dropped.
+    pub(crate) fn into_incoming(self) -> BoxStream<'static, io::Result<ClientStream>> {
+        match self {
+            Listener::Tcp(listener) => stream::unfold(listener, |listener| async move {
+                let accepted = listener.accept().await;
+                Some((accepted.map(|(stream, _)| stream.into()), listener))
+            })
+            .boxed(),
+            Listener::Unix(listener) => stream::unfold(listener, |listener| async move {
+                let accepted = listener.accept().await;
+                Some((accepted.map(|(stream, _)| stream.into()), listener))
+            })
+            .boxed(),
+        }
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        let addr = match self {
+            Listener::Tcp(listener) => listener.local_addr().map(|addr| addr.to_string()),
+            Listener::Unix(listener) => listener.local_addr().map(|addr| {
+                format!(
+                    "unix:{}",
+                    addr.as_pathname().unwrap_or(Path::new("")).display()
+                )
+            }),
+        };
+        addr.unwrap_or_else(|error| format!("unknown address ({error})"))
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+/// A reader that can look at what arrives before consuming it.
+pub(crate) struct Peekable<R> {
+    inner: R,
+    peeked: Vec<u8>,
+}
+
+impl<R: Read + Unpin> Peekable<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Peekable {
+            inner,
+            peeked: vec![],
+        }
+    }
+
+    /// Reads until `n` bytes are buffered or the stream ends, returning what is buffered without
+    /// consuming it. Safe to cancel, nothing read is lost.
+    pub(crate) async fn peek(&mut self, n: usize) -> io::Result<&[u8]> {
+        while self.peeked.len() < n {
+            let mut buf = vec![0u8; n - self.peeked.len()];
+            let read = self.inner.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            self.peeked.extend_from_slice(&buf[..read]);
+        }
+        Ok(&self.peeked)
+    }
+}
+
+impl<R: Read + Unpin> Read for Peekable<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.peeked.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let n = buf.len().min(this.peeked.len());
+        buf[..n].copy_from_slice(&this.peeked[..n]);
+        this.peeked.drain(..n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stream::{
+        accept_tls, bind_unix, connect_tls, load_tls, read_certs, ClientStream, Listener, Peekable,
+        Peer,
+    };
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::os::unix::net::UnixStream;
+    use futures::future::join;
+    use futures::StreamExt;
+    use futures_rustls::rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+    use futures_rustls::TlsConnector;
+    use std::env;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[async_std::test]
+    async fn test_peekable() {
+        let mut reader = Peekable::new(&b"{\"ping\": {}}"[..]);
+        assert_eq!(b"{\"pi", reader.peek(4).await.unwrap());
+        let mut all = String::new();
+        reader.read_to_string(&mut all).await.unwrap();
+        assert_eq!("{\"ping\": {}}", all);
+
+        let mut short = Peekable::new(&b"{}"[..]);
+        assert_eq!(b"{}", short.peek(4).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_unix_socket_client() {
+        let path = env::temp_dir().join(format!("discordshim-{}.sock", uuid::Uuid::new_v4()));
+        let listener = bind_unix(&path).await.unwrap();
+        // A socket left behind is replaced.
+        drop(listener);
+        let listener = Listener::from(bind_unix(&path).await.unwrap());
+        assert_eq!(format!("unix:{}", path.display()), listener.describe());
+        let mut incoming = listener.into_incoming();
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let mut accepted: ClientStream = incoming.next().await.unwrap().unwrap();
+        assert_eq!(
+            Peer::Unix(path.display().to_string()),
+            accepted.peer().unwrap()
+        );
+        assert_eq!(
+            format!("unix:{}", path.display()),
+            accepted.peer().unwrap().ip()
+        );
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"ping", &buf);
+
+        drop(incoming);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_tls() {
+        let cert = Path::new("test_data/tls/cert.pem");
+        let key = Path::new("test_data/tls/key.pem");
+        assert!(load_tls(cert, key).is_ok());
+        assert!(load_tls(key, key)
+            .err()
+            .unwrap()
+            .starts_with("No certificate in"));
+        assert!(load_tls(cert, cert)
+            .err()
+            .unwrap()
+            .starts_with("No private key in"));
+        assert!(load_tls(Path::new("test_data/tls/missing.pem"), key).is_err());
+    }
+
+    #[async_std::test]
+    async fn test_tls_client() {
+        let acceptor = load_tls(
+            Path::new("test_data/tls/cert.pem"),
+            Path::new("test_data/tls/key.pem"),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut roots = RootCertStore::empty();
+        let ca = std::fs::read("test_data/tls/ca.pem").unwrap();
+        for cert in rustls_pemfile::certs(&mut ca.as_slice()).unwrap() {
+            roots.add(&Certificate(cert)).unwrap();
+        }
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        let connect = async {
+            let tcp = TcpStream::connect(addr).await.unwrap();
+            let local = tcp.local_addr().unwrap();
+            let server_name = ServerName::try_from("localhost").unwrap();
+            (connector.connect(server_name, tcp).await.unwrap(), local)
+        };
+        let accept = async {
+            let (tcp, _) = listener.accept().await.unwrap();
+            accept_tls(&acceptor, tcp, Duration::from_secs(5))
+                .await
+                .unwrap()
+        };
+        let ((mut client, local), accepted) = join(connect, accept).await;
+        assert_eq!(Peer::Ip(local), accepted.peer().unwrap());
+
+        // Reading and writing the session at once, as a connection's reader and writer do.
+        let mut reader = accepted.clone();
+        let mut writer = accepted;
+        client.write_all(b"ping").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"ping", &buf);
+        writer.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"pong", &buf);
+
+        // A client that never starts the handshake is given up on.
+        let _silent = TcpStream::connect(addr).await.unwrap();
+        let (tcp, _) = listener.accept().await.unwrap();
+        let error = accept_tls(&acceptor, tcp, Duration::from_millis(100))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(std::io::ErrorKind::TimedOut, error.kind());
+    }
+
+    #[async_std::test]
+    async fn test_connect_tls_pins_certificate() {
+        let acceptor = load_tls(
+            Path::new("test_data/tls/cert.pem"),
+            Path::new("test_data/tls/key.pem"),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = read_certs(Path::new("test_data/tls/cert.pem")).unwrap();
+        let other = read_certs(Path::new("test_data/tls/ca.pem")).unwrap();
+
+        for (expected, trusted) in [(served[0].clone(), true), (other[0].clone(), false)] {
+            let accept = async {
+                let (tcp, _) = listener.accept().await.unwrap();
+                accept_tls(&acceptor, tcp, Duration::from_secs(5)).await
+            };
+            let (connected, _) = join(connect_tls(addr, expected), accept).await;
+            assert_eq!(trusted, connected.is_ok());
+        }
+    }
+}
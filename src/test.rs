@@ -1,13 +1,16 @@
 #[cfg(test)]
 mod tests {
     use crate::embedbuilder::{
-        build_embeds, split_file, DISCORD_MAX_AUTHOR, DISCORD_MAX_DESCRIPTION, DISCORD_MAX_FIELDS,
-        DISCORD_MAX_TITLE, DISCORD_MAX_VALUE, ONE_MEGABYTE,
+        build_embeds, code_block, flatten_embed, inline_file, inline_file_parts, parse_color,
+        split_code_block, split_content, split_file, Markers, DISCORD_MAX_AUTHOR,
+        DISCORD_MAX_DESCRIPTION, DISCORD_MAX_EMBED_TOTAL, DISCORD_MAX_FIELDS, DISCORD_MAX_FOOTER,
+        DISCORD_MAX_TITLE, DISCORD_MAX_VALUE, MAX_EMBEDS_PER_RESPONSE, ONE_MEGABYTE,
     };
     use crate::messages;
     use crate::messages::{EmbedContent, Response, Settings, TextField};
     use byteorder::{ByteOrder, LittleEndian};
-    use protobuf::{Message, MessageField};
+    use protobuf::Message;
+    use serenity::model::channel::AttachmentType;
     use std::fs::File;
     use std::io::{Read, Write};
     use std::net::{Shutdown, TcpStream};
@@ -32,7 +35,7 @@ mod tests {
         let mut buf = vec![0u8; length as usize];
         stream.read_exact(&mut buf).unwrap();
 
-        return messages::Request::parse_from_bytes(buf.as_slice()).unwrap();
+        messages::Request::parse_from_bytes(buf.as_slice()).unwrap()
     }
 
     fn get_snapshot() -> messages::ProtoFile {
@@ -107,7 +110,7 @@ mod tests {
             ..Default::default()
         };
         let snapshot = get_snapshot();
-        discord_embed.snapshot = MessageField::some(snapshot);
+        discord_embed.snapshots = vec![snapshot];
         for i in 0..50 {
             let field = TextField {
                 title: i.to_string(),
@@ -161,6 +164,7 @@ mod tests {
                     assert_ne!(request.user, 0);
                     seen_command = true;
                 }
+                Some(_) => {}
             }
             if seen_file && seen_command {
                 break;
@@ -170,7 +174,7 @@ mod tests {
 
     #[test]
     fn test_split_file_small_file() {
-        let attachments = split_file("filename".to_string(), "filedata".as_bytes());
+        let attachments = split_file("filename".to_string(), "filedata".as_bytes(), ONE_MEGABYTE);
         assert_eq!(attachments.len(), 1);
         assert_eq!(attachments[0].0, "filename");
     }
@@ -180,7 +184,7 @@ mod tests {
         let mut file = File::open("/dev/urandom").unwrap();
         let mut filedata = vec![0u8; 7 * ONE_MEGABYTE];
         file.read_exact(&mut filedata).unwrap();
-        let attachments = split_file("filename".to_string(), &filedata);
+        let attachments = split_file("filename".to_string(), &filedata, ONE_MEGABYTE);
         assert_eq!(attachments.len(), 8);
         assert_eq!(attachments[0].0, "filename.zip.000");
         assert_eq!(attachments[1].0, "filename.zip.001");
@@ -205,9 +209,9 @@ mod tests {
             description: str::repeat("b", DISCORD_MAX_DESCRIPTION),
             author: str::repeat("c", DISCORD_MAX_AUTHOR),
             color: 0,
-            snapshot: Default::default(),
+            snapshots: Default::default(),
             textfield: textfields,
-            special_fields: Default::default(),
+            ..Default::default()
         };
 
         let embeds = build_embeds(ec);
@@ -230,9 +234,9 @@ mod tests {
             description: str::repeat("b", DISCORD_MAX_DESCRIPTION),
             author: str::repeat("c", DISCORD_MAX_AUTHOR),
             color: 0,
-            snapshot: Default::default(),
+            snapshots: Default::default(),
             textfield: textfields,
-            special_fields: Default::default(),
+            ..Default::default()
         };
 
         let embeds = build_embeds(ec.clone());
@@ -249,4 +253,404 @@ mod tests {
 
         assert_eq!(num_fields, DISCORD_MAX_FIELDS + 1);
     }
+
+    #[test]
+    fn test_build_embeds_truncated_marker() {
+        let ec = EmbedContent {
+            title: str::repeat("a", DISCORD_MAX_TITLE + 1),
+            description: str::repeat("b", DISCORD_MAX_DESCRIPTION),
+            ..Default::default()
+        };
+
+        let embeds = build_embeds(ec);
+        let marker = Markers::default().truncated;
+        assert_eq!(DISCORD_MAX_TITLE, embeds[0].title.len());
+        assert!(embeds[0].title.ends_with(&marker));
+        assert!(!embeds[0].description.ends_with(&marker));
+    }
+
+    #[test]
+    fn test_split_content_fits() {
+        let markers = Markers::default();
+        let chunks = split_content("short content", 20, &markers);
+        assert_eq!(vec!["short content".to_string()], chunks);
+    }
+
+    #[test]
+    fn test_split_content_continued_marker() {
+        let markers = Markers {
+            continued: ">".to_string(),
+            truncated: "".to_string(),
+        };
+        let chunks = split_content("aaaaabbbbcccc", 5, &markers);
+        assert_eq!(vec!["aaaaa", ">bbbb", ">cccc"], chunks);
+        for chunk in chunks {
+            assert!(chunk.len() <= 5);
+        }
+    }
+
+    #[test]
+    fn test_split_content_multibyte() {
+        let markers = Markers::default();
+        let content = str::repeat("\u{1f600}", 10);
+        let chunks = split_content(&content, 30, &markers);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks[1..] {
+            assert!(chunk.starts_with(&markers.continued));
+            assert!(chunk.len() <= 30);
+        }
+    }
+
+    #[test]
+    fn test_build_embeds_author_at_limit() {
+        let ec = EmbedContent {
+            author: str::repeat("c", DISCORD_MAX_AUTHOR),
+            ..Default::default()
+        };
+
+        let embeds = build_embeds(ec.clone());
+        assert_eq!(ec.author, embeds[0].author);
+    }
+
+    #[test]
+    fn test_build_embeds_author_over_limit() {
+        // Multi-byte characters that don't line up with the limit must not be split.
+        let ec = EmbedContent {
+            author: str::repeat("\u{00e9}", DISCORD_MAX_AUTHOR),
+            ..Default::default()
+        };
+
+        let embeds = build_embeds(ec);
+        let author = &embeds[0].author;
+        assert!(author.len() <= DISCORD_MAX_AUTHOR);
+        assert!(author.ends_with(&Markers::default().truncated));
+        assert!(author.starts_with('\u{00e9}'));
+    }
+
+    fn text_file(text: &str, inline: bool) -> messages::ProtoFile {
+        messages::ProtoFile {
+            data: text.as_bytes().to_vec(),
+            filename: "octoprint.log".to_string(),
+            inline,
+            language: "log".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_code_block() {
+        assert_eq!(
+            "```gcode\nG28\nG1 X10\n```",
+            code_block("G28\nG1 X10", "gcode")
+        );
+        assert_eq!("```\nplain\n```", code_block("plain", ""));
+    }
+
+    #[test]
+    fn test_code_block_escapes_fences() {
+        let block = code_block("a\n```\nb", "");
+        assert_eq!(2, block.matches("```").count());
+    }
+
+    #[test]
+    fn test_inline_file_small_text() {
+        let file = text_file("Printer connected", true);
+        assert_eq!(
+            Some("```log\nPrinter connected\n```".to_string()),
+            inline_file(&file, 1024)
+        );
+    }
+
+    #[test]
+    fn test_inline_file_not_requested() {
+        let file = text_file("Printer connected", false);
+        assert_eq!(None, inline_file(&file, 1024));
+    }
+
+    #[test]
+    fn test_inline_file_over_threshold() {
+        let file = text_file(&str::repeat("a", 1025), true);
+        assert_eq!(None, inline_file(&file, 1024));
+
+        // Above Discord's content limit once fenced, even when the threshold allows it.
+        let file = text_file(&str::repeat("a", 1995), true);
+        assert_eq!(None, inline_file(&file, 4096));
+    }
+
+    #[test]
+    fn test_inline_file_binary() {
+        let mut file = text_file("", true);
+        file.data = vec![0xff, 0xfe, 0x00];
+        assert_eq!(None, inline_file(&file, 1024));
+    }
+
+    #[test]
+    fn test_flatten_embed() {
+        let e = EmbedContent {
+            title: "Print done".to_string(),
+            description: "benchy.gcode".to_string(),
+            textfield: vec![TextField {
+                title: "Time".to_string(),
+                text: "1h 2m".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            "**Print done**\nbenchy.gcode\n**Time**: 1h 2m",
+            flatten_embed(&e)
+        );
+        assert_eq!("", flatten_embed(&EmbedContent::default()));
+    }
+
+    #[test]
+    fn test_split_code_block_preserves_fences() {
+        let text = (0..100)
+            .map(|i| format!("line {i}: printer heating"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let chunks = split_code_block(&text, "log", 200);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.len() <= 200);
+            assert!(chunk.starts_with("```log\n"));
+            assert!(chunk.ends_with("\n```"));
+        }
+
+        // Splits happen at line breaks, so every line survives whole.
+        let rejoined = chunks
+            .iter()
+            .map(|chunk| &chunk["```log\n".len()..chunk.len() - "\n```".len()])
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(text, rejoined);
+    }
+
+    #[test]
+    fn test_split_code_block_long_line() {
+        let text = str::repeat("a", 50);
+        let chunks = split_code_block(&text, "", 30);
+        assert_eq!(3, chunks.len());
+        assert_eq!(format!("```\n{}\n```", str::repeat("a", 22)), chunks[0]);
+        assert_eq!("```\naaaaaa\n```", chunks[2]);
+    }
+
+    #[test]
+    fn test_inline_file_parts_splits_long_text() {
+        let file = text_file(&str::repeat("a\n", 1500), true);
+        assert_eq!(None, inline_file(&file, 4096));
+        let parts = inline_file_parts(&file, 4096).unwrap();
+        assert_eq!(2, parts.len());
+        assert!(parts.iter().all(|part| part.starts_with("```log\n")));
+    }
+
+    fn field(title: &str, text: &str) -> TextField {
+        TextField {
+            title: title.to_string(),
+            text: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_build_embeds_description_boundary() {
+        let ec = EmbedContent {
+            description: str::repeat("b", DISCORD_MAX_DESCRIPTION),
+            ..Default::default()
+        };
+        assert_eq!(ec.description, build_embeds(ec.clone())[0].description);
+
+        let ec = EmbedContent {
+            description: str::repeat("b", DISCORD_MAX_DESCRIPTION + 1),
+            ..Default::default()
+        };
+        let description = &build_embeds(ec)[0].description;
+        assert_eq!(DISCORD_MAX_DESCRIPTION, description.len());
+        assert!(description.ends_with(&Markers::default().truncated));
+    }
+
+    #[test]
+    fn test_build_embeds_field_boundary() {
+        let at_limit = str::repeat("e", DISCORD_MAX_VALUE);
+        let over_limit = str::repeat("e", DISCORD_MAX_VALUE + 1);
+        let long_title = str::repeat("d", DISCORD_MAX_TITLE + 1);
+        let ec = EmbedContent {
+            textfield: vec![field("a", &at_limit), field(&long_title, &over_limit)],
+            ..Default::default()
+        };
+
+        let fields = &build_embeds(ec)[0].textfield;
+        assert_eq!(at_limit, fields[0].text);
+        assert_eq!(DISCORD_MAX_TITLE, fields[1].title.len());
+        assert_eq!(DISCORD_MAX_VALUE, fields[1].text.len());
+        assert!(fields[1].text.ends_with(&Markers::default().truncated));
+    }
+
+    #[test]
+    fn test_build_embeds_field_count_boundary() {
+        let ec = EmbedContent {
+            textfield: vec![field("a", "b"); DISCORD_MAX_FIELDS],
+            ..Default::default()
+        };
+        assert_eq!(1, build_embeds(ec).len());
+
+        let ec = EmbedContent {
+            textfield: vec![field("a", "b"); DISCORD_MAX_FIELDS + 1],
+            ..Default::default()
+        };
+        let embeds = build_embeds(ec);
+        assert_eq!(2, embeds.len());
+        assert_eq!(1, embeds[1].textfield.len());
+    }
+
+    #[test]
+    fn test_build_embeds_drops_excess_fields() {
+        let ec = EmbedContent {
+            textfield: vec![field("a", "b"); DISCORD_MAX_FIELDS * (MAX_EMBEDS_PER_RESPONSE + 2)],
+            ..Default::default()
+        };
+        let embeds = build_embeds(ec);
+        assert_eq!(MAX_EMBEDS_PER_RESPONSE, embeds.len());
+        assert!(embeds
+            .iter()
+            .all(|embed| embed.textfield.len() == DISCORD_MAX_FIELDS));
+    }
+
+    #[test]
+    fn test_build_embeds_empty_field() {
+        let ec = EmbedContent {
+            textfield: vec![field("", "")],
+            ..Default::default()
+        };
+        let fields = &build_embeds(ec)[0].textfield;
+        assert_eq!("\u{200b}", fields[0].title);
+        assert_eq!("\u{200b}", fields[0].text);
+    }
+
+    #[test]
+    fn test_split_content_prefers_line_breaks() {
+        let markers = Markers {
+            continued: "".to_string(),
+            truncated: "".to_string(),
+        };
+        let chunks = split_content("first line\nsecond line\nthird", 20, &markers);
+        assert_eq!(vec!["first line", "second line\nthird"], chunks);
+
+        // Mentions are separated by spaces, so none of them gets cut in half.
+        let chunks = split_content("<@1234> <@5678> <@9012>", 16, &markers);
+        assert_eq!(vec!["<@1234> <@5678>", "<@9012>"], chunks);
+    }
+
+    #[test]
+    fn test_split_content_reopens_code_fence() {
+        let markers = Markers {
+            continued: "".to_string(),
+            truncated: "".to_string(),
+        };
+        let content = format!(
+            "Log:\n```python\n{}\n```\ndone",
+            (0..20)
+                .map(|i| format!("print({i})"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+        let chunks = split_content(&content, 60, &markers);
+        assert!(chunks.len() > 2);
+        for chunk in &chunks {
+            assert!(chunk.len() <= 60);
+            // Every chunk opens and closes as many fences as it needs.
+            assert_eq!(0, chunk.matches("```").count() % 2, "{chunk}");
+        }
+        for chunk in &chunks[1..chunks.len() - 1] {
+            assert!(chunk.starts_with("```python\n"), "{chunk}");
+        }
+        assert!(chunks.last().unwrap().ends_with("```\ndone"));
+    }
+
+    #[test]
+    fn test_split_file_size_limit_boundary() {
+        let limit = 64 * 1024;
+        let filedata = vec![7u8; limit];
+        let attachments = split_file("filename".to_string(), &filedata, limit);
+        assert_eq!(1, attachments.len());
+        assert_eq!("filename", attachments[0].0);
+
+        // One byte over is zipped, and each part stays within the limit.
+        let mut file = File::open("/dev/urandom").unwrap();
+        let mut filedata = vec![0u8; limit + 1];
+        file.read_exact(&mut filedata).unwrap();
+        let attachments = split_file("filename".to_string(), &filedata, limit);
+        assert_eq!(2, attachments.len());
+        assert_eq!("filename.zip.000", attachments[0].0);
+        for (_, attachment) in &attachments {
+            match attachment {
+                AttachmentType::Bytes { data, .. } => assert!(data.len() <= limit),
+                _ => panic!("Expected bytes"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_embeds_color_hex() {
+        let embed = |color_hex: &str| EmbedContent {
+            color: 0x123456,
+            color_hex: color_hex.to_string(),
+            textfield: vec![field("a", "b"); DISCORD_MAX_FIELDS + 1],
+            ..Default::default()
+        };
+        let embeds = build_embeds(embed("#ff8800"));
+        assert!(embeds.iter().all(|embed| embed.color == 0xff8800));
+        assert_eq!(0x00ff00, build_embeds(embed("0x00ff00"))[0].color);
+        assert_eq!(0x123456, build_embeds(embed(""))[0].color);
+        // An invalid string falls back to the default instead of failing the embed.
+        let embeds = build_embeds(embed("#zzzzzz"));
+        assert_eq!(2, embeds.len());
+        assert!(embeds.iter().all(|embed| embed.color == 0x123456));
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(Some(0xff8800), parse_color("#ff8800"));
+        assert_eq!(Some(0x00ff00), parse_color(" 0x00FF00 "));
+        assert_eq!(Some(0x000001), parse_color("000001"));
+        for invalid in ["-1", "+ff", "#fff", "#ff88001", "-0x00ff", "", "#"] {
+            assert_eq!(None, parse_color(invalid), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_build_embeds_footer_on_last() {
+        let ec = EmbedContent {
+            footer_text: "footer".to_string(),
+            timestamp: "2024-01-31T12:00:00Z".to_string(),
+            textfield: vec![field("a", "b"); DISCORD_MAX_FIELDS + 1],
+            ..Default::default()
+        };
+        let embeds = build_embeds(ec);
+        assert_eq!(2, embeds.len());
+        assert!(embeds[0].footer_text.is_empty());
+        assert!(embeds[0].timestamp.is_empty());
+        assert_eq!("footer", embeds[1].footer_text);
+        assert_eq!("2024-01-31T12:00:00Z", embeds[1].timestamp);
+    }
+
+    #[test]
+    fn test_build_embeds_footer_within_total() {
+        let ec = EmbedContent {
+            title: str::repeat("a", DISCORD_MAX_TITLE),
+            description: str::repeat("b", DISCORD_MAX_DESCRIPTION),
+            author: str::repeat("c", DISCORD_MAX_AUTHOR),
+            footer_text: str::repeat("d", DISCORD_MAX_FOOTER),
+            ..Default::default()
+        };
+        let embeds = build_embeds(ec);
+        assert_eq!(1, embeds.len());
+        let embed = &embeds[0];
+        let total = embed.title.len()
+            + embed.description.len()
+            + embed.author.len()
+            + embed.footer_text.len();
+        assert_eq!(DISCORD_MAX_EMBED_TOTAL, total);
+        assert!(embed.footer_text.ends_with(&Markers::default().truncated));
+    }
 }
@@ -0,0 +1,73 @@
+use log::warn;
+use serenity::builder::CreateEmbed;
+use std::env;
+
+/// Post-processes outgoing embeds right before they are sent, giving self-hosters a way to
+/// customise messages (inject a footer, rewrite links, ...) without forking the shim.
+pub(crate) trait MessageTransform: Send + Sync {
+    fn transform_embed(&self, embed: &mut CreateEmbed);
+}
+
+/// Leaves embeds untouched.
+pub(crate) struct NoopTransform;
+
+impl MessageTransform for NoopTransform {
+    fn transform_embed(&self, _embed: &mut CreateEmbed) {}
+}
+
+/// Sets the same footer on every embed.
+pub(crate) struct FooterTransform {
+    pub text: String,
+}
+
+impl MessageTransform for FooterTransform {
+    fn transform_embed(&self, embed: &mut CreateEmbed) {
+        embed.footer(|f| f.text(&self.text));
+    }
+}
+
+/// Builds the transforms named in the comma separated `MESSAGE_TRANSFORMS` environment variable,
+/// in the order they should be applied.
+pub(crate) fn load_transforms() -> Vec<Box<dyn MessageTransform>> {
+    let names = env::var("MESSAGE_TRANSFORMS").unwrap_or_default();
+    let mut transforms: Vec<Box<dyn MessageTransform>> = vec![];
+    for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        match name {
+            "noop" => transforms.push(Box::new(NoopTransform)),
+            "footer" => transforms.push(Box::new(FooterTransform {
+                text: env::var("TRANSFORM_FOOTER_TEXT").unwrap_or_default(),
+            })),
+            _ => warn!("Unknown message transform [{name}], ignoring"),
+        }
+    }
+    transforms
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transform::{FooterTransform, MessageTransform, NoopTransform};
+    use serenity::builder::CreateEmbed;
+
+    #[test]
+    fn test_noop_transform() {
+        let mut embed = CreateEmbed::default();
+        embed.title("Title");
+        let before = embed.0.clone();
+        NoopTransform.transform_embed(&mut embed);
+        assert_eq!(before, embed.0);
+    }
+
+    #[test]
+    fn test_footer_transform() {
+        let transforms: Vec<Box<dyn MessageTransform>> = vec![Box::new(FooterTransform {
+            text: "Sent by my printer".to_string(),
+        })];
+
+        let mut embed = CreateEmbed::default();
+        embed.title("Title");
+        for transform in &transforms {
+            transform.transform_embed(&mut embed);
+        }
+        assert_eq!("Sent by my printer", embed.0["footer"]["text"]);
+    }
+}
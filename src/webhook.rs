@@ -0,0 +1,98 @@
+use log::{debug, warn};
+use reqwest::header::CONTENT_TYPE;
+use std::env;
+use std::time::{Duration, SystemTime};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where connection events are posted, from `EVENT_WEBHOOK_URL`. No events are posted when unset.
+pub(crate) fn event_webhook_url() -> Option<String> {
+    env::var("EVENT_WEBHOOK_URL")
+        .ok()
+        .filter(|url| !url.is_empty())
+}
+
+/// A client connecting or disconnecting, posted as JSON for alerting pipelines.
+#[derive(Debug, PartialEq, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum ConnectionEvent {
+    Connected {
+        ip: String,
+        /// Unix timestamp of the event.
+        timestamp: u64,
+    },
+    Disconnected {
+        ip: String,
+        timestamp: u64,
+        duration_secs: u64,
+        messages: u64,
+        bytes: u64,
+    },
+}
+
+/// Seconds since the Unix epoch, for event timestamps.
+pub(crate) fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Posts `event` to `url` in the background. A failure is only logged, so a slow or broken
+/// webhook never holds up the client's connection.
+pub(crate) fn notify(url: String, event: ConnectionEvent) {
+    // The HTTP client needs the tokio runtime Discord is served from.
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        warn!("Not posting {event:?} to the event webhook, no tokio runtime");
+        return;
+    };
+    runtime.spawn(async move {
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(error) => {
+                warn!("Failed to encode {event:?}: {error}");
+                return;
+            }
+        };
+        let posted = reqwest::Client::new()
+            .post(&url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .timeout(WEBHOOK_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match posted {
+            Ok(_) => debug!("Posted {event:?} to the event webhook"),
+            Err(error) => warn!("Failed to post {event:?} to the event webhook: {error}"),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webhook::ConnectionEvent;
+
+    #[test]
+    fn test_event_json() {
+        let connected = ConnectionEvent::Connected {
+            ip: "127.0.0.1".to_string(),
+            timestamp: 1706702400,
+        };
+        assert_eq!(
+            r#"{"event":"connected","ip":"127.0.0.1","timestamp":1706702400}"#,
+            serde_json::to_string(&connected).unwrap()
+        );
+
+        let disconnected = ConnectionEvent::Disconnected {
+            ip: "127.0.0.1".to_string(),
+            timestamp: 1706706000,
+            duration_secs: 3600,
+            messages: 12,
+            bytes: 4096,
+        };
+        assert_eq!(
+            r#"{"event":"disconnected","ip":"127.0.0.1","timestamp":1706706000,"duration_secs":3600,"messages":12,"bytes":4096}"#,
+            serde_json::to_string(&disconnected).unwrap()
+        );
+    }
+}
@@ -63,7 +63,7 @@ class TestDiscordShim(LiveDiscordTestCase):
                 title="Title",
                 description="description",
                 color=COLOR_INFO,
-                snapshot=ProtoFile(data=self.snapshot_bytes, filename="snapshot.png"),
+                snapshots=[ProtoFile(data=self.snapshot_bytes, filename="snapshot.png")],
                 textfield=[TextField(title="Title"), TextField(text="Text")]
             )
         )